@@ -7,34 +7,106 @@
 //!     - #id
 //!     - *
 //!     - combination of all the above (e.g. tag#id.class1.class2)
+//!   * Combinators:
+//!     - descendant (e.g. `div p`)
+//!     - child (e.g. `div > p`)
+//!
+//! Supported at-rules:
+//!   * @media (see the `media` module)
+//!   * @supports, evaluated by the parser against the `properties` registry
+//!   * @layer, both the statement and the block forms
+//!
+//! Other at-rules are skipped with a diagnostic.
+//!
+//! Nested style rules (including the `&` nesting selector) and @media, @supports and @layer
+//! rules nested in style rules are desugared by the parser into flat rules following their
+//! parent rule.
+//!
+//! Supported values: keywords, `#rrggbb` and `#rrggbbaa` colors, lengths in `px`, percentages
+//! and the math functions `calc()`, `min()`, `max()` and `clamp()`.
+//!
+//! Custom properties (`--name: value`) and `var()` references are kept unparsed until
+//! computed-value time (see the `custom_properties` module).
+
+use media::MediaQueryList;
+use properties;
+use std::collections::hashmap::HashMap;
+use std::f32;
+use std::fmt;
+use std::uint;
 
-#[deriving(Show)]
+#[deriving(Show, Clone)]
 pub struct Stylesheet {
     pub rules: Vec<Rule>,
+    // Names of the cascade layers, in the order they were first declared. Sublayers are named
+    // with dots, e.g. `base.reset`.
+    pub layers: Vec<String>,
+    pub origin: Origin,
+    // What the parser skipped. Not taken into account when comparing stylesheets.
+    pub diagnostics: Vec<Diagnostic>,
 }
 
-#[deriving(Show)]
+/// Something the parser skipped and why, e.g. an invalid declaration.
+#[deriving(Clone)]
+pub struct Diagnostic {
+    pub location: SourceLocation,
+    pub message: String,
+}
+
+/// Cascade origin of a stylesheet. Declarations from later origins win.
+#[deriving(Show, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Origin {
+    UserAgent,
+    Author,
+}
+
+#[deriving(Show, Clone, PartialEq)]
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
+    // Media query lists of the enclosing @media blocks, outermost first. The rule only applies
+    // if all of them match.
+    pub media: Vec<MediaQueryList>,
+    // Cascade layer the rule belongs to, or `None` for unlayered rules.
+    pub layer: Option<String>,
 }
 
-#[deriving(Show)]
+#[deriving(Show, Clone, PartialEq)]
 pub enum Selector {
     Simple(SimpleSelector),
+    // A selector, a combinator and the simple selector on its right, e.g. `div > p` is
+    // `Complex(Simple(div), Child, p)`.
+    Complex(Box<Selector>, Combinator, SimpleSelector),
 }
 
-#[deriving(Show)]
+#[deriving(Show, Clone, PartialEq)]
+pub enum Combinator {
+    Descendant,
+    Child,
+}
+
+#[deriving(Show, Clone, PartialEq)]
 pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub id: Option<String>,
     pub class: Vec<String>
 }
 
-#[deriving(Show)]
+#[deriving(Show, Clone)]
 pub struct Declaration {
     pub name: String,
     pub value: Value,
+    // Where the declaration was parsed from. Not taken into account when comparing declarations.
+    pub location: SourceLocation,
+}
+
+/// A position in a stylesheet, for diagnostics.
+#[deriving(Clone)]
+pub struct SourceLocation {
+    // Path of the stylesheet file, or another description of where it comes from.
+    pub source: String,
+    pub line: uint,
+    pub column: uint,
 }
 
 #[deriving(Show, Clone, PartialEq)]
@@ -42,6 +114,14 @@ pub enum Value {
     Keyword(String),
     Color(u8, u8, u8, u8), // RGBA
     Length(f32, Unit),
+    Percentage(f32),
+    // A math function: `calc()`, `min()`, `max()` or `clamp()`.
+    Calc(Box<CalcNode>),
+    // Unparsed value text, for custom properties and for values containing `var()` references.
+    Unparsed(String),
+    // Unparsed value text of the named shorthand, containing `var()` references. Each longhand of
+    // the shorthand has this value; its own value is picked after substitution.
+    UnparsedShorthand(String, String),
 }
 
 #[deriving(Show, Clone, PartialEq)]
@@ -49,24 +129,290 @@ pub enum Unit {
     Px // Pixels
 }
 
+/// A node in the expression tree of a math function. The top-level node of a `Calc` value is
+/// `CalcMin`, `CalcMax` or `CalcClamp` for those functions, and the argument of `calc()`
+/// otherwise.
+#[deriving(Show, Clone, PartialEq)]
+pub enum CalcNode {
+    CalcNumber(f32),
+    CalcLength(f32), // px
+    CalcPercentage(f32),
+    CalcSum(Box<CalcNode>, Box<CalcNode>),
+    CalcDifference(Box<CalcNode>, Box<CalcNode>),
+    CalcProduct(Box<CalcNode>, Box<CalcNode>),
+    CalcQuotient(Box<CalcNode>, Box<CalcNode>),
+    CalcMin(Vec<CalcNode>),
+    CalcMax(Vec<CalcNode>),
+    CalcClamp(Box<CalcNode>, Box<CalcNode>, Box<CalcNode>), // min, value, max
+}
+
 pub type Specificity = (uint, uint, uint);
 
+impl Declaration {
+    /// Custom properties are the ones whose name starts with `--`.
+    pub fn is_custom_property(&self) -> bool {
+        self.name.as_slice().starts_with("--")
+    }
+
+    /// Build the declarations for `name: value` without parsing, expanding shorthands like the
+    /// parser does. Fails if the property is unknown or the value invalid for it.
+    pub fn build(name: &str, value: Value) -> Vec<Declaration> {
+        if !properties::is_known(name) {
+            fail!("Unknown property {}", name);
+        }
+        let value = match value {
+            // Like in the parser, the longhands of a shorthand with `var()` references share its
+            // unparsed text.
+            Unparsed(text) => match properties::expand_shorthand(name) {
+                Some(_) => UnparsedShorthand(name.to_string(), text),
+                None => Unparsed(text)
+            },
+            value => {
+                if !properties::is_supported(name, &value) {
+                    fail!("Invalid value for property {}", name);
+                }
+                value
+            }
+        };
+        let location = SourceLocation { source: "<builder>".to_string(), line: 0, column: 0 };
+        let names = match properties::expand_shorthand(name) {
+            Some(longhands) => longhands.iter().map(|longhand| longhand.to_string()).collect(),
+            None => vec![name.to_string()]
+        };
+        names.into_iter().map(|name| Declaration {
+            name: name,
+            value: value.clone(),
+            location: location.clone(),
+        }).collect()
+    }
+}
+
+/// Conversion of Rust values to CSS values, for building declarations: strings are keywords and
+/// numbers are lengths in px.
+pub trait ToValue {
+    fn to_value(self) -> Value;
+}
+
+impl ToValue for Value {
+    fn to_value(self) -> Value { self }
+}
+
+impl<'a> ToValue for &'a str {
+    fn to_value(self) -> Value { Keyword(self.to_string()) }
+}
+
+impl ToValue for f32 {
+    fn to_value(self) -> Value { Length(self, Px) }
+}
+
+impl PartialEq for Declaration {
+    fn eq(&self, other: &Declaration) -> bool {
+        self.name == other.name && self.value == other.value
+    }
+}
+
+impl PartialEq for Stylesheet {
+    fn eq(&self, other: &Stylesheet) -> bool {
+        self.rules == other.rules && self.layers == other.layers && self.origin == other.origin
+    }
+}
+
+impl fmt::Show for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+impl fmt::Show for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.line, self.column)
+    }
+}
+
 impl Value {
-    /// Return the size of a length in px, or zero for non-lengths.
+    /// Return the size of a length in px, or zero for non-lengths. Percentages resolve to zero;
+    /// use `resolve` once the size they refer to is known.
     pub fn to_px(&self) -> f32 {
+        self.resolve(0.0)
+    }
+
+    /// Return the size in px of a length, percentage or math function, with percentages taken
+    /// of `percentage_base`. Other values are zero.
+    pub fn resolve(&self, percentage_base: f32) -> f32 {
         match *self {
             Length(f, Px) => f,
+            Percentage(p) => percentage_base * p / 100.0,
+            Calc(ref node) => node.resolve(percentage_base),
             _ => 0.0
         }
     }
+
+    /// Return true if the size of this value depends on what percentages refer to.
+    pub fn has_percentage(&self) -> bool {
+        match *self {
+            Percentage(_) => true,
+            Calc(ref node) => node.has_percentage(),
+            _ => false
+        }
+    }
+}
+
+impl CalcNode {
+    /// Evaluate the expression in px, with percentages taken of `percentage_base`.
+    pub fn resolve(&self, percentage_base: f32) -> f32 {
+        let eval = |node: &Box<CalcNode>| node.resolve(percentage_base);
+        match *self {
+            CalcNumber(n) | CalcLength(n) => n,
+            CalcPercentage(p) => percentage_base * p / 100.0,
+            CalcSum(ref a, ref b) => eval(a) + eval(b),
+            CalcDifference(ref a, ref b) => eval(a) - eval(b),
+            CalcProduct(ref a, ref b) => eval(a) * eval(b),
+            CalcQuotient(ref a, ref b) => eval(a) / eval(b),
+            CalcMin(ref args) => {
+                args.iter().map(|a| a.resolve(percentage_base)).fold(f32::INFINITY, |a, b| a.min(b))
+            }
+            CalcMax(ref args) => {
+                args.iter().map(|a| a.resolve(percentage_base))
+                    .fold(f32::NEG_INFINITY, |a, b| a.max(b))
+            }
+            CalcClamp(ref min, ref value, ref max) => eval(min).max(eval(value).min(eval(max))),
+        }
+    }
+
+    /// Return true if the expression is a plain number, without lengths or percentages.
+    pub fn is_number(&self) -> bool {
+        match *self {
+            CalcNumber(_) => true,
+            CalcLength(_) | CalcPercentage(_) => false,
+            CalcSum(ref a, ref b) | CalcDifference(ref a, ref b) |
+            CalcProduct(ref a, ref b) | CalcQuotient(ref a, ref b) => {
+                a.is_number() && b.is_number()
+            }
+            CalcMin(ref args) | CalcMax(ref args) => args.iter().all(|a| a.is_number()),
+            CalcClamp(ref min, ref value, ref max) => {
+                min.is_number() && value.is_number() && max.is_number()
+            }
+        }
+    }
+
+    pub fn has_percentage(&self) -> bool {
+        match *self {
+            CalcNumber(_) | CalcLength(_) => false,
+            CalcPercentage(_) => true,
+            CalcSum(ref a, ref b) | CalcDifference(ref a, ref b) |
+            CalcProduct(ref a, ref b) | CalcQuotient(ref a, ref b) => {
+                a.has_percentage() || b.has_percentage()
+            }
+            CalcMin(ref args) | CalcMax(ref args) => args.iter().any(|a| a.has_percentage()),
+            CalcClamp(ref min, ref value, ref max) => {
+                min.has_percentage() || value.has_percentage() || max.has_percentage()
+            }
+        }
+    }
+}
+
+impl Stylesheet {
+    /// An author stylesheet with `rules` and no cascade layers.
+    pub fn new(rules: Vec<Rule>) -> Stylesheet {
+        Stylesheet { rules: rules, layers: Vec::new(), origin: Author, diagnostics: Vec::new() }
+    }
+
+    /// Rank of each cascade layer: rules in layers of higher rank win. Sublayers rank below
+    /// the rules placed directly in their parent layer, and unlayered rules (see
+    /// `unlayered_rank`) win over all layers.
+    pub fn layer_ranks(&self) -> HashMap<String, uint> {
+        // Sort layers by their position in the layer tree. A layer comes after all its
+        // sublayers, so its own position is a path ending in "infinity".
+        let mut paths: Vec<(Vec<uint>, &String)> = self.layers.iter().map(|name| {
+            let segments: Vec<&str> = name.as_slice().split('.').collect();
+            let mut path: Vec<uint> = range(1, segments.len() + 1).map(|n| {
+                let prefix = segments.slice_to(n).connect(".");
+                self.sibling_index(prefix.as_slice())
+            }).collect();
+            path.push(uint::MAX);
+            (path, name)
+        }).collect();
+        paths.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+        paths.into_iter().enumerate().map(|(rank, (_, name))| (name.clone(), rank)).collect()
+    }
+
+    /// Rank of unlayered rules, above the rank of every layer.
+    pub fn unlayered_rank(&self) -> uint {
+        self.layers.len()
+    }
+
+    /// Position of layer `name` among the layers with the same parent.
+    fn sibling_index(&self, name: &str) -> uint {
+        let parent = name.rfind('.').map(|i| name.slice_to(i));
+        let parent_of = |layer: &str| layer.rfind('.').map(|i| layer.slice_to(i));
+        self.layers.iter()
+            .filter(|layer| parent_of(layer.as_slice()) == parent)
+            .position(|layer| layer.as_slice() == name)
+            .unwrap()
+    }
+}
+
+impl Rule {
+    /// A rule outside of any @media block or cascade layer. The selectors are sorted by
+    /// specificity (highest first), like the parser does.
+    pub fn new(mut selectors: Vec<Selector>, declarations: Vec<Declaration>) -> Rule {
+        selectors.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
+        Rule { selectors: selectors, declarations: declarations, media: Vec::new(), layer: None }
+    }
 }
 
 impl Selector {
+    /// Add a tag name to the rightmost compound selector.
+    pub fn with_tag(mut self, tag_name: &str) -> Selector {
+        self.rightmost_mut().tag_name = Some(tag_name.to_string());
+        self
+    }
+
+    /// Add an id to the rightmost compound selector.
+    pub fn with_id(mut self, id: &str) -> Selector {
+        self.rightmost_mut().id = Some(id.to_string());
+        self
+    }
+
+    /// Add a class to the rightmost compound selector.
+    pub fn with_class(mut self, class: &str) -> Selector {
+        self.rightmost_mut().class.push(class.to_string());
+        self
+    }
+
+    /// Combine the selector with a new universal compound selector on its right.
+    pub fn then(self, combinator: Combinator) -> Selector {
+        Complex(box self, combinator, SimpleSelector::universal())
+    }
+
+    fn rightmost_mut(&mut self) -> &mut SimpleSelector {
+        match *self {
+            Simple(ref mut simple) | Complex(_, _, ref mut simple) => simple,
+        }
+    }
+
+    pub fn specificity(&self) -> Specificity {
+        match *self {
+            Simple(ref simple) => simple.specificity(),
+            Complex(ref left, _, ref simple) => {
+                let (a1, b1, c1) = left.specificity();
+                let (a2, b2, c2) = simple.specificity();
+                (a1 + a2, b1 + b2, c1 + c2)
+            }
+        }
+    }
+}
+
+impl SimpleSelector {
+    /// The selector `*`.
+    pub fn universal() -> SimpleSelector {
+        SimpleSelector { tag_name: None, id: None, class: Vec::new() }
+    }
+
     pub fn specificity(&self) -> Specificity {
-        let Simple(ref simple) = *self;
-        let a = simple.id.iter().len();
-        let b = simple.class.len();
-        let c = simple.tag_name.iter().len();
+        let a = self.id.iter().len();
+        let b = self.class.len();
+        let c = self.tag_name.iter().len();
         (a, b, c)
     }
 }
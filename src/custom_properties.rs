@@ -0,0 +1,209 @@
+//! CSS custom properties (`--name: value`) and `var()` substitution.
+//!
+//! Custom properties are always inherited. Their values are kept as unparsed text; `var()`
+//! references are substituted at computed-value time and the result is then parsed as the value
+//! of the property that contained them.
+
+use std::collections::hashmap::{HashMap, HashSet};
+
+/// Computed values of the custom properties of an element, with all `var()` references
+/// substituted. A property missing from the map has the guaranteed-invalid value.
+pub type CustomPropertyMap = HashMap<String, String>;
+
+/// Compute the custom properties of an element from the ones `specified` on it and the ones
+/// `inherited` from its parent.
+pub fn compute(inherited: &CustomPropertyMap, specified: &HashMap<String, String>)
+               -> CustomPropertyMap {
+    let mut resolver = Resolver {
+        inherited: inherited,
+        specified: specified,
+        resolved: HashMap::new(),
+        stack: Vec::new(),
+        cyclic: HashSet::new(),
+    };
+    for name in specified.keys() {
+        resolver.resolve(name.as_slice());
+    }
+
+    let mut computed = inherited.clone();
+    for (name, value) in resolver.resolved.into_iter() {
+        match value {
+            Some(value) => { computed.insert(name, value); }
+            None => { computed.remove(&name); }
+        }
+    }
+    computed
+}
+
+/// Replace every `var(--name[, fallback])` in `text` with the value of the custom property, or
+/// the fallback if the property has the guaranteed-invalid value. Returns `None` if a reference
+/// can't be substituted (an invalid property without fallback, or malformed `var()` syntax).
+pub fn substitute(text: &str, lookup: |&str| -> Option<String>) -> Option<String> {
+    let mut result = String::new();
+    let mut rest = text;
+    loop {
+        let start = match rest.find_str("var(") {
+            Some(start) => start,
+            None => {
+                result.push_str(rest);
+                return Some(result);
+            }
+        };
+        result.push_str(rest.slice_to(start));
+
+        let args_start = start + 4;
+        let args_end = match closing_paren(rest, args_start) {
+            Some(end) => end,
+            None => return None
+        };
+        let args = rest.slice(args_start, args_end);
+        let (name, fallback) = match top_level_comma(args) {
+            Some(comma) => (args.slice_to(comma).trim(), Some(args.slice_from(comma + 1).trim())),
+            None => (args.trim(), None)
+        };
+        if !name.starts_with("--") {
+            return None;
+        }
+
+        let value = match lookup(name) {
+            Some(value) => value,
+            None => match fallback {
+                Some(fallback) => match substitute(fallback, |n| lookup(n)) {
+                    Some(value) => value,
+                    None => return None
+                },
+                None => return None
+            }
+        };
+        result.push_str(value.as_slice());
+        rest = rest.slice_from(args_end + 1);
+    }
+}
+
+/// Return true if `text` contains a `var()` reference.
+pub fn has_references(text: &str) -> bool {
+    text.contains("var(")
+}
+
+/// Resolves references between the custom properties specified on one element.
+struct Resolver<'a> {
+    inherited: &'a CustomPropertyMap,
+    specified: &'a HashMap<String, String>,
+    resolved: HashMap<String, Option<String>>,
+    // Properties currently being resolved, used to detect reference cycles.
+    stack: Vec<String>,
+    // Properties that are part of a cycle. They all compute to the guaranteed-invalid value.
+    cyclic: HashSet<String>,
+}
+
+impl<'a> Resolver<'a> {
+    fn resolve(&mut self, name: &str) -> Option<String> {
+        match self.resolved.find_equiv(&name) {
+            Some(value) => return value.clone(),
+            None => {}
+        }
+        let raw = match self.specified.find_equiv(&name) {
+            Some(raw) => raw.clone(),
+            None => return self.inherited.find_equiv(&name).map(|v| v.clone())
+        };
+        match self.stack.iter().position(|n| n.as_slice() == name) {
+            Some(i) => {
+                for n in self.stack.slice_from(i).iter() {
+                    self.cyclic.insert(n.clone());
+                }
+                return None;
+            }
+            None => {}
+        }
+
+        self.stack.push(name.to_string());
+        let value = match raw.as_slice().trim() {
+            "initial" => None,
+            "inherit" | "unset" => self.inherited.find_equiv(&name).map(|v| v.clone()),
+            raw => substitute(raw, |n| self.resolve(n)),
+        };
+        self.stack.pop();
+
+        let value = if self.cyclic.contains_equiv(&name) { None } else { value };
+        self.resolved.insert(name.to_string(), value.clone());
+        value
+    }
+}
+
+/// Find the `)` matching an opening parenthesis just before `start`.
+fn closing_paren(text: &str, start: uint) -> Option<uint> {
+    let mut depth = 0u;
+    for (i, c) in text.slice_from(start).char_indices() {
+        match c {
+            '(' => depth = depth + 1,
+            ')' if depth == 0 => return Some(start + i),
+            ')' => depth = depth - 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Find the first comma in `text` that is not nested inside parentheses.
+fn top_level_comma(text: &str) -> Option<uint> {
+    let mut depth = 0u;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth = depth + 1,
+            ')' if depth > 0 => depth = depth - 1,
+            ',' if depth == 0 => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hashmap::HashMap;
+    use super::{compute, substitute, CustomPropertyMap};
+
+    fn compute_specified(declarations: &[(&str, &str)]) -> CustomPropertyMap {
+        let specified = declarations.iter()
+                                    .map(|&(name, value)| (name.to_string(), value.to_string()))
+                                    .collect();
+        compute(&HashMap::new(), &specified)
+    }
+
+    fn get<'a>(computed: &'a CustomPropertyMap, name: &str) -> Option<&'a str> {
+        computed.find_equiv(&name).map(|value| value.as_slice())
+    }
+
+    #[test]
+    fn reference_cycles_are_invalid() {
+        let computed = compute_specified(&[
+            ("--a", "var(--b)"),
+            ("--b", "var(--a)"),
+            ("--self", "var(--self, 1px)"),
+            ("--c", "var(--a, 2px)"),
+            ("--d", "var(--c) var(--b, 3px)"),
+        ]);
+        assert_eq!(get(&computed, "--a"), None);
+        assert_eq!(get(&computed, "--b"), None);
+        assert_eq!(get(&computed, "--self"), None);
+        assert_eq!(get(&computed, "--c"), Some("2px"));
+        assert_eq!(get(&computed, "--d"), Some("2px 3px"));
+    }
+
+    #[test]
+    fn empty_values_are_valid() {
+        let computed = compute_specified(&[
+            ("--empty", ""),
+            ("--x", "var(--empty, fallback)"),
+            ("--initial", "initial"),
+            ("--y", "var(--initial, fallback)"),
+        ]);
+        assert_eq!(get(&computed, "--empty"), Some(""));
+        assert_eq!(get(&computed, "--x"), Some(""));
+        assert_eq!(get(&computed, "--initial"), None);
+        assert_eq!(get(&computed, "--y"), Some("fallback"));
+        assert_eq!(substitute("1px var(--empty)", |name| get(&computed, name).map(|v| {
+            v.to_string()
+        })), Some("1px ".to_string()));
+    }
+}
@@ -0,0 +1,508 @@
+//! Structural diff between two DOM trees.
+//!
+//! The children of matching nodes are matched by the longest common subsequence of their keys
+//! (the tag name and id of elements; all text nodes have the same key). Unmatched children with
+//! the same key are reported as moved if they have an id or identical subtrees. The nodes of the
+//! subtrees left unmatched are then paired the same way across the whole tree, so that moves
+//! between different parents are reported too; the others are reported as removed or inserted.
+//! Nodes without an id are only paired if one of them is the root of its subtree, so that the
+//! contents of a replaced element are part of its replacement rather than moves.
+//! Nodes are identified by XPath-like paths, e.g. `/html/body/p[2]/text()`.
+
+use dom::{NodeRef, NodeId, Element, Text};
+use std::cmp::max;
+use std::collections::hashmap::{HashMap, HashSet};
+use traversal::pre_order;
+
+/// A difference between the old and the new tree.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Edit {
+    Inserted(String), // path in the new tree
+    Removed(String), // path in the old tree
+    Moved(String, String), // old and new path
+    AttributeAdded(String, String, String), // path in the new tree, name and value
+    AttributeRemoved(String, String, String), // path in the new tree, name and old value
+    AttributeChanged(String, String, String, String), // path in the new tree, name, old and new
+    TextChanged(String, String, String), // path in the new tree, old and new text
+}
+
+/// Return the differences between the trees of `old` and `new`, in document order.
+pub fn diff<'a>(old: NodeRef<'a>, new: NodeRef<'a>) -> Vec<Edit> {
+    if key(old) != key(new) {
+        return vec![Removed(path(old)), Inserted(path(new))];
+    }
+    let mut matching = Matching {
+        old_to_new: HashMap::new(),
+        new_to_old: HashMap::new(),
+        moved: HashSet::new(),
+        removed: Vec::new(),
+        inserted: Vec::new(),
+    };
+    matching.add(old, new, false);
+    matching.match_moved_subtrees();
+
+    let mut edits = Vec::new();
+    for node in pre_order(new) {
+        match matching.new_to_old.find(&node.id()) {
+            Some(&old_node) => {
+                if matching.moved.contains(&node.id()) {
+                    edits.push(Moved(path(old_node), path(node)));
+                }
+                diff_nodes(old_node, node, &mut edits);
+                for child in old_node.children() {
+                    if !matching.old_to_new.contains_key(&child.id()) {
+                        edits.push(Removed(path(child)));
+                    }
+                }
+            }
+            None => {
+                // Only the root of an inserted subtree is reported.
+                let parent = node.parent().unwrap();
+                if matching.new_to_old.contains_key(&parent.id()) {
+                    edits.push(Inserted(path(node)));
+                }
+            }
+        }
+    }
+    edits
+}
+
+/// The nodes of the old tree matched with nodes of the new tree.
+struct Matching<'a> {
+    old_to_new: HashMap<NodeId, NodeRef<'a>>,
+    new_to_old: HashMap<NodeId, NodeRef<'a>>,
+    moved: HashSet<NodeId>, // ids of the new nodes that were moved
+    // The nodes of the old and new subtrees that were left unmatched so far, in pre-order, with
+    // whether they are the root of their subtree.
+    removed: Vec<(NodeRef<'a>, bool)>,
+    inserted: Vec<(NodeRef<'a>, bool)>,
+}
+
+impl<'a> Matching<'a> {
+    /// Match `old` with `new`, and their descendants by matching the children of each pair.
+    fn add(&mut self, old: NodeRef<'a>, new: NodeRef<'a>, moved: bool) {
+        let mut pairs = vec![(old, new, moved)];
+        loop {
+            let (old, new, moved) = match pairs.pop() {
+                Some(pair) => pair,
+                None => break
+            };
+            self.old_to_new.insert(old.id(), new);
+            self.new_to_old.insert(new.id(), old);
+            if moved {
+                self.moved.insert(new.id());
+            }
+            pairs.extend(self.match_children(old, new).into_iter());
+        }
+    }
+
+    /// Match the children of two matched nodes. Returns the pairs of children, with whether they
+    /// were moved, and adds the subtrees of the other children to `removed` and `inserted`.
+    fn match_children(&mut self, old: NodeRef<'a>, new: NodeRef<'a>)
+                      -> Vec<(NodeRef<'a>, NodeRef<'a>, bool)> {
+        let old_children: Vec<NodeRef> = old.children().collect();
+        let new_children: Vec<NodeRef> = new.children().collect();
+        let old_keys: Vec<String> = old_children.iter().map(|&child| key(child)).collect();
+        let new_keys: Vec<String> = new_children.iter().map(|&child| key(child)).collect();
+
+        // Index of the matching new child of each old child, and the other way around.
+        let mut old_match: Vec<Option<uint>> = Vec::from_elem(old_children.len(), None);
+        let mut new_match: Vec<Option<uint>> = Vec::from_elem(new_children.len(), None);
+        for &(i, j) in common_subsequence(old_keys.as_slice(), new_keys.as_slice()).iter() {
+            *old_match.get_mut(i) = Some(j);
+            *new_match.get_mut(j) = Some(i);
+        }
+
+        // Pair the remaining children that were moved.
+        let mut moved = Vec::new();
+        for i in range(0, old_children.len()) {
+            if old_match[i].is_some() {
+                continue;
+            }
+            let found = range(0, new_children.len()).find(|&j| {
+                new_match[j].is_none() && old_keys[i] == new_keys[j] &&
+                    (has_id(old_children[i]) || same_subtree(old_children[i], new_children[j]))
+            });
+            for &j in found.iter() {
+                *old_match.get_mut(i) = Some(j);
+                *new_match.get_mut(j) = Some(i);
+                moved.push(j);
+            }
+        }
+
+        for (i, &child) in old_children.iter().enumerate() {
+            if old_match[i].is_none() {
+                self.removed.extend(pre_order(child).map(|node| (node, node == child)));
+            }
+        }
+        let mut pairs = Vec::new();
+        for (j, &child) in new_children.iter().enumerate() {
+            match new_match[j] {
+                Some(i) => pairs.push((old_children[i], child, moved.contains(&j))),
+                None => self.inserted.extend(pre_order(child).map(|node| (node, node == child)))
+            }
+        }
+        pairs
+    }
+
+    /// Match the unmatched nodes of the old and new trees that have the same key and an id or
+    /// identical subtrees: they were moved to another parent. Matching them can leave more nodes
+    /// unmatched, which are paired in turn.
+    fn match_moved_subtrees(&mut self) {
+        // The unmatched new nodes by key, in pre-order, and how many of `inserted` are in it.
+        let mut index: HashMap<String, Vec<(NodeRef<'a>, bool)>> = HashMap::new();
+        let mut indexed = 0;
+        let mut next_removed = 0;
+        while next_removed < self.removed.len() {
+            for &(new, is_root) in self.inserted.slice_from(indexed).iter() {
+                index.find_or_insert_with(key(new), |_| Vec::new()).push((new, is_root));
+            }
+            indexed = self.inserted.len();
+
+            let (old, old_is_root) = self.removed[next_removed];
+            next_removed += 1;
+            if self.old_to_new.contains_key(&old.id()) {
+                continue;
+            }
+            let found = match index.find_mut(&key(old)) {
+                Some(candidates) => {
+                    let new_to_old = &self.new_to_old;
+                    candidates.retain(|&(new, _)| !new_to_old.contains_key(&new.id()));
+                    candidates.iter().position(|&(new, new_is_root)| {
+                        if has_id(old) {
+                            return true;
+                        }
+                        (old_is_root || new_is_root) && same_subtree(old, new)
+                    }).map(|position| candidates.remove(position).unwrap().val0())
+                }
+                None => None
+            };
+            for &new in found.iter() {
+                self.add(old, new, true);
+            }
+        }
+    }
+}
+
+/// Compare two matching nodes, without their descendants.
+fn diff_nodes(old: NodeRef, new: NodeRef, edits: &mut Vec<Edit>) {
+    match (old.node_type(), new.node_type()) {
+        (&Element(ref old_elem), &Element(ref new_elem)) => {
+            for (name, old_value) in old_elem.attributes().iter() {
+                match new_elem.get_attribute(name.as_slice()) {
+                    Some(new_value) if new_value != old_value => {
+                        edits.push(AttributeChanged(path(new), name.clone(), old_value.clone(),
+                                                    new_value.clone()));
+                    }
+                    Some(_) => {}
+                    None => {
+                        edits.push(AttributeRemoved(path(new), name.clone(), old_value.clone()));
+                    }
+                }
+            }
+            for (name, new_value) in new_elem.attributes().iter() {
+                if old_elem.get_attribute(name.as_slice()).is_none() {
+                    edits.push(AttributeAdded(path(new), name.clone(), new_value.clone()));
+                }
+            }
+        }
+        (&Text(ref old_text), &Text(ref new_text)) => {
+            if old_text != new_text {
+                edits.push(TextChanged(path(new), old_text.clone(), new_text.clone()));
+            }
+        }
+        _ => fail!("Only nodes with the same key are compared")
+    }
+}
+
+/// Pairs of indices of the longest common subsequence of `a` and `b`, in order.
+fn common_subsequence(a: &[String], b: &[String]) -> Vec<(uint, uint)> {
+    // lengths[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+    let mut lengths = Vec::from_fn(a.len() + 1, |_| Vec::from_elem(b.len() + 1, 0u));
+    for i in range(0, a.len()).rev() {
+        for j in range(0, b.len()).rev() {
+            let length = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                max(lengths[i + 1][j], lengths[i][j + 1])
+            };
+            *lengths.get_mut(i).get_mut(j) = length;
+        }
+    }
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+/// What children are matched by: the tag name and id of elements.
+fn key(node: NodeRef) -> String {
+    match *node.node_type() {
+        Element(ref elem) => match elem.id() {
+            Some(id) => format!("{}#{}", elem.tag_name, id),
+            None => elem.tag_name.clone()
+        },
+        Text(_) => "#text".to_string()
+    }
+}
+
+fn has_id(node: NodeRef) -> bool {
+    node.element().map_or(false, |elem| elem.id().is_some())
+}
+
+/// Are the trees of `a` and `b` identical? They are if their nodes, in pre-order, are pairwise
+/// identical and have as many children.
+fn same_subtree(a: NodeRef, b: NodeRef) -> bool {
+    pre_order(a).zip(pre_order(b)).all(|(a, b)| {
+        let same_node = match (a.node_type(), b.node_type()) {
+            (&Element(ref a), &Element(ref b)) => {
+                a.tag_name == b.tag_name && a.attributes() == b.attributes()
+            }
+            (&Text(ref a), &Text(ref b)) => a == b,
+            _ => false
+        };
+        same_node && a.children().count() == b.children().count()
+    })
+}
+
+/// The path of `node` from the root, e.g. `/html/body/p[2]/text()`. Steps have the position of
+/// the node among the siblings with the same name if there are several.
+pub fn path(node: NodeRef) -> String {
+    let mut steps = Vec::new();
+    let mut current = Some(node);
+    loop {
+        match current {
+            Some(node) => {
+                steps.push(step(node));
+                current = node.parent();
+            }
+            None => break
+        }
+    }
+    steps.reverse();
+    format!("/{}", steps.connect("/"))
+}
+
+fn step(node: NodeRef) -> String {
+    let name = match *node.node_type() {
+        Element(ref elem) => elem.tag_name.clone(),
+        Text(_) => "text()".to_string()
+    };
+    let siblings: Vec<NodeRef> = match node.parent() {
+        Some(parent) => parent.children().filter(|&sibling| same_name(sibling, node)).collect(),
+        None => return name
+    };
+    if siblings.len() == 1 {
+        return name;
+    }
+    let position = siblings.iter().position(|&sibling| sibling == node).unwrap() + 1;
+    match *node.node_type() {
+        Element(_) => format!("{}[{}]", name, position),
+        Text(_) => format!("text()[{}]", position)
+    }
+}
+
+fn same_name(a: NodeRef, b: NodeRef) -> bool {
+    match (a.node_type(), b.node_type()) {
+        (&Element(ref a), &Element(ref b)) => a.tag_name == b.tag_name,
+        (&Text(_), &Text(_)) => true,
+        _ => false
+    }
+}
+
+/// Format the edits for people, one per line.
+pub fn to_text(edits: &[Edit]) -> String {
+    let mut output = String::new();
+    for edit in edits.iter() {
+        let line = match *edit {
+            Inserted(ref path) => format!("+ {}", path),
+            Removed(ref path) => format!("- {}", path),
+            Moved(ref from, ref to) => format!("> {} -> {}", from, to),
+            AttributeAdded(ref path, ref name, ref value) => {
+                format!("~ {} @{}: added {}", path, name, quote(value.as_slice()))
+            }
+            AttributeRemoved(ref path, ref name, ref value) => {
+                format!("~ {} @{}: removed {}", path, name, quote(value.as_slice()))
+            }
+            AttributeChanged(ref path, ref name, ref old, ref new) => {
+                format!("~ {} @{}: {} -> {}", path, name, quote(old.as_slice()),
+                        quote(new.as_slice()))
+            }
+            TextChanged(ref path, ref old, ref new) => {
+                format!("~ {}: {} -> {}", path, quote(old.as_slice()), quote(new.as_slice()))
+            }
+        };
+        output.push_str(line.as_slice());
+        output.push_char('\n');
+    }
+    output
+}
+
+/// Format the edits as a JSON array of objects.
+pub fn to_json(edits: &[Edit]) -> String {
+    let objects: Vec<String> = edits.iter().map(|edit| {
+        let fields = match *edit {
+            Inserted(ref path) => vec![("type", "inserted"), ("path", path.as_slice())],
+            Removed(ref path) => vec![("type", "removed"), ("path", path.as_slice())],
+            Moved(ref from, ref to) => {
+                vec![("type", "moved"), ("from", from.as_slice()), ("to", to.as_slice())]
+            }
+            AttributeAdded(ref path, ref name, ref value) => {
+                vec![("type", "attribute-added"), ("path", path.as_slice()),
+                     ("name", name.as_slice()), ("value", value.as_slice())]
+            }
+            AttributeRemoved(ref path, ref name, ref value) => {
+                vec![("type", "attribute-removed"), ("path", path.as_slice()),
+                     ("name", name.as_slice()), ("old", value.as_slice())]
+            }
+            AttributeChanged(ref path, ref name, ref old, ref new) => {
+                vec![("type", "attribute-changed"), ("path", path.as_slice()),
+                     ("name", name.as_slice()), ("old", old.as_slice()), ("new", new.as_slice())]
+            }
+            TextChanged(ref path, ref old, ref new) => {
+                vec![("type", "text-changed"), ("path", path.as_slice()),
+                     ("old", old.as_slice()), ("new", new.as_slice())]
+            }
+        };
+        let members: Vec<String> = fields.iter().map(|&(name, value)| {
+            format!("{}: {}", quote(name), quote(value))
+        }).collect();
+        format!("{{{}}}", members.connect(", "))
+    }).collect();
+    format!("[{}]\n", objects.connect(",\n "))
+}
+
+/// Quote a string with the JSON syntax.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from_str("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if (c as u32) < 0x20 => quoted.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c => quoted.push_char(c)
+        }
+    }
+    quoted.push_char('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::parse_html;
+    use super::{diff, to_text, to_json, Edit, Inserted, Removed, Moved, AttributeAdded};
+    use super::{AttributeRemoved, AttributeChanged, TextChanged};
+
+    fn diff_html(old: &str, new: &str) -> Vec<Edit> {
+        let old = parse_html(old.to_string());
+        let new = parse_html(new.to_string());
+        diff(old.root(), new.root())
+    }
+
+    #[test]
+    fn moves_between_parents() {
+        let edits = diff_html("<body><div><p id=\"a\">A</p></div><section></section></body>",
+                              "<body><div></div><section><p id=\"a\">B</p></section></body>");
+        assert_eq!(edits, vec![
+            Moved("/body/div/p".to_string(), "/body/section/p".to_string()),
+            TextChanged("/body/section/p/text()".to_string(), "A".to_string(), "B".to_string()),
+        ]);
+
+        // Identical subtrees without ids are matched too, e.g. when they are wrapped.
+        let edits = diff_html("<body><ul><li>One</li></ul><p>Text</p></body>",
+                              "<body><div><ul><li>One</li></ul></div><p>Text</p></body>");
+        assert_eq!(edits, vec![
+            Inserted("/body/div".to_string()),
+            Moved("/body/ul".to_string(), "/body/div/ul".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn replaced_elements_keep_their_contents() {
+        let edits = diff_html("<div><p>Hi <b>there</b></p></div>",
+                              "<div><section>Hi <b>there</b></section></div>");
+        assert_eq!(edits, vec![
+            Removed("/div/p".to_string()),
+            Inserted("/div/section".to_string()),
+        ]);
+
+        // Elements with an id are still moved out of a replaced element.
+        let edits = diff_html("<div><p>Hi <b id=\"x\">there</b></p></div>",
+                              "<div><section>Hi <b id=\"x\">there</b></section></div>");
+        assert_eq!(edits, vec![
+            Removed("/div/p".to_string()),
+            Inserted("/div/section".to_string()),
+            Moved("/div/p/b".to_string(), "/div/section/b".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn many_moves() {
+        fn items<I: Iterator<uint>>(order: I) -> String {
+            let items: Vec<String> = order.map(|i| format!("<li id=\"i{}\">{}</li>", i, i))
+                                          .collect();
+            items.concat()
+        }
+        let old = format!("<div><ul>{}</ul><ol></ol></div>", items(range(0u, 2000)));
+        let new = format!("<div><ul></ul><ol>{}</ol></div>", items(range(0u, 2000).rev()));
+        let edits = diff_html(old.as_slice(), new.as_slice());
+        assert_eq!(edits.len(), 2000);
+        assert_eq!(edits[0], Moved("/div/ul/li[2000]".to_string(), "/div/ol/li[1]".to_string()));
+    }
+
+    fn example_edits() -> Vec<Edit> {
+        let string = |s: &str| s.to_string();
+        vec![
+            Inserted(string("/p")),
+            Removed(string("/div")),
+            Moved(string("/ul"), string("/div/ul")),
+            AttributeAdded(string("/p"), string("title"), string("say \"hi\"")),
+            AttributeRemoved(string("/p"), string("lang"), string("en")),
+            AttributeChanged(string("/p"), string("id"), string("a"), string("b")),
+            TextChanged(string("/b"), string("\t\n"), string("\x07\\\x1b")),
+        ]
+    }
+
+    #[test]
+    fn text_output() {
+        let lines = [
+            r#"+ /p"#,
+            r#"- /div"#,
+            r#"> /ul -> /div/ul"#,
+            r#"~ /p @title: added "say \"hi\"""#,
+            r#"~ /p @lang: removed "en""#,
+            r#"~ /p @id: "a" -> "b""#,
+            r#"~ /b: "\t\n" -> "\u0007\\\u001b""#,
+        ];
+        assert_eq!(to_text(example_edits().as_slice()), format!("{}\n", lines.connect("\n")));
+    }
+
+    #[test]
+    fn json_output() {
+        let objects = [
+            r#"{"type": "inserted", "path": "/p"}"#,
+            r#"{"type": "removed", "path": "/div"}"#,
+            r#"{"type": "moved", "from": "/ul", "to": "/div/ul"}"#,
+            r#"{"type": "attribute-added", "path": "/p", "name": "title", "value": "say \"hi\""}"#,
+            r#"{"type": "attribute-removed", "path": "/p", "name": "lang", "old": "en"}"#,
+            r#"{"type": "attribute-changed", "path": "/p", "name": "id", "old": "a", "new": "b"}"#,
+            r#"{"type": "text-changed", "path": "/b", "old": "\t\n", "new": "\u0007\\\u001b"}"#,
+        ];
+        assert_eq!(to_json(example_edits().as_slice()),
+                   format!("[{}]\n", objects.connect(",\n ")));
+        assert_eq!(to_json(&[]), "[]\n".to_string());
+    }
+}
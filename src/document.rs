@@ -0,0 +1,302 @@
+//! The document: the DOM of a page and what is known about the page itself.
+
+use dom::{Dom, NodeRef};
+use std::ascii::StrAsciiExt; // for `to_ascii_lower`
+
+/// A parsed HTML document.
+pub struct Document {
+    pub dom: Dom,
+    pub doctype: Option<Doctype>,
+    pub quirks_mode: QuirksMode,
+    pub url: Option<String>, // where the document was loaded from
+}
+
+/// The `<!DOCTYPE>` of a document.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Doctype {
+    pub name: String, // in lowercase, e.g. "html"
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+}
+
+/// Whether the document is rendered like legacy browsers did, because it has no doctype or the
+/// doctype of a legacy version of HTML. Limited-quirks mode is rendered like no-quirks mode.
+#[deriving(Show, Clone, PartialEq)]
+pub enum QuirksMode {
+    Quirks,
+    NoQuirks,
+}
+
+/// Public identifiers of doctypes that trigger quirks mode, and the prefixes that do.
+static QUIRKS_PUBLIC_IDS: &'static [&'static str] = &[
+    "-//w3o//dtd w3 html strict 3.0//en//", "-/w3c/dtd html 4.0 transitional/en", "html",
+];
+static QUIRKS_PUBLIC_ID_PREFIXES: &'static [&'static str] = &[
+    "+//silmaril//dtd html pro v0r11 19970101//", "-//as//dtd html 3.0 aswedit + extensions//",
+    "-//advasoft ltd//dtd html 3.0 aswedit + extensions//", "-//ietf//dtd html 2.0 level 1//",
+    "-//ietf//dtd html 2.0 level 2//", "-//ietf//dtd html 2.0 strict level 1//",
+    "-//ietf//dtd html 2.0 strict level 2//", "-//ietf//dtd html 2.0 strict//",
+    "-//ietf//dtd html 2.0//", "-//ietf//dtd html 2.1e//", "-//ietf//dtd html 3.0//",
+    "-//ietf//dtd html 3.2 final//", "-//ietf//dtd html 3.2//", "-//ietf//dtd html 3//",
+    "-//ietf//dtd html level 0//", "-//ietf//dtd html level 1//", "-//ietf//dtd html level 2//",
+    "-//ietf//dtd html level 3//", "-//ietf//dtd html strict level 0//",
+    "-//ietf//dtd html strict level 1//", "-//ietf//dtd html strict level 2//",
+    "-//ietf//dtd html strict level 3//", "-//ietf//dtd html strict//", "-//ietf//dtd html//",
+    "-//metrius//dtd metrius presentational//",
+    "-//microsoft//dtd internet explorer 2.0 html strict//",
+    "-//microsoft//dtd internet explorer 2.0 html//",
+    "-//microsoft//dtd internet explorer 2.0 tables//",
+    "-//microsoft//dtd internet explorer 3.0 html strict//",
+    "-//microsoft//dtd internet explorer 3.0 html//",
+    "-//microsoft//dtd internet explorer 3.0 tables//", "-//netscape comm. corp.//dtd html//",
+    "-//netscape comm. corp.//dtd strict html//", "-//o'reilly and associates//dtd html 2.0//",
+    "-//o'reilly and associates//dtd html extended 1.0//",
+    "-//o'reilly and associates//dtd html extended relaxed 1.0//",
+    "-//sq//dtd html 2.0 hotmetal + extensions//",
+    "-//softquad software//dtd hotmetal pro 6.0::19990601::extensions to html 4.0//",
+    "-//softquad//dtd hotmetal pro 4.0::19971010::extensions to html 4.0//",
+    "-//spyglass//dtd html 2.0 extended//", "-//sun microsystems corp.//dtd hotjava html//",
+    "-//sun microsystems corp.//dtd hotjava strict html//", "-//w3c//dtd html 3 1995-03-24//",
+    "-//w3c//dtd html 3.2 draft//", "-//w3c//dtd html 3.2 final//", "-//w3c//dtd html 3.2//",
+    "-//w3c//dtd html 3.2s draft//", "-//w3c//dtd html 4.0 frameset//",
+    "-//w3c//dtd html 4.0 transitional//", "-//w3c//dtd html experimental 19960712//",
+    "-//w3c//dtd html experimental 970421//", "-//w3c//dtd w3 html//",
+    "-//w3o//dtd w3 html 3.0//", "-//webtechs//dtd mozilla html 2.0//",
+    "-//webtechs//dtd mozilla html//",
+];
+/// Public identifier prefixes that trigger quirks mode only without a system identifier.
+static QUIRKS_PUBLIC_ID_PREFIXES_WITHOUT_SYSTEM_ID: &'static [&'static str] = &[
+    "-//w3c//dtd html 4.01 frameset//", "-//w3c//dtd html 4.01 transitional//",
+];
+static QUIRKS_SYSTEM_ID: &'static str =
+    "http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd";
+
+impl Doctype {
+    /// A doctype without public and system identifiers, e.g. `Doctype::new("html")`.
+    pub fn new(name: &str) -> Doctype {
+        Doctype { name: name.to_string(), public_id: None, system_id: None }
+    }
+
+    /// The mode a document with this doctype is rendered in, following the HTML standard.
+    fn quirks_mode(&self) -> QuirksMode {
+        let public_id = self.public_id.as_ref().map(|id| id.as_slice().to_ascii_lower());
+        let public_id = public_id.as_ref().map_or("", |id| id.as_slice());
+        let system_id = self.system_id.as_ref().map(|id| id.as_slice().to_ascii_lower());
+        let starts_with_any = |prefixes: &[&str]| {
+            prefixes.iter().any(|&prefix| public_id.starts_with(prefix))
+        };
+        let quirks = self.name.as_slice() != "html" ||
+                     QUIRKS_PUBLIC_IDS.iter().any(|&id| id == public_id) ||
+                     starts_with_any(QUIRKS_PUBLIC_ID_PREFIXES) ||
+                     (system_id.is_none() &&
+                      starts_with_any(QUIRKS_PUBLIC_ID_PREFIXES_WITHOUT_SYSTEM_ID)) ||
+                     system_id.as_ref().map_or(false, |id| id.as_slice() == QUIRKS_SYSTEM_ID);
+        if quirks { Quirks } else { NoQuirks }
+    }
+}
+
+impl Document {
+    /// Create a document for `dom`. Documents without a doctype or with the doctype of a legacy
+    /// version of HTML are in quirks mode.
+    pub fn new(dom: Dom, doctype: Option<Doctype>) -> Document {
+        let quirks_mode = doctype.as_ref().map_or(Quirks, |doctype| doctype.quirks_mode());
+        Document { dom: dom, doctype: doctype, quirks_mode: quirks_mode, url: None }
+    }
+
+    /// The root element.
+    pub fn root(&self) -> NodeRef {
+        self.dom.root()
+    }
+
+    /// The text of the first `<title>` element, with whitespace stripped and collapsed.
+    pub fn title(&self) -> Option<String> {
+        self.root().query_selector("title").unwrap().map(|title| {
+            let text = title.text_content();
+            let words: Vec<&str> = text.as_slice().words().collect();
+            words.connect(" ")
+        })
+    }
+
+    /// The `href` attribute of the first `<base>` element that has one.
+    pub fn base_href(&self) -> Option<String> {
+        self.root().query_selector_all("base").unwrap().into_iter()
+            .filter_map(|base| base.element().and_then(|elem| elem.get_attribute("href")))
+            .map(|href| href.clone())
+            .next()
+    }
+
+    /// The URL that relative URLs in the document are resolved against: the `<base href>`,
+    /// resolved against the document URL, or else the document URL.
+    pub fn base_url(&self) -> Option<String> {
+        match (self.base_href(), &self.url) {
+            (Some(href), &Some(ref url)) => Some(resolve_url(url.as_slice(), href.as_slice())),
+            (Some(href), &None) => Some(href),
+            (None, url) => url.clone()
+        }
+    }
+}
+
+/// Resolve `url` against the absolute URL `base`, like browsers resolve links. A base without a
+/// path has the path `/`, and `.` and `..` segments are removed from the resolved path.
+pub fn resolve_url(base: &str, url: &str) -> String {
+    if has_scheme(url) {
+        return url.to_string();
+    }
+    let (origin, path, query) = split_url(base);
+    if url.starts_with("//") {
+        // Keep the scheme of the base.
+        let scheme_end = origin.find(':').map_or(0, |i| i + 1);
+        return format!("{}{}", origin.slice_to(scheme_end), url);
+    }
+    let path = if path.is_empty() { "/" } else { path };
+    let url_path_end = url.find(|c: char| c == '?' || c == '#').unwrap_or(url.len());
+    let (url_path, suffix) = (url.slice_to(url_path_end), url.slice_from(url_path_end));
+    if url_path.is_empty() {
+        // Only a query or a fragment replaces that of the base; a fragment keeps its query.
+        let query = if suffix.starts_with("?") { "" } else { query };
+        return format!("{}{}{}{}", origin, path, query, suffix);
+    }
+    let resolved_path = if url_path.starts_with("/") {
+        remove_dot_segments(url_path)
+    } else {
+        let directory = path.slice_to(path.rfind('/').map_or(0, |i| i + 1));
+        remove_dot_segments(format!("{}{}", directory, url_path).as_slice())
+    };
+    format!("{}{}{}", origin, resolved_path, suffix)
+}
+
+/// Does `url` start with a scheme, e.g. `http:` or `mailto:`?
+fn has_scheme(url: &str) -> bool {
+    match url.find(|c: char| c == ':' || c == '/' || c == '?' || c == '#') {
+        Some(i) => i > 0 && url.char_at(i) == ':' && url.char_at(0).is_alphabetic(),
+        None => false
+    }
+}
+
+/// Split `url` into its scheme and host, e.g. `http://example.com`, its path and its query
+/// (with the `?`). The fragment is dropped.
+fn split_url(url: &str) -> (&str, &str, &str) {
+    let scheme_end = if has_scheme(url) { url.find(':').unwrap() + 1 } else { 0 };
+    let origin_end = if url.slice_from(scheme_end).starts_with("//") {
+        let host_start = scheme_end + 2;
+        url.slice_from(host_start).find(|c: char| c == '/' || c == '?' || c == '#')
+           .map_or(url.len(), |i| host_start + i)
+    } else {
+        scheme_end
+    };
+    let path_end = url.slice_from(origin_end).find(|c: char| c == '?' || c == '#')
+                      .map_or(url.len(), |i| origin_end + i);
+    let query_end = url.slice_from(path_end).find('#').map_or(url.len(), |i| path_end + i);
+    (url.slice_to(origin_end), url.slice(origin_end, path_end), url.slice(path_end, query_end))
+}
+
+/// Remove the `.` and `..` segments of a path. `..` at the root of an absolute path is ignored,
+/// and the leading `..` segments of a relative path, e.g. a file path, are kept.
+fn remove_dot_segments(path: &str) -> String {
+    let absolute = path.starts_with("/");
+    let parts: Vec<&str> = path.split('/').collect();
+    let mut segments = Vec::new();
+    for (i, &part) in parts.iter().enumerate() {
+        match part {
+            "." | ".." => {
+                if part == ".." {
+                    let last = segments.last().map(|&segment| segment);
+                    match last {
+                        Some("") if absolute && segments.len() == 1 => {}
+                        Some("..") | None => segments.push(".."),
+                        Some(_) => { segments.pop(); }
+                    }
+                }
+                // Keep the trailing slash of a path that ends with a dot segment.
+                if i == parts.len() - 1 {
+                    segments.push("");
+                }
+            }
+            part => segments.push(part)
+        }
+    }
+    segments.connect("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::parse_html;
+    use super::{resolve_url, Doctype, Quirks, NoQuirks};
+
+    #[test]
+    fn resolve_urls() {
+        let cases = [
+            ("http://example.com", "page.html", "http://example.com/page.html"),
+            ("http://example.com/a/b.html", "c.html", "http://example.com/a/c.html"),
+            ("http://example.com/a/b.html", "/c.html", "http://example.com/c.html"),
+            ("http://example.com/a/b.html", "https://example.org/", "https://example.org/"),
+            ("http://example.com/a/b.html", "mailto:a@example.com", "mailto:a@example.com"),
+            ("https://example.com/a/b.html", "//cdn.example.com/x.js",
+             "https://cdn.example.com/x.js"),
+            ("http://example.com/a/b.html?x=1#top", "?y=2", "http://example.com/a/b.html?y=2"),
+            ("http://example.com/a/b.html?x=1#top", "#end",
+             "http://example.com/a/b.html?x=1#end"),
+            ("http://example.com/a/b.html?x=1#top", "", "http://example.com/a/b.html?x=1"),
+            ("http://example.com/a/b.html", "c?q=1#f", "http://example.com/a/c?q=1#f"),
+            ("http://example.com/a/b/c.html", "../d.html", "http://example.com/a/d.html"),
+            ("http://example.com/a/b/c.html", "./d/./e/../f", "http://example.com/a/b/d/f"),
+            ("http://example.com/a/", "../../x", "http://example.com/x"),
+            ("http://example.com/a/b", "..", "http://example.com/"),
+            ("http://example.com/a/", "./", "http://example.com/a/"),
+            ("../examples/test.html", "style.css", "../examples/style.css"),
+            ("examples/test.html", "../x.css", "x.css"),
+            ("a/b.html", "../../x/../../y.css", "../../y.css"),
+            ("test.html", "./img/a.png", "img/a.png"),
+        ];
+        for &(base, url, expected) in cases.iter() {
+            assert_eq!(resolve_url(base, url), expected.to_string());
+        }
+    }
+
+    #[test]
+    fn doctype() {
+        let document = parse_html("<!DOCTYPE html><html></html>".to_string());
+        assert_eq!(document.doctype, Some(Doctype::new("html")));
+        assert_eq!(document.quirks_mode, NoQuirks);
+
+        let document = parse_html("<!doctype HTML><html></html>".to_string());
+        assert_eq!(document.doctype, Some(Doctype::new("html")));
+
+        let document = parse_html("<!-- generated -->\n<!DOCTYPE html><html></html>".to_string());
+        assert_eq!(document.doctype, Some(Doctype::new("html")));
+
+        let document = parse_html("<html></html>".to_string());
+        assert_eq!(document.doctype, None);
+        assert_eq!(document.quirks_mode, Quirks);
+    }
+
+    #[test]
+    fn legacy_doctypes() {
+        let doctypes = [
+            ("<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\">", Quirks),
+            ("<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\" \
+              \"http://www.w3.org/TR/html4/loose.dtd\">", NoQuirks),
+            ("<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01//EN\">", NoQuirks),
+            ("<!DOCTYPE html PUBLIC '-//IETF//DTD HTML 2.0//EN'>", Quirks),
+            ("<!DOCTYPE html PUBLIC \"HTML\">", Quirks),
+            ("<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \
+              \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">", NoQuirks),
+            ("<!DOCTYPE html SYSTEM \"about:legacy-compat\">", NoQuirks),
+            ("<!DOCTYPE html SYSTEM \
+              \"http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd\">", Quirks),
+            ("<!DOCTYPE svg>", Quirks),
+        ];
+        for &(doctype, quirks_mode) in doctypes.iter() {
+            let document = parse_html(format!("{}<html></html>", doctype));
+            assert_eq!((doctype, document.quirks_mode), (doctype, quirks_mode));
+        }
+
+        let document = parse_html("<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \
+                                   'x.dtd'><html></html>".to_string());
+        assert_eq!(document.doctype, Some(Doctype {
+            name: "html".to_string(),
+            public_id: Some("-//W3C//DTD XHTML 1.0 Strict//EN".to_string()),
+            system_id: Some("x.dtd".to_string()),
+        }));
+    }
+}
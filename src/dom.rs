@@ -1,10 +1,37 @@
 //! Basic DOM data structures
+//!
+//! The nodes of a document live in an arena, a `Dom`, and refer to each other by `NodeId`: each
+//! node links to its parent, its first and last children and its previous and next siblings. Ids
+//! stay valid as long as the `Dom` does, even for nodes removed from the tree. Trees are built with
+//! `text` and `elem`, then moved into a `Dom`.
 
-use std::collections::hashmap::{HashMap, HashSet};
+use css::Selector;
+use parser;
+use std::collections::hashmap::HashSet;
+use std::slice::Items;
+use std::mem;
+use style;
+use traversal::{pre_order, PreOrder};
+
+/// Index of a node in its `Dom`.
+#[deriving(Show, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(uint);
+
+/// The nodes of a document.
+pub struct Dom {
+    nodes: Vec<Node>,
+    root: NodeId,
+    // changes made to the tree since the records were last taken:
+    records: Vec<MutationRecord>,
+}
 
 pub struct Node {
     // data common to all nodes:
-    pub children: Vec<Node>,
+    parent: Option<NodeId>,
+    first_child: Option<NodeId>,
+    last_child: Option<NodeId>,
+    previous_sibling: Option<NodeId>,
+    next_sibling: Option<NodeId>,
 
     // specific data to each node type:
     pub node_type: NodeType,
@@ -19,42 +46,596 @@ pub enum NodeType {
 #[deriving(Show)]
 pub struct ElementData {
     pub tag_name: String,
-    pub attributes: AttrMap,
+    attributes: AttrMap,
+    classes: HashSet<String>, // the tokens of the class attribute
 }
 
-pub type AttrMap = HashMap<String, String>;
+/// The attributes of an element, in the order they were first set.
+#[deriving(Show, Clone, PartialEq)]
+pub struct AttrMap {
+    entries: Vec<(String, String)>,
+}
+
+/// A change made to the tree, like the records of a `MutationObserver`. Later stages, e.g.
+/// `invalidation`, use them to find out what changed.
+#[deriving(Show, Clone, PartialEq)]
+pub enum MutationRecord {
+    ChildrenChanged(NodeId, Vec<NodeId>, Vec<NodeId>), // parent, added and removed children
+    AttributeChanged(NodeId, String, Option<String>), // element, attribute name and old value
+    TextChanged(NodeId, String), // text node and old text
+}
+
+/// A node and its descendants, not yet part of a `Dom`.
+pub struct Tree {
+    pub node_type: NodeType,
+    pub children: Vec<Tree>,
+}
+
+/// A node of a `Dom`, for walking the tree.
+#[deriving(Clone)]
+pub struct NodeRef<'a> {
+    dom: &'a Dom,
+    id: NodeId,
+}
 
 // Constructors
 
-pub fn text(data: String) -> Node {
-    Node { children: vec![], node_type: Text(data) }
+pub fn text(data: String) -> Tree {
+    Tree { children: vec![], node_type: Text(data) }
 }
 
-pub fn elem(name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
-    Node {
+pub fn elem(name: String, attrs: AttrMap, children: Vec<Tree>) -> Tree {
+    Tree {
         children: children,
-        node_type: Element(ElementData {
-            tag_name: name,
-            attributes: attrs,
+        node_type: Element(ElementData::new(name, attrs))
+    }
+}
+
+// Dom methods
+
+impl Dom {
+    /// Create a DOM whose root is `root`. Fails if a text node in it has children.
+    pub fn new(root: Tree) -> Dom {
+        let mut dom = Dom { nodes: Vec::new(), root: NodeId(0), records: Vec::new() };
+        dom.root = dom.add_tree(root);
+        dom
+    }
+
+    pub fn root(&self) -> NodeRef {
+        self.get(self.root)
+    }
+
+    pub fn get(&self, id: NodeId) -> NodeRef {
+        NodeRef { dom: self, id: id }
+    }
+
+    fn node(&self, id: NodeId) -> &Node {
+        let NodeId(index) = id;
+        &self.nodes[index]
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        let NodeId(index) = id;
+        self.nodes.get_mut(index)
+    }
+
+    /// Move `tree` into the arena, detached from the document, and return the id of its root. It
+    /// can then be inserted with `append_child`, `insert_before` or `replace_child`. Fails if a
+    /// text node in it has children.
+    pub fn create(&mut self, tree: Tree) -> NodeId {
+        self.add_tree(tree)
+    }
+
+    fn add_tree(&mut self, tree: Tree) -> NodeId {
+        let root = NodeId(self.nodes.len());
+        // The trees still to add, the next one last, with the node they are appended to.
+        let mut stack = vec![(tree, None)];
+        loop {
+            let (Tree { node_type, children }, parent) = match stack.pop() {
+                Some(entry) => entry,
+                None => break
+            };
+            let id = NodeId(self.nodes.len());
+            match node_type {
+                Text(_) if !children.is_empty() => fail!("Text node {} can't have children", id),
+                _ => {}
+            }
+            self.nodes.push(Node {
+                parent: None,
+                first_child: None,
+                last_child: None,
+                previous_sibling: None,
+                next_sibling: None,
+                node_type: node_type,
+            });
+            for &parent in parent.iter() {
+                self.link(parent, id, None);
+            }
+            stack.extend(children.into_iter().rev().map(|child| (child, Some(id))));
+        }
+        root
+    }
+
+    /// Link detached node `child` into the children of `parent`, before `reference` or last.
+    fn link(&mut self, parent: NodeId, child: NodeId, reference: Option<NodeId>) {
+        let previous = match reference {
+            Some(reference) => self.node(reference).previous_sibling,
+            None => self.node(parent).last_child
+        };
+        match previous {
+            Some(previous) => self.node_mut(previous).next_sibling = Some(child),
+            None => self.node_mut(parent).first_child = Some(child)
+        }
+        match reference {
+            Some(reference) => self.node_mut(reference).previous_sibling = Some(child),
+            None => self.node_mut(parent).last_child = Some(child)
+        }
+        let node = self.node_mut(child);
+        node.parent = Some(parent);
+        node.previous_sibling = previous;
+        node.next_sibling = reference;
+    }
+
+    /// Detach `id` from its parent and siblings.
+    fn unlink(&mut self, id: NodeId) {
+        let (parent, previous, next) = {
+            let node = self.node(id);
+            (node.parent, node.previous_sibling, node.next_sibling)
+        };
+        match previous {
+            Some(previous) => self.node_mut(previous).next_sibling = next,
+            None => for &parent in parent.iter() { self.node_mut(parent).first_child = next; }
+        }
+        match next {
+            Some(next) => self.node_mut(next).previous_sibling = previous,
+            None => for &parent in parent.iter() { self.node_mut(parent).last_child = previous; }
+        }
+        let node = self.node_mut(id);
+        node.parent = None;
+        node.previous_sibling = None;
+        node.next_sibling = None;
+    }
+
+    /// Add `child` after the last child of `parent`, moving it if it's already in the tree.
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) {
+        self.insert_before(parent, child, None);
+    }
+
+    /// Add `child` to the children of `parent` before `reference`, or last if it is `None`,
+    /// moving it if it's already in the tree. Fails if `parent` is a text node, if `reference`
+    /// is not a child of `parent`, or if `child` is the root, `parent` or one of its ancestors.
+    pub fn insert_before(&mut self, parent: NodeId, child: NodeId, reference: Option<NodeId>) {
+        match self.node(parent).node_type {
+            Text(_) => fail!("Text node {} can't have children", parent),
+            Element(_) => {}
+        }
+        for &reference in reference.iter() {
+            if self.node(reference).parent != Some(parent) {
+                fail!("{} is not a child of {}", reference, parent);
+            }
+        }
+        if child == self.root {
+            fail!("The root {} can't be moved", child);
+        }
+        if self.is_inclusive_ancestor(child, parent) {
+            fail!("Inserting {} into {} would create a cycle", child, parent);
+        }
+
+        // Inserting a node before itself leaves it in place.
+        let reference = if reference == Some(child) {
+            self.node(child).next_sibling
+        } else {
+            reference
+        };
+        let old_parent = self.node(child).parent;
+        for &old_parent in old_parent.iter() {
+            self.records.push(ChildrenChanged(old_parent, vec![], vec![child]));
+        }
+        self.unlink(child);
+        self.link(parent, child, reference);
+        self.records.push(ChildrenChanged(parent, vec![child], vec![]));
+    }
+
+    /// Remove `child` from the children of `parent`. It stays in the arena, detached, and can be
+    /// inserted again.
+    pub fn remove_child(&mut self, parent: NodeId, child: NodeId) {
+        if self.node(child).parent != Some(parent) {
+            fail!("{} is not a child of {}", child, parent);
+        }
+        self.unlink(child);
+        self.records.push(ChildrenChanged(parent, vec![], vec![child]));
+    }
+
+    /// Replace child `old` of `parent` with `new`, moving `new` if it's already in the tree.
+    pub fn replace_child(&mut self, parent: NodeId, new: NodeId, old: NodeId) {
+        if new == old {
+            return;
+        }
+        self.insert_before(parent, new, Some(old));
+        self.remove_child(parent, old);
+    }
+
+    /// Is `ancestor` the node `id` or one of its ancestors?
+    fn is_inclusive_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut current = Some(id);
+        loop {
+            match current {
+                Some(node) if node == ancestor => return true,
+                Some(node) => current = self.node(node).parent,
+                None => return false
+            }
+        }
+    }
+
+    /// Set attribute `name` of element `id` to `value`.
+    pub fn set_attribute(&mut self, id: NodeId, name: &str, value: String) {
+        let old = self.node_mut(id).element_mut().set_attribute(name, value);
+        self.records.push(AttributeChanged(id, name.to_string(), old));
+    }
+
+    /// Remove attribute `name` of element `id`, if it has it.
+    pub fn remove_attribute(&mut self, id: NodeId, name: &str) {
+        let old = self.node_mut(id).element_mut().remove_attribute(name);
+        if old.is_some() {
+            self.records.push(AttributeChanged(id, name.to_string(), old));
+        }
+    }
+
+    /// Replace the text of text node `id`.
+    pub fn set_text(&mut self, id: NodeId, text: String) {
+        let old = match self.node_mut(id).node_type {
+            Text(ref mut data) => mem::replace(data, text),
+            Element(_) => fail!("{} is not a text node", id)
+        };
+        self.records.push(TextChanged(id, old));
+    }
+
+    /// Return the changes made since the records were last taken, oldest first.
+    pub fn take_records(&mut self) -> Vec<MutationRecord> {
+        mem::replace(&mut self.records, Vec::new())
+    }
+}
+
+impl Node {
+    fn element_mut(&mut self) -> &mut ElementData {
+        match self.node_type {
+            Element(ref mut elem) => elem,
+            Text(_) => fail!("Text nodes have no attributes")
+        }
+    }
+}
+
+// NodeRef methods
+
+impl<'a> NodeRef<'a> {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn node_type(&self) -> &'a NodeType {
+        &self.dom.node(self.id).node_type
+    }
+
+    pub fn parent(&self) -> Option<NodeRef<'a>> {
+        self.link(|node| node.parent)
+    }
+
+    pub fn first_child(&self) -> Option<NodeRef<'a>> {
+        self.link(|node| node.first_child)
+    }
+
+    pub fn last_child(&self) -> Option<NodeRef<'a>> {
+        self.link(|node| node.last_child)
+    }
+
+    pub fn previous_sibling(&self) -> Option<NodeRef<'a>> {
+        self.link(|node| node.previous_sibling)
+    }
+
+    pub fn next_sibling(&self) -> Option<NodeRef<'a>> {
+        self.link(|node| node.next_sibling)
+    }
+
+    /// Iterate over the children of the node, first to last.
+    pub fn children(&self) -> Children<'a> {
+        Children { next: self.first_child() }
+    }
+
+    /// Iterate over the ancestors of the node, parent first.
+    pub fn ancestors(&self) -> Ancestors<'a> {
+        Ancestors { next: self.parent() }
+    }
+
+    /// The text of the node and its descendants, in document order.
+    pub fn text_content(&self) -> String {
+        let mut text = String::new();
+        for node in pre_order(*self) {
+            match *node.node_type() {
+                Text(ref data) => text.push_str(data.as_slice()),
+                Element(_) => {}
+            }
+        }
+        text
+    }
+
+    /// The data of the node if it's an element.
+    pub fn element(&self) -> Option<&'a ElementData> {
+        match *self.node_type() {
+            Element(ref elem) => Some(elem),
+            Text(_) => None
+        }
+    }
+
+    /// Return true if the node is an element matched by the comma separated `selectors`, or an
+    /// error if they aren't a valid selector list.
+    pub fn matches(&self, selectors: &str) -> Result<bool, String> {
+        let selectors = try!(parser::parse_selectors(selectors.to_string()));
+        Ok(self.matches_any(selectors.as_slice(), self.ancestor_elements().as_slice()))
+    }
+
+    /// Return the node or its closest ancestor matched by the comma separated `selectors`, or an
+    /// error if they aren't a valid selector list.
+    pub fn closest(&self, selectors: &str) -> Result<Option<NodeRef<'a>>, String> {
+        let selectors = try!(parser::parse_selectors(selectors.to_string()));
+        let mut ancestors = self.ancestor_elements();
+        let mut node = Some(*self);
+        loop {
+            match node {
+                Some(current) => {
+                    if current.matches_any(selectors.as_slice(), ancestors.as_slice()) {
+                        return Ok(node);
+                    }
+                    // The parent is the last ancestor.
+                    ancestors.pop();
+                    node = current.parent();
+                }
+                None => return Ok(None)
+            }
+        }
+    }
+
+    /// Return the first descendant, in document order, matched by the comma separated
+    /// `selectors`, or an error if they aren't a valid selector list.
+    pub fn query_selector(&self, selectors: &str) -> Result<Option<NodeRef<'a>>, String> {
+        Ok(try!(self.matching(selectors)).next())
+    }
+
+    /// Return all the descendants, in document order, matched by the comma separated `selectors`,
+    /// or an error if they aren't a valid selector list.
+    pub fn query_selector_all(&self, selectors: &str) -> Result<Vec<NodeRef<'a>>, String> {
+        Ok(try!(self.matching(selectors)).collect())
+    }
+
+    /// Iterate over the descendants matched by the comma separated `selectors`.
+    fn matching(&self, selectors: &str) -> Result<Matching<'a>, String> {
+        let selectors = try!(parser::parse_selectors(selectors.to_string()));
+        let mut path: Vec<NodeRef<'a>> = self.ancestors().collect();
+        path.reverse();
+        path.push(*self);
+        let mut nodes = pre_order(*self);
+        nodes.next();
+        Ok(Matching {
+            selectors: selectors,
+            nodes: nodes,
+            elements: path.iter().filter_map(|node| node.element()).collect(),
+            path: path,
         })
     }
+
+    fn matches_any(&self, selectors: &[Selector], ancestors: &[&ElementData]) -> bool {
+        match self.element() {
+            Some(elem) => {
+                selectors.iter().any(|selector| style::matches(elem, ancestors, selector))
+            }
+            None => false
+        }
+    }
+
+    /// The elements the node is a descendant of, root first.
+    fn ancestor_elements(&self) -> Vec<&'a ElementData> {
+        let mut ancestors: Vec<&'a ElementData> =
+            self.ancestors().filter_map(|ancestor| ancestor.element()).collect();
+        ancestors.reverse();
+        ancestors
+    }
+
+    fn link(&self, f: |&Node| -> Option<NodeId>) -> Option<NodeRef<'a>> {
+        let dom = self.dom;
+        f(dom.node(self.id)).map(|id| dom.get(id))
+    }
+}
+
+/// Are two references to the same node?
+impl<'a> PartialEq for NodeRef<'a> {
+    fn eq(&self, other: &NodeRef<'a>) -> bool {
+        self.id == other.id && self.dom as *const Dom == other.dom as *const Dom
+    }
+}
+
+pub struct Children<'a> {
+    next: Option<NodeRef<'a>>,
+}
+
+impl<'a> Iterator<NodeRef<'a>> for Children<'a> {
+    fn next(&mut self) -> Option<NodeRef<'a>> {
+        let node = self.next.take();
+        self.next = node.and_then(|node| node.next_sibling());
+        node
+    }
+}
+
+/// The descendants of a node matched by a selector list, in document order.
+struct Matching<'a> {
+    selectors: Vec<Selector>,
+    nodes: PreOrder<NodeRef<'a>>,
+    // The elements from the root to the parent of the next node, and their data.
+    path: Vec<NodeRef<'a>>,
+    elements: Vec<&'a ElementData>,
+}
+
+impl<'a> Iterator<NodeRef<'a>> for Matching<'a> {
+    fn next(&mut self) -> Option<NodeRef<'a>> {
+        loop {
+            let node = match self.nodes.next() {
+                Some(node) => node,
+                None => return None
+            };
+            let parent = node.parent();
+            while self.path.last().map_or(false, |&last| Some(last) != parent) {
+                self.path.pop();
+                self.elements.pop();
+            }
+            match node.element() {
+                Some(elem) => {
+                    let matched = self.selectors.iter().any(|selector| {
+                        style::matches(elem, self.elements.as_slice(), selector)
+                    });
+                    self.path.push(node);
+                    self.elements.push(elem);
+                    if matched {
+                        return Some(node);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+pub struct Ancestors<'a> {
+    next: Option<NodeRef<'a>>,
+}
+
+impl<'a> Iterator<NodeRef<'a>> for Ancestors<'a> {
+    fn next(&mut self) -> Option<NodeRef<'a>> {
+        let node = self.next.take();
+        self.next = node.and_then(|node| node.parent());
+        node
+    }
 }
 
 // Element methods
 
 impl ElementData {
+    pub fn new(tag_name: String, attributes: AttrMap) -> ElementData {
+        let classes = class_set(attributes.get("class"));
+        ElementData { tag_name: tag_name, attributes: attributes, classes: classes }
+    }
+
+    pub fn attributes(&self) -> &AttrMap {
+        &self.attributes
+    }
+
     pub fn get_attribute(&self, key: &str) -> Option<&String> {
-        self.attributes.find_equiv(&key)
+        self.attributes.get(key)
     }
 
     pub fn id(&self) -> Option<&String> {
         self.get_attribute("id")
     }
 
-    pub fn classes(&self) -> HashSet<&str> {
-        match self.get_attribute("class") {
-            Some(classlist) => classlist.as_slice().split(' ').collect(),
-            None => HashSet::new()
+    pub fn classes(&self) -> &HashSet<String> {
+        &self.classes
+    }
+
+    /// Set attribute `name`, returning its previous value.
+    fn set_attribute(&mut self, name: &str, value: String) -> Option<String> {
+        let old = self.attributes.insert(name.to_string(), value);
+        if name == "class" {
+            self.classes = class_set(self.attributes.get("class"));
+        }
+        old
+    }
+
+    /// Remove attribute `name`, returning its value.
+    fn remove_attribute(&mut self, name: &str) -> Option<String> {
+        let old = self.attributes.remove(name);
+        if name == "class" {
+            self.classes = HashSet::new();
+        }
+        old
+    }
+}
+
+/// The classes listed in the value of a class attribute, which are separated by ASCII whitespace.
+pub fn class_set(value: Option<&String>) -> HashSet<String> {
+    match value {
+        Some(value) => {
+            value.as_slice().split(|c: char| " \t\n\x0c\r".contains_char(c))
+                 .filter(|class| !class.is_empty())
+                 .map(|class| class.to_string())
+                 .collect()
+        }
+        None => HashSet::new()
+    }
+}
+
+// Attribute map methods
+
+impl AttrMap {
+    pub fn new() -> AttrMap {
+        AttrMap { entries: Vec::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries.iter().find(|&&(ref n, _)| n.as_slice() == name).map(|&(_, ref value)| value)
+    }
+
+    /// Set attribute `name` to `value`, returning its previous value. An attribute that is
+    /// already set keeps its position.
+    pub fn insert(&mut self, name: String, value: String) -> Option<String> {
+        match self.position(name.as_slice()) {
+            Some(i) => match *self.entries.get_mut(i) {
+                (_, ref mut old) => Some(mem::replace(old, value))
+            },
+            None => {
+                self.entries.push((name, value));
+                None
+            }
+        }
+    }
+
+    /// Remove attribute `name`, returning its value.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        match self.position(name) {
+            Some(i) => self.entries.remove(i).map(|(_, value)| value),
+            None => None
         }
     }
+
+    /// Iterate over the names and values of the attributes, in order.
+    pub fn iter(&self) -> Attributes {
+        Attributes { entries: self.entries.iter() }
+    }
+
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    fn position(&self, name: &str) -> Option<uint> {
+        self.entries.iter().position(|&(ref n, _)| n.as_slice() == name)
+    }
+}
+
+pub struct Attributes<'a> {
+    entries: Items<'a, (String, String)>,
+}
+
+impl<'a> Iterator<(&'a String, &'a String)> for Attributes<'a> {
+    fn next(&mut self) -> Option<(&'a String, &'a String)> {
+        self.entries.next().map(|&(ref name, ref value)| (name, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dom, AttrMap, elem, text};
+
+    #[test]
+    #[should_fail]
+    fn text_nodes_with_children_are_rejected() {
+        let mut node = text("a".to_string());
+        node.children.push(text("b".to_string()));
+        Dom::new(elem("p".to_string(), AttrMap::new(), vec![node]));
+    }
 }
@@ -0,0 +1,146 @@
+//! Invalidation: find the elements whose style may be affected by the changes made to the DOM
+//! since it was last styled, so that `style::restyle_tree` only restyles those.
+//!
+//! Selectors only test tag names, ids and classes, so only changes to the `id` and `class`
+//! attributes can change which selectors match an element, and only if the ids or classes involved
+//! appear in some selector. If one appears in the rightmost compound selector, the element itself
+//! must be restyled; if it appears further left, its descendants may match differently too.
+//! Ids and classes are compared in ASCII lowercase, since they match case-insensitively in quirks
+//! mode. Elements whose children changed have all their children restyled. Changes to text don't
+//! affect styles.
+
+use css::{Stylesheet, Selector, Simple, Complex, SimpleSelector};
+use dom;
+use dom::{Dom, Element, ElementData, Text};
+use dom::{MutationRecord, AttributeChanged, ChildrenChanged, TextChanged};
+use std::ascii::StrAsciiExt; // for `to_ascii_lower`
+use std::collections::hashmap::{HashMap, HashSet};
+use style::StyleSnapshot;
+
+/// What needs to be restyled because of the changes to an element.
+#[deriving(Show, Clone, PartialEq, PartialOrd)]
+pub enum Damage {
+    NoDamage,
+    RestyleSelf,
+    RestyleSubtree,
+}
+
+/// The damage caused by adding or removing each id and class mentioned by the selectors.
+struct InvalidationMap {
+    ids: HashMap<String, Damage>,
+    classes: HashMap<String, Damage>,
+}
+
+impl InvalidationMap {
+    fn new(stylesheets: &[&Stylesheet]) -> InvalidationMap {
+        let mut map = InvalidationMap { ids: HashMap::new(), classes: HashMap::new() };
+        for stylesheet in stylesheets.iter() {
+            for rule in stylesheet.rules.iter() {
+                for selector in rule.selectors.iter() {
+                    map.add_selector(selector);
+                }
+            }
+        }
+        map
+    }
+
+    fn add_selector(&mut self, selector: &Selector) {
+        let mut selector = selector;
+        let mut damage = RestyleSelf;
+        loop {
+            match *selector {
+                Simple(ref simple) => {
+                    self.add_simple_selector(simple, damage);
+                    break;
+                }
+                Complex(ref left, _, ref simple) => {
+                    self.add_simple_selector(simple, damage);
+                    selector = &**left;
+                    damage = RestyleSubtree;
+                }
+            }
+        }
+    }
+
+    fn add_simple_selector(&mut self, selector: &SimpleSelector, damage: Damage) {
+        for id in selector.id.iter() {
+            note(&mut self.ids, id, damage);
+        }
+        for class in selector.class.iter() {
+            note(&mut self.classes, class, damage);
+        }
+    }
+
+    /// The damage caused by changing attribute `name` of `elem` from `old` to its current value.
+    fn attribute_damage(&self, elem: &ElementData, name: &str, old: &Option<String>) -> Damage {
+        match name {
+            "class" => {
+                let old = dom::class_set(old.as_ref());
+                old.symmetric_difference(elem.classes())
+                   .filter_map(|class| self.classes.find(&class.as_slice().to_ascii_lower()))
+                   .fold(NoDamage, |a, &b| max(a, b))
+            }
+            "id" => {
+                old.iter().chain(elem.id().into_iter())
+                   .filter_map(|id| self.ids.find(&id.as_slice().to_ascii_lower()))
+                   .fold(NoDamage, |a, &b| max(a, b))
+            }
+            _ => NoDamage
+        }
+    }
+}
+
+fn note(map: &mut HashMap<String, Damage>, name: &String, damage: Damage) {
+    let entry = map.find_or_insert(name.as_slice().to_ascii_lower(), damage);
+    *entry = max(*entry, damage);
+}
+
+fn max(a: Damage, b: Damage) -> Damage {
+    if a > b { a } else { b }
+}
+
+/// Record in `styles`, a snapshot of the styles of the tree of `dom` before the changes in
+/// `records`, which elements need to be restyled.
+pub fn invalidate(dom: &Dom, records: &[MutationRecord], stylesheets: &[&Stylesheet],
+                  styles: &mut StyleSnapshot) {
+    let map = InvalidationMap::new(stylesheets);
+    let mut damage = HashMap::new();
+    let mut children_changed = HashSet::new();
+    for record in records.iter() {
+        match *record {
+            AttributeChanged(id, ref name, ref old) => match *dom.get(id).node_type() {
+                Element(ref elem) => {
+                    let changed = map.attribute_damage(elem, name.as_slice(), old);
+                    let entry = damage.find_or_insert(id, NoDamage);
+                    *entry = max(*entry, changed);
+                }
+                Text(_) => {}
+            },
+            ChildrenChanged(id, _, _) => {
+                children_changed.insert(id);
+            }
+            TextChanged(..) => {}
+        }
+    }
+
+    // The nodes still to mark, with their snapshots.
+    let mut stack = vec![(dom.root().id(), styles)];
+    loop {
+        let (id, styles) = match stack.pop() {
+            Some(entry) => entry,
+            None => break
+        };
+        for &node_damage in damage.find(&id).iter() {
+            styles.damage = max(styles.damage, *node_damage);
+        }
+        if children_changed.contains(&id) {
+            // The children will all be restyled.
+            styles.children_changed = true;
+        }
+        if !styles.children_changed {
+            for (child, child_styles) in dom.get(id).children().zip(styles.children.iter_mut()) {
+                stack.push((child.id(), child_styles));
+            }
+        }
+    }
+}
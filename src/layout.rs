@@ -3,8 +3,10 @@
 use std::default::Default;
 use std::iter::AdditiveIterator; // for `sum`
 
-use css::{Keyword, Length, Px};
+use document::{Document, Quirks};
+use properties::Len;
 use style::{StyledNode, Inline, Block, DisplayNone};
+use traversal::{Visitor, walk};
 
 // CSS box model. All sizes are in px.
 
@@ -46,31 +48,84 @@ pub enum BoxType<'a> {
     AnonymousBlock,
 }
 
-/// Transform a style tree into a layout tree.
-pub fn layout_tree<'a>(node: &'a StyledNode<'a>, containing_block: Dimensions) -> LayoutBox<'a> {
+/// Transform the style tree of `document` into a layout tree.
+pub fn layout_tree<'a>(document: &Document, node: &'a StyledNode<'a>,
+                       containing_block: Dimensions) -> LayoutBox<'a> {
     let mut root_box = build_layout_tree(node);
     root_box.layout(containing_block);
+    if document.quirks_mode == Quirks {
+        body_height_quirk(&mut root_box, containing_block.height);
+    }
     root_box
 }
 
+/// In quirks mode, the root element and the body element are at least as tall as the viewport
+/// if their height is auto.
+fn body_height_quirk(root: &mut LayoutBox, viewport_height: f32) {
+    root.fill_height(viewport_height);
+    let root_height = root.dimensions.height;
+    for child in root.children.iter_mut() {
+        let is_body = match child.box_type {
+            BlockNode(styled) => {
+                styled.node().element().map_or(false, |elem| elem.tag_name.as_slice() == "body")
+            }
+            _ => false
+        };
+        if is_body {
+            child.fill_height(root_height);
+        }
+    }
+}
+
 /// Build the tree of LayoutBoxes, but don't perform any layout calculations yet.
 fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
-    // Create the root box.
-    let mut root = LayoutBox::new(match style_node.display() {
-        Block => BlockNode(style_node),
-        Inline => InlineNode(style_node),
-        DisplayNone => fail!("Root node has display: none.")
-    });
-
-    // Create the descendant boxes.
-    for child in style_node.children.iter() {
-        match child.display() {
-            Block => root.children.push(build_layout_tree(child)),
-            Inline => root.get_inline_container().children.push(build_layout_tree(child)),
-            DisplayNone => {} // Skip nodes with `display: None;`
+    let mut builder = LayoutTreeBuilder { stack: Vec::new(), hidden_depth: 0, root: None };
+    walk(style_node, &mut builder);
+    builder.root.unwrap()
+}
+
+/// Builds the tree of LayoutBoxes of a style tree while `walk` visits it.
+struct LayoutTreeBuilder<'a> {
+    // The boxes of the nodes entered but not left yet, root first.
+    stack: Vec<LayoutBox<'a>>,
+    // Number of nodes entered inside a node with `display: none`, including it.
+    hidden_depth: uint,
+    root: Option<LayoutBox<'a>>,
+}
+
+impl<'a> Visitor<&'a StyledNode<'a>> for LayoutTreeBuilder<'a> {
+    fn enter(&mut self, style_node: &'a StyledNode<'a>) {
+        if self.hidden_depth > 0 {
+            self.hidden_depth += 1;
+            return;
+        }
+        let box_type = match style_node.display() {
+            Block => BlockNode(style_node),
+            Inline => InlineNode(style_node),
+            DisplayNone if self.stack.is_empty() => fail!("Root node has display: none."),
+            DisplayNone => {
+                // Skip nodes with `display: None;`
+                self.hidden_depth = 1;
+                return;
+            }
+        };
+        self.stack.push(LayoutBox::new(box_type));
+    }
+
+    fn leave(&mut self, style_node: &'a StyledNode<'a>) {
+        if self.hidden_depth > 0 {
+            self.hidden_depth -= 1;
+            return;
+        }
+        let layout_box = self.stack.pop().unwrap();
+        match self.stack.last_mut() {
+            Some(parent) => match style_node.display() {
+                Inline => parent.get_inline_container().children.push(layout_box),
+                _ => parent.children.push(layout_box)
+            },
+            None => self.root = Some(layout_box)
         }
     }
-    root
 }
 
 impl Dimensions {
@@ -134,108 +189,121 @@ impl<'a> LayoutBox<'a> {
     }
 
     fn calculate_block_width(&mut self, containing_block: Dimensions) {
-        let style = self.get_style_node();
+        let style = &self.get_style_node().style;
 
-        // `width` has initial value `auto`.
-        let auto = Keyword("auto".to_string());
-        let mut width = style.value("width").unwrap_or(auto.clone());
+        // Percentages and math functions are resolved against the containing block's width.
+        // `None` is `auto`.
+        let cb_width = containing_block.width;
 
-        // margin, border, and padding have initial value 0.
-        let zero = Length(0.0, Px);
+        let mut width = style.width.resolve(cb_width);
 
-        let mut margin_left = style.lookup("margin-left", "margin", &zero);
-        let mut margin_right = style.lookup("martin-right", "margin", &zero);
+        let mut margin_left = style.margin_left.resolve(cb_width);
+        let mut margin_right = style.margin_right.resolve(cb_width);
 
-        let border_left = style.lookup("border-left-width", "border-width", &zero);
-        let border_right = style.lookup("border-right-width", "border-width", &zero);
+        let border_left = style.border_left_width;
+        let border_right = style.border_right_width;
 
-        let padding_left = style.lookup("padding-left", "padding", &zero);
-        let padding_right = style.lookup("padding-right", "padding", &zero);
+        let padding_left = style.padding_left.resolve(cb_width);
+        let padding_right = style.padding_right.resolve(cb_width);
 
-        let total = [&margin_left, &margin_right, &border_left, &border_right,
-                     &padding_left, &padding_right, &width].iter().map(|v| v.to_px()).sum();
+        let total = [margin_left, margin_right, Some(border_left), Some(border_right),
+                     Some(padding_left), Some(padding_right), width].iter()
+                     .map(|v| v.unwrap_or(0.0)).sum();
 
         // If width is not auto and the total is wider than the container, treat auto margins as 0.
-        if width != auto && total > containing_block.width {
-            if margin_left == auto {
-                margin_left = Length(0.0, Px);
+        if width.is_some() && total > containing_block.width {
+            if margin_left.is_none() {
+                margin_left = Some(0.0);
             }
-            if margin_right == auto {
-                margin_right = Length(0.0, Px);
+            if margin_right.is_none() {
+                margin_right = Some(0.0);
             }
         }
 
         // http://www.w3.org/TR/CSS2/visudet.html#blockwidth
         let underflow = containing_block.width - total;
 
-        match (width == auto, margin_left == auto, margin_right == auto) {
+        match (width, margin_left, margin_right) {
             // If the values are over-constrained, calculate margin_right.
-            (false, false, false) => {
-                margin_right = Length(margin_right.to_px() + underflow, Px);
-            }
+            (Some(_), Some(_), Some(right)) => { margin_right = Some(right + underflow); }
 
             // If exactly one margin is auto, its used value follows from the equality.
-            (false, false, true) => { margin_right = Length(underflow, Px); }
-            (false, true, false) => { margin_left = Length(underflow, Px); }
+            (Some(_), Some(_), None) => { margin_right = Some(underflow); }
+            (Some(_), None, Some(_)) => { margin_left = Some(underflow); }
 
             // If width is set to auto, any other auto values become 0.
-            (true, _, _) => {
-               if margin_left == auto { margin_left = Length(0.0, Px); }
-               if margin_right == auto { margin_right = Length(0.0, Px); }
+            (None, _, _) => {
+               if margin_left.is_none() { margin_left = Some(0.0); }
+               if margin_right.is_none() { margin_right = Some(0.0); }
 
                if underflow >= 0.0 {
                    // Expand width to fill the underflow.
-                   width = Length(underflow, Px);
+                   width = Some(underflow);
                } else {
                    // Width can't be negative. Adjust the right margin instead.
-                   width = Length(0.0, Px);
-                   margin_right = Length(margin_right.to_px() + underflow, Px);
+                   width = Some(0.0);
+                   margin_right = Some(margin_right.unwrap() + underflow);
                }
             }
 
             // If margin-left and margin-right are both auto, their used values are equal.
-            (false, true, true) => {
-                margin_left = Length(underflow / 2.0, Px);
-                margin_right = Length(underflow / 2.0, Px);
+            (Some(_), None, None) => {
+                margin_left = Some(underflow / 2.0);
+                margin_right = Some(underflow / 2.0);
             }
         }
 
         let d = &mut self.dimensions;
-        d.width = width.to_px();
+        d.width = width.unwrap();
 
-        d.padding.left = padding_left.to_px();
-        d.padding.right = padding_right.to_px();
+        d.padding.left = padding_left;
+        d.padding.right = padding_right;
 
-        d.margin.left = margin_left.to_px();
-        d.margin.right = margin_right.to_px();
+        d.margin.left = margin_left.unwrap();
+        d.margin.right = margin_right.unwrap();
 
-        d.border.left = border_left.to_px();
-        d.border.right = border_right.to_px();
+        d.border.left = border_left;
+        d.border.right = border_right;
     }
 
     fn calculate_block_height(&mut self) {
-        // If height is set to an explicit length, use that exact length.
-        match self.get_style_node().value("height") {
-            Some(Length(h, Px)) => { self.dimensions.height = h; }
+        // If height is set to an explicit length, use that exact length. The height of the
+        // containing block is not known yet, so heights with percentages behave as `auto`.
+        match self.get_style_node().style.height {
+            Len(ref h) if !h.has_percentage() => { self.dimensions.height = h.resolve(0.0); }
             _ => {}
         }
     }
 
+    /// Grow a box with auto height so that its margin box is at least `height` tall.
+    fn fill_height(&mut self, height: f32) {
+        match self.get_style_node().style.height {
+            Len(ref h) if !h.has_percentage() => return,
+            _ => {}
+        }
+        let d = &mut self.dimensions;
+        let edges = d.margin_box_height() - d.height;
+        if d.height < height - edges {
+            d.height = height - edges;
+        }
+    }
+
     fn calculate_block_position(&mut self, containing_block: Dimensions) {
-        let style = self.get_style_node();
+        let style = &self.get_style_node().style;
         let d = &mut self.dimensions;
 
-        // margin, border, and padding have initial value 2.
-        let zero = Length(0.0, Px);
+        // Vertical margins and padding are percentages of the containing block's *width*. Auto
+        // vertical margins are 0.
+        let cb_width = containing_block.width;
 
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px();
+        d.margin.top = style.margin_top.resolve(cb_width).unwrap_or(0.0);
+        d.margin.bottom = style.margin_bottom.resolve(cb_width).unwrap_or(0.0);
 
-        d.border.top = style.lookup("border-top-width", "border-width", &zero).to_px();
-        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).to_px();
+        d.border.top = style.border_top_width;
+        d.border.bottom = style.border_bottom_width;
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px();
+        d.padding.top = style.padding_top.resolve(cb_width);
+        d.padding.bottom = style.padding_bottom.resolve(cb_width);
 
         // Position the box below all the previous boxes in the container.
         d.x = containing_block.x +
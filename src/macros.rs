@@ -0,0 +1,253 @@
+//! Macros for building DOM trees and stylesheets in code, e.g. for tests, without going through
+//! the parsers.
+//!
+//! ```ignore
+//! let tree = html!(div(id="main") {
+//!     p(class="inner") { "Hello, " b { "world" } }
+//!     br
+//! });
+//! let stylesheet = stylesheet!(
+//!     (div#main > p.inner), (b) { "margin": 10.0f32, "display": "block" }
+//!     (*) { "color": Color(0, 0, 0, 255) }
+//! );
+//! ```
+//!
+//! Tag, attribute, id and class names must be Rust identifiers. Text must be a string literal,
+//! plain or raw; `html!` fails on other literals, e.g. numbers or byte strings. Declaration values
+//! can be anything implementing `css::ToValue`.
+#![macro_escape]
+
+/// Build a `dom::Tree`: an element with optional attributes and children, or a text node.
+macro_rules! html(
+    ($tag:tt ( $($name:ident = $value:expr),* ) { $($children:tt)* }) => ({
+        let mut attributes = ::dom::AttrMap::new();
+        $(attributes.insert(stringify!($name).to_string(), $value.to_string());)*
+        let mut children = Vec::new();
+        html_children!(children; $($children)*);
+        ::dom::elem(stringify!($tag).to_string(), attributes, children)
+    });
+    ($tag:tt { $($children:tt)* }) => (html!($tag () { $($children)* }));
+    ($tag:tt ( $($attributes:tt)* )) => (html!($tag ( $($attributes)* ) {}));
+    ($node:tt) => (::macros::bare_node(stringify!($node)));
+)
+
+/// Push the nodes described by the tokens to `$nodes`, a vector of `dom::Tree`.
+//
+// Tags are matched as token trees rather than identifiers: an `ident` fragment aborts the
+// expansion when it meets a string literal instead of trying the next rule.
+macro_rules! html_children(
+    ($nodes:ident; ) => (());
+    ($nodes:ident; $tag:tt ( $($attributes:tt)* ) { $($children:tt)* } $($rest:tt)*) => ({
+        $nodes.push(html!($tag ( $($attributes)* ) { $($children)* }));
+        html_children!($nodes; $($rest)*);
+    });
+    ($nodes:ident; $tag:tt ( $($attributes:tt)* ) $($rest:tt)*) => ({
+        $nodes.push(html!($tag ( $($attributes)* )));
+        html_children!($nodes; $($rest)*);
+    });
+    ($nodes:ident; $tag:tt { $($children:tt)* } $($rest:tt)*) => ({
+        $nodes.push(html!($tag { $($children)* }));
+        html_children!($nodes; $($rest)*);
+    });
+    ($nodes:ident; $node:tt $($rest:tt)*) => ({
+        $nodes.push(html!($node));
+        html_children!($nodes; $($rest)*);
+    });
+)
+
+/// The node for a lone token in `html!`: a text node for a string literal, or else an empty
+/// element. The token is given as written in the source, so escapes are decoded here. Fails if
+/// the token is neither a string literal nor an identifier.
+pub fn bare_node(token: &str) -> ::dom::Tree {
+    if token.starts_with("\"") {
+        return ::dom::text(unescape(token.slice(1, token.len() - 1)));
+    }
+    if token.starts_with("r\"") || token.starts_with("r#") {
+        let hashes = token.slice_from(1).chars().take_while(|&c| c == '#').count();
+        return ::dom::text(token.slice(hashes + 2, token.len() - hashes - 1).to_string());
+    }
+    let is_identifier = token.chars().all(|c| c.is_alphanumeric() || c == '_') &&
+                        !token.char_at(0).is_digit();
+    if !is_identifier {
+        fail!("html!: {} is neither a tag name nor a string literal", token);
+    }
+    ::dom::elem(token.to_string(), ::dom::AttrMap::new(), Vec::new())
+}
+
+/// Decode the escapes of the contents of a string literal.
+fn unescape(literal: &str) -> String {
+    let mut text = String::new();
+    let mut chars = literal.chars().peekable();
+    loop {
+        let c = match chars.next() {
+            Some('\\') => chars.next().expect("html!: unterminated escape"),
+            Some(c) => {
+                text.push_char(c);
+                continue;
+            }
+            None => break
+        };
+        match c {
+            'n' => text.push_char('\n'),
+            't' => text.push_char('\t'),
+            'r' => text.push_char('\r'),
+            '0' => text.push_char('\0'),
+            '\\' | '\'' | '"' => text.push_char(c),
+            'x' | 'u' | 'U' => {
+                let digits: String = if c == 'u' && chars.peek() == Some(&'{') {
+                    chars.next();
+                    chars.by_ref().take_while(|&c| c != '}').collect()
+                } else {
+                    let count = match c { 'x' => 2, 'u' => 4, _ => 8 };
+                    chars.by_ref().take(count).collect()
+                };
+                let code = ::std::num::from_str_radix::<u32>(digits.as_slice(), 16);
+                match code.and_then(|code| ::std::char::from_u32(code)) {
+                    Some(c) => text.push_char(c),
+                    None => fail!("html!: invalid escape \\{}{}", c, digits)
+                }
+            }
+            // A line continuation: the newline and the whitespace after it are skipped.
+            '\n' => {
+                while chars.peek().map_or(false, |c| c.is_whitespace()) {
+                    chars.next();
+                }
+            }
+            c => fail!("html!: unknown escape \\{}", c)
+        }
+    }
+    text
+}
+
+/// Build a `css::Selector` from CSS-like tokens, e.g. `selector!(div.inner > p#intro)`.
+macro_rules! selector(
+    ($($tokens:tt)+) => (
+        selector_compound!(::css::Simple(::css::SimpleSelector::universal()); $($tokens)+)
+    );
+)
+
+/// Start a compound selector of `$selector`.
+macro_rules! selector_compound(
+    ($selector:expr; * $($rest:tt)*) => (selector_rest!($selector; $($rest)*));
+    ($selector:expr; . $($rest:tt)*) => (selector_rest!($selector; . $($rest)*));
+    ($selector:expr; # $($rest:tt)*) => (selector_rest!($selector; # $($rest)*));
+    ($selector:expr; $tag:ident $($rest:tt)*) => (
+        selector_rest!($selector.with_tag(stringify!($tag)); $($rest)*)
+    );
+)
+
+/// Continue a compound selector of `$selector`, or start the next one.
+macro_rules! selector_rest(
+    ($selector:expr; ) => ($selector);
+    ($selector:expr; . $class:ident $($rest:tt)*) => (
+        selector_rest!($selector.with_class(stringify!($class)); $($rest)*)
+    );
+    ($selector:expr; # $id:ident $($rest:tt)*) => (
+        selector_rest!($selector.with_id(stringify!($id)); $($rest)*)
+    );
+    ($selector:expr; > $($rest:tt)*) => (
+        selector_compound!($selector.then(::css::Child); $($rest)*)
+    );
+    ($selector:expr; $($rest:tt)+) => (
+        selector_compound!($selector.then(::css::Descendant); $($rest)+)
+    );
+)
+
+/// Build a `css::Rule`: parenthesized selectors separated by commas, then the declarations.
+macro_rules! rule(
+    ($(( $($selector:tt)+ )),+ { $($name:expr : $value:expr),* }) => ({
+        use css::ToValue;
+        let mut declarations = Vec::new();
+        $(declarations.extend(::css::Declaration::build($name, $value.to_value()).into_iter());)*
+        ::css::Rule::new(vec![$(selector!($($selector)+)),+], declarations)
+    });
+)
+
+/// Build an author `css::Stylesheet` from rules written like in `rule!`.
+macro_rules! stylesheet(
+    ($($(( $($selector:tt)+ )),+ { $($declarations:tt)* })*) => (
+        ::css::Stylesheet::new(vec![$(rule!($(( $($selector)+ )),+ { $($declarations)* })),*])
+    );
+)
+
+#[cfg(test)]
+mod tests {
+    use css::{Length, Px, Keyword};
+    use document::{Document, Doctype};
+    use dom::Dom;
+    use layout;
+    use layout::{Dimensions, BlockNode};
+    use media::{Device, Screen};
+    use parser::parse_css;
+    use std::default::Default;
+    use style::style_tree;
+    use traversal::pre_order;
+
+    #[test]
+    fn style_and_layout_of_built_trees() {
+        let tree = html!(html {
+            body {
+                div(id="main") {
+                    p(class="inner") { "Hello, " b { "world" } }
+                    br
+                }
+            }
+        });
+        let document = Document::new(Dom::new(tree), Some(Doctype::new("html")));
+        let stylesheet = stylesheet!(
+            (html), (body), (div) { "display": "block" }
+            (div#main > p.inner) { "margin": 10.0f32, "display": "block" }
+            (p b) { "display": "block", "height": 20.0f32 }
+        );
+        let stylesheets = [&stylesheet];
+        let device = Device::new(Screen, 800.0, 600.0);
+        let (style_root, _) = style_tree(&document, &stylesheets, &device);
+
+        let p = document.root().query_selector("p").unwrap().unwrap();
+        let styled_p = pre_order(&style_root).find(|styled| styled.node() == p).unwrap();
+        assert_eq!(styled_p.value("margin-left"), Some(Length(10.0, Px)));
+        assert_eq!(styled_p.value("display"), Some(Keyword("block".to_string())));
+
+        let mut viewport: Dimensions = Default::default();
+        viewport.width = 800.0;
+        viewport.height = 600.0;
+        let layout_root = layout::layout_tree(&document, &style_root, viewport);
+        let p_box = pre_order(&layout_root).find(|layout_box| match layout_box.box_type {
+            BlockNode(styled) => styled.node() == p,
+            _ => false
+        }).unwrap();
+        let d = p_box.dimensions;
+        assert_eq!((d.x, d.y, d.width, d.height), (10.0, 10.0, 780.0, 20.0));
+    }
+
+    #[test]
+    fn built_and_parsed_rules_cascade_alike() {
+        let document = Document::new(Dom::new(html!(p(id="x", class="a") {})), None);
+        let built = stylesheet!(
+            (p), (#x) { "margin": 10.0f32 }
+            (p.a) { "margin": 20.0f32 }
+        );
+        let parsed = parse_css("p, #x { margin: 10px; } p.a { margin: 20px; }".to_string());
+        let device = Device::new(Screen, 800.0, 600.0);
+        for stylesheet in [built, parsed].iter() {
+            assert_eq!(stylesheet.rules[0].selectors[0].specificity(), (1, 0, 0));
+            let stylesheets = [stylesheet];
+            let (style_root, _) = style_tree(&document, &stylesheets, &device);
+            assert_eq!(style_root.value("margin-left"), Some(Length(10.0, Px)));
+        }
+    }
+
+    #[test]
+    fn text_literals() {
+        let tree = html!(p { "a\x41\u00e9\"\\\n" r"raw\n" r#"with "quotes""# });
+        let document = Document::new(Dom::new(tree), None);
+        assert_eq!(document.root().text_content(),
+                   "aA\u00e9\"\\\nraw\\nwith \"quotes\"".to_string());
+    }
+
+    #[test]
+    #[should_fail]
+    fn other_literals_are_rejected() {
+        html!(p { 42 });
+    }
+}
@@ -1,21 +1,52 @@
+#![feature(macro_rules, unsafe_destructor)]
+
 extern crate getopts;
 
-use getopts::{optopt,getopts};
+use getopts::{optopt,optflag,getopts};
 use std::default::Default;
 use std::io::fs::File;
-use std::os::args;
+use std::io::stderr;
+use std::os::{args, num_cpus};
+
+// Declared first so that the macros are visible in the other modules.
+mod macros;
 
 mod css;
+mod custom_properties;
+mod diff;
+mod document;
 mod dom;
+mod invalidation;
 mod layout;
+mod media;
+mod parallel;
 mod parser;
+mod properties;
+mod query;
+mod rule_index;
+mod sanitizer;
+mod serializer;
+mod sharing;
 mod style;
+mod text;
+mod traversal;
+mod user_agent;
 
 fn main() {
     // Parse command-line options:
     let opts = [
         optopt("h", "html", "HTML document", "FILENAME"),
         optopt("c", "css", "CSS stylesheet", "FILENAME"),
+        optopt("m", "media", "Media type to render for (default: screen)", "TYPE"),
+        optopt("s", "serialize", "Print the stylesheet instead of rendering", "pretty|minified"),
+        optflag("n", "no-ua", "Don't apply the built-in user-agent stylesheet"),
+        optflag("p", "parallel", "Style the document on all CPUs"),
+        optopt("q", "query", "Print the computed style of the matching elements", "SELECTORS"),
+        optopt("e", "explain", "Print the cascade of the matching elements", "SELECTORS"),
+        optflag("t", "text", "Print the text of the document"),
+        optopt("d", "diff", "Print the differences from another HTML document", "FILENAME"),
+        optflag("j", "json", "Print the differences as JSON"),
+        optflag("u", "untrusted", "Sanitize the HTML document, reporting what is removed"),
     ];
     let matches = match getopts(args().tail(), opts) {
         Ok(m) => m,
@@ -23,15 +54,10 @@ fn main() {
     };
 
     // Read input files:
-    let read_source = |arg_filename: Option<String>, default_filename: &str| {
-        let path = match arg_filename {
-            Some(ref filename) => filename.as_slice(),
-            None => default_filename,
-        };
-        File::open(&Path::new(path)).read_to_string().unwrap()
-    };
-    let html = read_source(matches.opt_str("h"), "examples/test.html");
-    let css  = read_source(matches.opt_str("c"), "examples/test.css");
+    let html_path = matches.opt_str("h").unwrap_or("examples/test.html".to_string());
+    let html = File::open(&Path::new(html_path.as_slice())).read_to_string().unwrap();
+    // The stylesheet is read by the parser so it can resolve @import rules relative to it.
+    let css_path = Path::new(matches.opt_str("c").unwrap_or("examples/test.css".to_string()));
 
     // Since we don't have an actual window, hard-code the "viewport" size.
     let initial_containing_block = layout::Dimensions {
@@ -44,12 +70,113 @@ fn main() {
         margin: Default::default(),
     };
 
+    // Media queries are evaluated against the viewport.
+    let media_type = media::MediaType::from_name(matches.opt_str("m")
+                                                        .unwrap_or("screen".to_string())
+                                                        .as_slice());
+    let device = media::Device::new(media_type,
+                                    initial_containing_block.width,
+                                    initial_containing_block.height);
+
     // Parsing and rendering:
-    let root_node = parser::parse_html(html);
-    let stylesheet = parser::parse_css(css);
-    let style_root = style::style_tree(&root_node, &stylesheet);
-    let layout_root = layout::layout_tree(&style_root, initial_containing_block);
+    let mut document = parser::parse_html(html);
+    document.url = Some(html_path);
+    if matches.opt_present("u") {
+        let (tree, removals) = sanitizer::sanitize(document.root(), &sanitizer::DEFAULT_POLICY);
+        for removal in removals.iter() {
+            let _ = writeln!(&mut stderr(), "{}", removal);
+        }
+        document.dom = dom::Dom::new(tree);
+    }
+    let stylesheet = parser::parse_css_file(&css_path);
+    for diagnostic in stylesheet.diagnostics.iter() {
+        let _ = writeln!(&mut stderr(), "{}", diagnostic);
+    }
+
+    match matches.opt_str("d") {
+        Some(other_path) => {
+            let other_html = File::open(&Path::new(other_path)).read_to_string().unwrap();
+            let other = parser::parse_html(other_html);
+            let edits = diff::diff(document.root(), other.root());
+            if matches.opt_present("j") {
+                print!("{}", diff::to_json(edits.as_slice()));
+            } else {
+                print!("{}", diff::to_text(edits.as_slice()));
+            }
+            return;
+        }
+        None => {}
+    }
+
+    match matches.opt_str("s") {
+        Some(format) => {
+            let format = match format.as_slice() {
+                "pretty" => serializer::Pretty,
+                "minified" => serializer::Minified,
+                _ => fail!("Unknown stylesheet format {}", format)
+            };
+            print!("{}", serializer::to_css(&stylesheet, format));
+            return;
+        }
+        None => {}
+    }
+
+    // The user-agent stylesheet applies beneath the author stylesheet.
+    let user_agent_stylesheet = user_agent::stylesheet();
+    let mut stylesheets = vec![&stylesheet];
+    if !matches.opt_present("n") {
+        stylesheets.insert(0, &user_agent_stylesheet);
+    }
+
+    let (style_root, sharing_stats) = if matches.opt_present("p") {
+        parallel::style_tree(&document, stylesheets.as_slice(), &device, num_cpus())
+    } else {
+        style::style_tree(&document, stylesheets.as_slice(), &device)
+    };
+    let layout_root = layout::layout_tree(&document, &style_root, initial_containing_block);
+
+    match matches.opt_str("q") {
+        Some(selectors) => {
+            let elements = match query::query_selector_all(&style_root, &layout_root,
+                                                           selectors.as_slice()) {
+                Ok(elements) => elements,
+                Err(message) => {
+                    let _ = writeln!(&mut stderr(), "Invalid selector: {}", message);
+                    return;
+                }
+            };
+            for element in elements.iter() {
+                println!("{}", element);
+            }
+            return;
+        }
+        None => {}
+    }
+
+    match matches.opt_str("e") {
+        Some(selectors) => {
+            let elements = match query::query_selector_all(&style_root, &layout_root,
+                                                           selectors.as_slice()) {
+                Ok(elements) => elements,
+                Err(message) => {
+                    let _ = writeln!(&mut stderr(), "Invalid selector: {}", message);
+                    return;
+                }
+            };
+            for element in elements.iter() {
+                println!("{}", element.explain());
+            }
+            return;
+        }
+        None => {}
+    }
+
+    if matches.opt_present("t") {
+        println!("{}", text::inner_text(&style_root));
+        return;
+    }
 
     // Debug output:
     println!("{}", layout_root.dimensions);
+    println!("{}", sharing_stats);
 }
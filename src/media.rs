@@ -0,0 +1,269 @@
+//! Media queries
+//!
+//! Supported syntax (a subset of Media Queries Level 4):
+//!   * Media types: `all`, `screen`, `print`
+//!   * Qualifiers: `not`, `only`
+//!   * Conditions: `and`, `or`, `not` and nested parentheses
+//!   * Features:
+//!     - width, height (`px`, `em`, `rem`; including `min-`/`max-` prefixes and range syntax)
+//!     - orientation
+//!     - resolution (`dppx`, `x`, `dpi`, `dpcm`)
+//!     - prefers-color-scheme
+//!     - color (boolean context)
+
+/// A comma separated list of media queries. It matches if any of its queries match.
+#[deriving(Show, Clone, PartialEq)]
+pub struct MediaQueryList {
+    pub queries: Vec<MediaQuery>,
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct MediaQuery {
+    pub qualifier: Option<Qualifier>,
+    pub media_type: MediaType,
+    pub condition: Option<MediaCondition>,
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Qualifier {
+    NotQualifier,
+    OnlyQualifier,
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum MediaType {
+    AllMedia,
+    Screen,
+    Print,
+    UnknownMedia(String),
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum MediaCondition {
+    FeatureCondition(MediaFeature),
+    NotCondition(Box<MediaCondition>),
+    AndCondition(Vec<MediaCondition>),
+    OrCondition(Vec<MediaCondition>),
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum MediaFeature {
+    /// A feature in boolean context, e.g. `(color)`.
+    BooleanFeature(String),
+    /// A feature compared for equality, e.g. `(orientation: portrait)`.
+    PlainFeature(String, FeatureValue),
+    /// A feature compared against a value, e.g. `(width >= 600px)`. `min-width: 600px` is
+    /// normalized to this form. The feature is always on the left hand side.
+    RangeFeature(String, Comparison, FeatureValue),
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum FeatureValue {
+    FeatureLength(f32),     // px
+    FeatureResolution(f32), // dppx
+    FeatureNumber(f32),
+    FeatureIdent(String),
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Comparison {
+    CmpLt,
+    CmpLe,
+    CmpEq,
+    CmpGe,
+    CmpGt,
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum ColorScheme {
+    Light,
+    Dark,
+}
+
+/// The output device media queries are evaluated against.
+#[deriving(Show, Clone)]
+pub struct Device {
+    pub media_type: MediaType,
+    pub width: f32,
+    pub height: f32,
+    pub resolution: f32, // dppx
+    pub color_scheme: ColorScheme,
+}
+
+impl Device {
+    /// A screen device with the given viewport size.
+    pub fn new(media_type: MediaType, width: f32, height: f32) -> Device {
+        Device {
+            media_type: media_type,
+            width: width,
+            height: height,
+            resolution: 1.0,
+            color_scheme: Light,
+        }
+    }
+
+    fn orientation(&self) -> &'static str {
+        if self.height >= self.width { "portrait" } else { "landscape" }
+    }
+}
+
+impl MediaType {
+    pub fn from_name(name: &str) -> MediaType {
+        match name {
+            "all" => AllMedia,
+            "screen" => Screen,
+            "print" => Print,
+            _ => UnknownMedia(name.to_string())
+        }
+    }
+}
+
+impl MediaQueryList {
+    /// An empty media query list matches every device.
+    pub fn matches(&self, device: &Device) -> bool {
+        self.queries.is_empty() || self.queries.iter().any(|query| query.matches(device))
+    }
+}
+
+impl MediaQuery {
+    /// `not all`, which never matches. The parser replaces malformed queries with it.
+    pub fn not_all() -> MediaQuery {
+        MediaQuery { qualifier: Some(NotQualifier), media_type: AllMedia, condition: None }
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        let type_matches = match self.media_type {
+            AllMedia => true,
+            UnknownMedia(_) => false,
+            ref t => *t == device.media_type,
+        };
+        let result = type_matches && self.condition.iter().all(|c| c.matches(device));
+        match self.qualifier {
+            Some(NotQualifier) => !result,
+            _ => result
+        }
+    }
+}
+
+impl MediaCondition {
+    pub fn matches(&self, device: &Device) -> bool {
+        match *self {
+            FeatureCondition(ref feature) => feature.matches(device),
+            NotCondition(ref condition) => !condition.matches(device),
+            AndCondition(ref conditions) => conditions.iter().all(|c| c.matches(device)),
+            OrCondition(ref conditions) => conditions.iter().any(|c| c.matches(device)),
+        }
+    }
+}
+
+impl MediaFeature {
+    /// Unknown features and values of the wrong type never match.
+    pub fn matches(&self, device: &Device) -> bool {
+        match *self {
+            BooleanFeature(ref name) => match name.as_slice() {
+                "width" => device.width != 0.0,
+                "height" => device.height != 0.0,
+                "resolution" => device.resolution != 0.0,
+                "orientation" | "prefers-color-scheme" | "color" => true,
+                _ => false
+            },
+            PlainFeature(ref name, ref value) => match (name.as_slice(), value) {
+                ("orientation", &FeatureIdent(ref o)) => o.as_slice() == device.orientation(),
+                ("prefers-color-scheme", &FeatureIdent(ref s)) => {
+                    match (s.as_slice(), &device.color_scheme) {
+                        ("light", &Light) | ("dark", &Dark) => true,
+                        _ => false
+                    }
+                }
+                _ => self.as_range().map_or(false, |range| range.matches(device))
+            },
+            RangeFeature(ref name, ref cmp, ref value) => {
+                match (name.as_slice(), value) {
+                    ("width", &FeatureLength(v)) => compare(device.width, cmp, v),
+                    ("height", &FeatureLength(v)) => compare(device.height, cmp, v),
+                    ("resolution", &FeatureResolution(v)) => compare(device.resolution, cmp, v),
+                    _ => false
+                }
+            }
+        }
+    }
+
+    /// Check that the feature is one of the supported features, used in a context it allows and
+    /// with a value of the right type. Returns an error message otherwise.
+    pub fn check(&self) -> Result<(), String> {
+        let valid = match *self {
+            BooleanFeature(ref name) => is_known_feature(name.as_slice()),
+            PlainFeature(ref name, FeatureIdent(ref value)) => {
+                match (name.as_slice(), value.as_slice()) {
+                    ("orientation", "portrait") | ("orientation", "landscape") => true,
+                    ("prefers-color-scheme", "light") | ("prefers-color-scheme", "dark") => true,
+                    _ => false
+                }
+            }
+            PlainFeature(ref name, ref value) | RangeFeature(ref name, _, ref value) => {
+                match (name.as_slice(), value) {
+                    ("width", &FeatureLength(_)) | ("height", &FeatureLength(_)) => true,
+                    ("resolution", &FeatureResolution(_)) => true,
+                    _ => false
+                }
+            }
+        };
+        if valid {
+            Ok(())
+        } else if is_known_feature(self.name()) {
+            Err(format!("Invalid value for media feature {}", self.name()))
+        } else {
+            Err(format!("Unknown media feature {}", self.name()))
+        }
+    }
+
+    fn name(&self) -> &str {
+        match *self {
+            BooleanFeature(ref name) |
+            PlainFeature(ref name, _) |
+            RangeFeature(ref name, _, _) => name.as_slice()
+        }
+    }
+
+    /// Treat `(width: 600px)` as `(width = 600px)`.
+    fn as_range(&self) -> Option<MediaFeature> {
+        match *self {
+            PlainFeature(ref name, ref value) => {
+                Some(RangeFeature(name.clone(), CmpEq, value.clone()))
+            }
+            _ => None
+        }
+    }
+}
+
+fn is_known_feature(name: &str) -> bool {
+    match name {
+        "width" | "height" | "orientation" | "resolution" => true,
+        "prefers-color-scheme" | "color" => true,
+        _ => false
+    }
+}
+
+fn compare(actual: f32, cmp: &Comparison, expected: f32) -> bool {
+    match *cmp {
+        CmpLt => actual < expected,
+        CmpLe => actual <= expected,
+        CmpEq => actual == expected,
+        CmpGe => actual >= expected,
+        CmpGt => actual > expected,
+    }
+}
+
+impl Comparison {
+    /// The comparison with its operands swapped, used to normalize `600px < width` into
+    /// `width > 600px`.
+    pub fn flip(&self) -> Comparison {
+        match *self {
+            CmpLt => CmpGt,
+            CmpLe => CmpGe,
+            CmpEq => CmpEq,
+            CmpGe => CmpLe,
+            CmpGt => CmpLt,
+        }
+    }
+}
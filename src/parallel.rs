@@ -0,0 +1,335 @@
+//! Parallel styling of a DOM tree.
+//!
+//! The top levels of the tree are styled serially until there are enough subtrees to keep the
+//! worker tasks busy. Those subtrees are spread over the deques of the worker tasks, which steal
+//! from each other once their own deque is empty. The styled subtrees are grafted back into the
+//! style tree in tree order, so the result is the same as with `style::style_tree`.
+
+use css::Stylesheet;
+use custom_properties::CustomPropertyMap;
+use document::Document;
+use dom::{NodeRef, ElementData};
+use media::Device;
+use properties::ComputedStyle;
+use sharing::SharingStats;
+use std::any::Any;
+use std::collections::hashmap::HashMap;
+use std::kinds::marker;
+use std::mem;
+use std::sync::{Arc, Future};
+use std::sync::deque::{BufferPool, Worker, Stealer, Data, Empty, Abort};
+use std::task::TaskBuilder;
+use style::{CascadeTrace, StyledNode, StyleContext, TraversalState, style_node, style_subtree};
+use traversal::{TreeNode, Visitor, walk};
+
+/// Number of subtrees to aim for per worker task, so that the work can be balanced.
+static UNITS_PER_TASK: uint = 4;
+
+/// A subtree to be styled by a worker task.
+struct WorkUnit<'a> {
+    node: NodeRef<'a>,
+    ancestors: Vec<&'a ElementData>,
+    parent_style: Arc<ComputedStyle>,
+    inherited_custom: CustomPropertyMap,
+}
+
+/// Apply stylesheets to the tree of a document like `style::style_tree`, styling subtrees on
+/// `tasks` worker tasks.
+pub fn style_tree<'a>(document: &'a Document, stylesheets: &[&'a Stylesheet], device: &Device,
+                      tasks: uint) -> (StyledNode<'a>, SharingStats) {
+    let ctx = StyleContext::new(document, stylesheets, device);
+    let root = document.root();
+    let frontier = frontier_depth(root, tasks * UNITS_PER_TASK);
+
+    // Style the nodes above the frontier, collecting the subtrees at the frontier.
+    let mut state = TraversalState::new(&[]);
+    let mut units = Vec::new();
+    let mut styled_root = style_top(root, frontier, &mut state, &ctx,
+                                    &Arc::new(ComputedStyle::initial()), &HashMap::new(),
+                                    &mut units);
+    let mut stats = state.sharing_cache.stats.clone();
+
+    // Deal the units (by index) to the deques of the worker tasks.
+    let pool = BufferPool::new();
+    let mut workers = Vec::new();
+    let mut stealers = Vec::new();
+    for _ in range(0, tasks) {
+        let (worker, stealer) = pool.deque();
+        workers.push(worker);
+        stealers.push(stealer);
+    }
+    for position in range(0, units.len()) {
+        workers[position % tasks].push(position);
+    }
+
+    // Style the subtrees on the worker tasks, each collecting its results in its own vector.
+    let mut outputs: Vec<Vec<(uint, StyledNode<'a>, SharingStats)>> =
+        Vec::from_fn(tasks, |_| Vec::new());
+    {
+        let units = units.as_slice();
+        let stealers = stealers.as_slice();
+        let ctx = &ctx;
+        let mut scope = Scope::new();
+        for (index, (worker, output)) in workers.into_iter().zip(outputs.iter_mut()).enumerate() {
+            scope.spawn(proc() {
+                loop {
+                    let position = match next_unit(&worker, stealers, index) {
+                        Some(position) => position,
+                        None => break
+                    };
+                    let unit = &units[position];
+                    let mut state = TraversalState::new(unit.ancestors.as_slice());
+                    let styled = style_subtree(unit.node, &mut state, ctx, &unit.parent_style,
+                                               &unit.inherited_custom);
+                    output.push((position, styled, state.sharing_cache.stats));
+                }
+            });
+        }
+        scope.join();
+    }
+
+    // Graft the subtrees back in tree order.
+    let mut results = Vec::from_fn(units.len(), |_| None);
+    for output in outputs.into_iter() {
+        for (position, styled, unit_stats) in output.into_iter() {
+            stats.add(&unit_stats);
+            *results.get_mut(position) = Some(styled);
+        }
+    }
+    let subtrees: Vec<StyledNode<'a>> = results.into_iter().map(|s| s.unwrap()).collect();
+    graft(&mut styled_root, frontier, &mut subtrees.into_iter());
+    (styled_root, stats)
+}
+
+/// The next unit for the worker task with deque `own`: one of its own, or else one stolen from
+/// the other tasks. `index` is the position of the task's own stealer in `stealers`.
+fn next_unit(own: &Worker<uint>, stealers: &[Stealer<uint>], index: uint) -> Option<uint> {
+    match own.pop() {
+        Some(position) => return Some(position),
+        None => {}
+    }
+    for offset in range(1, stealers.len()) {
+        let victim = &stealers[(index + offset) % stealers.len()];
+        loop {
+            match victim.steal() {
+                Data(position) => return Some(position),
+                Abort => continue,
+                Empty => break
+            }
+        }
+    }
+    None
+}
+
+/// Worker tasks that may borrow data living for `'a`.
+///
+/// Tasks can only be spawned with `'static` procs, so `spawn` hides the borrows from the type
+/// system. This is sound because a scope can't outlive `'a` and joins all its tasks before it
+/// goes away, including when the task owning it unwinds.
+struct Scope<'a> {
+    tasks: Vec<Future<Result<(), Box<Any + Send>>>>,
+    marker: marker::ContravariantLifetime<'a>,
+}
+
+impl<'a> Scope<'a> {
+    fn new() -> Scope<'a> {
+        Scope { tasks: Vec::new(), marker: marker::ContravariantLifetime }
+    }
+
+    /// Run `f` on a new task.
+    fn spawn(&mut self, f: proc():'a) {
+        let f: proc():Send = unsafe { mem::transmute(f) };
+        self.tasks.push(TaskBuilder::new().try_future(f));
+    }
+
+    /// Wait for all the tasks to finish, and fail if one of them failed.
+    fn join(mut self) {
+        let tasks = mem::replace(&mut self.tasks, Vec::new());
+        let mut failed = false;
+        for task in tasks.into_iter() {
+            if task.unwrap().is_err() {
+                failed = true;
+            }
+        }
+        if failed {
+            fail!("A worker task failed");
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for Scope<'a> {
+    fn drop(&mut self) {
+        for task in self.tasks.iter_mut() {
+            task.get_ref();
+        }
+    }
+}
+
+/// The depth at which to split the tree: the first one with at least `target` nodes, or else the
+/// one with the most nodes.
+fn frontier_depth(root: NodeRef, target: uint) -> uint {
+    let mut level = vec![root];
+    let mut depth = 0;
+    let mut widest_depth = 0;
+    let mut widest_count = 1;
+    while !level.is_empty() {
+        if level.len() >= target {
+            return depth;
+        }
+        if level.len() > widest_count {
+            widest_depth = depth;
+            widest_count = level.len();
+        }
+        let mut next = Vec::new();
+        for node in level.iter() {
+            next.extend(node.children());
+        }
+        level = next;
+        depth += 1;
+    }
+    widest_depth
+}
+
+/// A node at or above depth `frontier`. The children of the nodes at the frontier are left out.
+struct TopNode<'a> {
+    node: NodeRef<'a>,
+    depth: uint,
+    frontier: uint,
+}
+
+impl<'a> TreeNode for TopNode<'a> {
+    fn child_nodes(&self) -> Vec<TopNode<'a>> {
+        if self.depth == self.frontier {
+            return Vec::new();
+        }
+        self.node.children().map(|child| {
+            TopNode { node: child, depth: self.depth + 1, frontier: self.frontier }
+        }).collect()
+    }
+}
+
+/// Style the nodes above depth `frontier`. The subtrees at the frontier are added to `units` and
+/// left as placeholders, to be replaced by `graft`.
+fn style_top<'a, 'b>(root: NodeRef<'a>, frontier: uint, state: &'b mut TraversalState<'a>,
+                     ctx: &'b StyleContext, parent_style: &'b Arc<ComputedStyle>,
+                     inherited_custom: &'b CustomPropertyMap, units: &'b mut Vec<WorkUnit<'a>>)
+                     -> StyledNode<'a> {
+    let mut styler = TopStyler {
+        state: state,
+        ctx: ctx,
+        parent_style: parent_style,
+        inherited_custom: inherited_custom,
+        units: units,
+        stack: Vec::new(),
+        root: None,
+    };
+    walk(TopNode { node: root, depth: 0, frontier: frontier }, &mut styler);
+    styler.root.unwrap()
+}
+
+/// Builds the top of the style tree while `walk` visits the nodes above the frontier.
+struct TopStyler<'a, 'b> {
+    state: &'b mut TraversalState<'a>,
+    ctx: &'b StyleContext<'b>,
+    // The style and custom properties of the parent of the root.
+    parent_style: &'b Arc<ComputedStyle>,
+    inherited_custom: &'b CustomPropertyMap,
+    units: &'b mut Vec<WorkUnit<'a>>,
+    // The nodes entered but not left yet, root first, with their styled children so far.
+    stack: Vec<(Arc<ComputedStyle>, CustomPropertyMap, Arc<CascadeTrace>, Vec<StyledNode<'a>>)>,
+    root: Option<StyledNode<'a>>,
+}
+
+impl<'a, 'b> Visitor<TopNode<'a>> for TopStyler<'a, 'b> {
+    fn enter(&mut self, top: TopNode<'a>) {
+        let (style, custom, cascade) = {
+            let (parent_style, inherited_custom) = match self.stack.last() {
+                Some(&(ref style, ref custom, _, _)) => (style, custom),
+                None => (self.parent_style, self.inherited_custom)
+            };
+            if top.depth == top.frontier {
+                self.units.push(WorkUnit {
+                    node: top.node,
+                    ancestors: self.state.ancestors().to_vec(),
+                    parent_style: parent_style.clone(),
+                    inherited_custom: inherited_custom.clone(),
+                });
+                (parent_style.clone(), HashMap::new(), Arc::new(HashMap::new()))
+            } else {
+                style_node(top.node, self.state, self.ctx, parent_style, inherited_custom)
+            }
+        };
+        self.state.enter(top.node);
+        self.stack.push((style, custom, cascade, Vec::new()));
+    }
+
+    fn leave(&mut self, top: TopNode<'a>) {
+        self.state.leave(top.node);
+        let (style, custom, cascade, children) = self.stack.pop().unwrap();
+        let styled = StyledNode::new(top.node, style, custom, cascade, children);
+        match self.stack.last_mut() {
+            Some(&(_, _, _, ref mut children)) => children.push(styled),
+            None => self.root = Some(styled)
+        }
+    }
+}
+
+/// Replace the placeholders at depth `frontier` with the styled subtrees, in tree order.
+fn graft<'a, I: Iterator<StyledNode<'a>>>(root: &mut StyledNode<'a>, frontier: uint,
+                                          subtrees: &mut I) {
+    // The nodes still to visit, the next one last, with their depth.
+    let mut stack = vec![(root, 0u)];
+    loop {
+        let (node, depth) = match stack.pop() {
+            Some(entry) => entry,
+            None => break
+        };
+        if depth == frontier {
+            *node = subtrees.next().unwrap();
+        } else {
+            stack.extend(node.children.iter_mut().rev().map(|child| (child, depth + 1)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media::{Device, Screen};
+    use parser::{parse_css, parse_html};
+    use style;
+    use traversal::pre_order;
+    use user_agent;
+
+    #[test]
+    fn same_styles_as_serial_styling() {
+        let mut html = String::from_str("<!DOCTYPE html><html><body id=\"main\">");
+        for i in range(0u, 60) {
+            html.push_str(format!("<div class=\"item i{}\"><p>Item <span id=\"s{}\">{}</span>\
+                                   </p><ul><li class=\"a\">x</li><li>y</li></ul></div>",
+                                  i % 3, i, i).as_slice());
+        }
+        html.push_str("</body></html>");
+        let document = parse_html(html);
+        let stylesheet = parse_css("
+            body { --gap: 3px; color: #102030; }
+            .item { margin: var(--gap); padding: 2%; }
+            .i1 > p { font-size: 20px; }
+            .i2 span { display: block; color: red; }
+            #main li.a { line-height: 24px; }
+            #s7, #s42 { height: calc(10px + 5%); }
+        ".to_string());
+        let user_agent_stylesheet = user_agent::stylesheet();
+        let stylesheets = [&user_agent_stylesheet, &stylesheet];
+        let device = Device::new(Screen, 800.0, 600.0);
+
+        let (serial, _) = style::style_tree(&document, &stylesheets, &device);
+        let (parallel, _) = super::style_tree(&document, &stylesheets, &device, 4);
+        let serial: Vec<_> = pre_order(&serial).map(|n| (n.node().id(), (*n.style).clone()))
+                                                .collect();
+        let parallel: Vec<_> = pre_order(&parallel).map(|n| (n.node().id(), (*n.style).clone()))
+                                                    .collect();
+        assert_eq!(serial.len(), parallel.len());
+        assert!(serial == parallel);
+    }
+}
@@ -1,36 +1,245 @@
 //! A simple parser for a tiny subset of HTML and CSS.
 
 use std::ascii::OwnedStrAsciiExt; // for `into_ascii_lower`
-use std::collections::hashmap::HashMap;
-use std::num::FromStrRadix;
+use std::io::fs::File;
+use std::mem;
+use std::os;
 
 use css::{Stylesheet,Rule,Selector,Simple,SimpleSelector,Declaration,Value,Keyword,Length,Unit,Color,Px};
+use css::{Complex, Combinator, Descendant, Child, Author};
+use css::{Percentage, Calc, Unparsed, UnparsedShorthand, SourceLocation, Diagnostic};
+use css::{CalcNode, CalcNumber, CalcLength, CalcPercentage, CalcSum, CalcDifference, CalcProduct};
+use css::{CalcQuotient, CalcMin, CalcMax, CalcClamp};
+use custom_properties;
+use document::{Document, Doctype};
 use dom;
+use properties;
+use media::{MediaQueryList, MediaQuery, MediaType, NotQualifier, OnlyQualifier, AllMedia};
+use media::{MediaCondition, FeatureCondition, NotCondition, AndCondition, OrCondition};
+use media::{BooleanFeature, PlainFeature, RangeFeature};
+use media::{FeatureValue, FeatureLength, FeatureResolution, FeatureNumber, FeatureIdent};
+use media::{Comparison, CmpLt, CmpLe, CmpEq, CmpGe, CmpGt};
 
-/// Parse an HTML document and return the root element.
-pub fn parse_html(source: String) -> dom::Node {
-    let mut nodes = Parser { pos: 0u, input: source }.parse_nodes();
+/// Parse an HTML document.
+pub fn parse_html(source: String) -> Document {
+    let mut parser = Parser::new(source);
+    let doctype = parser.parse_doctype();
+    let mut nodes = parser.parse_nodes();
 
-    // If the document contains a root element, just return it. Otherwise create one.
-    if nodes.len() == 1 {
+    // If the document contains a root element, just use it. Otherwise create one.
+    let root = if nodes.len() == 1 {
         nodes.swap_remove(0).unwrap()
     } else {
-        dom::elem("html".to_string(), HashMap::new(), nodes)
+        dom::elem("html".to_string(), dom::AttrMap::new(), nodes)
+    };
+    Document::new(dom::Dom::new(root), doctype)
+}
+
+/// Parse a single property value, e.g. the result of `var()` substitution. Returns `None` if
+/// `source` is not a valid value.
+pub fn parse_value(source: String) -> Option<Value> {
+    let mut parser = Parser::new(source);
+    parser.consume_whitespace();
+    let value = parser.parse_value();
+    parser.consume_whitespace();
+    if parser.eof() { value } else { None }
+}
+
+/// Parse the whitespace separated values of a declaration of property `name`. Returns `None` if
+/// one of them is not valid for the property, or if a CSS-wide keyword such as `inherit` is not
+/// the only value.
+pub fn parse_values(name: &str, source: String) -> Option<Vec<Value>> {
+    let mut parser = Parser::new(source);
+    let mut values = Vec::new();
+    loop {
+        parser.consume_whitespace();
+        if parser.eof() {
+            let css_wide = values.iter().any(|value| match *value {
+                Keyword(ref k) => properties::is_css_wide_keyword(k.as_slice()),
+                _ => false
+            });
+            return if css_wide && values.len() > 1 { None } else { Some(values) };
+        }
+        match parser.parse_value() {
+            Some(value) if properties::is_supported(name, &value) => values.push(value),
+            _ => return None
+        }
+    }
+}
+
+/// Parse `source` as the value of shorthand `shorthand` and return the value it gives to its
+/// longhand `longhand`, e.g. after `var()` substitution. Returns `None` if `source` is not a valid
+/// value for the shorthand.
+pub fn parse_longhand_value(shorthand: &str, longhand: &str, source: String) -> Option<Value> {
+    let longhands = match properties::expand_shorthand(shorthand) {
+        Some(longhands) => longhands,
+        None => return None
+    };
+    let index = match longhands.iter().position(|&name| name == longhand) {
+        Some(index) => index,
+        None => return None
+    };
+    parse_values(shorthand, source).and_then(|values| distribute(longhands.len(), values))
+                                   .map(|values| values[index].clone())
+}
+
+/// Assign the values of a declaration to the `count` longhands it sets. A single value sets all of
+/// them, and the shorthands for the four sides take up to four values, e.g. `margin: 0 auto`.
+/// Returns `None` if there are too many values.
+fn distribute(count: uint, values: Vec<Value>) -> Option<Vec<Value>> {
+    let sides = match (count, values.len()) {
+        (n, 1) => Vec::from_elem(n, 0u),
+        (4, 2) => vec![0, 1, 0, 1],
+        (4, 3) => vec![0, 1, 2, 1],
+        (4, 4) => vec![0, 1, 2, 3],
+        _ => return None
+    };
+    Some(sides.into_iter().map(|i| values[i].clone()).collect())
+}
+
+/// Parse a comma separated list of selectors, e.g. to query the style tree. Returns an error
+/// message if `source` is not a valid selector list.
+pub fn parse_selectors(source: String) -> Result<Vec<Selector>, String> {
+    let mut parser = Parser::new(source);
+    parser.consume_whitespace();
+    let selectors = try!(parser.parse_selectors());
+    parser.consume_whitespace();
+    if !parser.eof() {
+        return Err(format!("Unexpected character {} in selector list", parser.next_char()));
     }
+    Ok(selectors)
 }
 
-/// Parse a whole CSS stylesheet.
+/// Maximum nesting depth of `@import` rules.
+static MAX_IMPORT_DEPTH: uint = 16;
+
+/// Maximum nesting depth of math functions and parenthesized expressions in values.
+static MAX_MATH_DEPTH: uint = 32;
+
+/// Parse a whole CSS stylesheet. `@import` rules are resolved relative to the current directory.
 pub fn parse_css(source: String) -> Stylesheet {
-    let mut parser = Parser { pos: 0u, input: source };
-    Stylesheet { rules: parser.parse_rules() }
+    Parser::new(source).parse_stylesheet()
+}
+
+/// Like `parse_css`, naming the stylesheet `source_name` in source locations.
+pub fn parse_css_named(source: String, source_name: &str) -> Stylesheet {
+    let mut parser = Parser::new(source);
+    parser.source_name = source_name.to_string();
+    parser.parse_stylesheet()
+}
+
+/// Parse the CSS stylesheet stored in the file at `path`. `@import` rules are resolved relative
+/// to the file.
+pub fn parse_css_file(path: &Path) -> Stylesheet {
+    match Parser::for_file(path, Vec::new()) {
+        Ok(mut parser) => parser.parse_stylesheet(),
+        Err(message) => fail!(message)
+    }
 }
 
 struct Parser {
     pos: uint,
     input: String,
+    // Name of the input and the offset of the start of each of its lines, for source locations.
+    source_name: String,
+    line_starts: Vec<uint>,
+
+    // CSS only: directory that `@import` URLs are relative to, and the chain of stylesheets that
+    // imported the one being parsed (used to detect cycles).
+    base: Path,
+    import_stack: Vec<Path>,
+
+    // CSS only: cascade layers declared so far, the layer of the block being parsed and the
+    // number of anonymous layers, used to name them.
+    layers: Vec<String>,
+    layer: Option<String>,
+    anonymous_layers: uint,
+
+    // CSS only: what was skipped so far.
+    diagnostics: Vec<Diagnostic>,
+
+    // CSS only: number of math functions the value being parsed is nested in.
+    math_depth: uint,
 }
 
 impl Parser {
+    fn new(input: String) -> Parser {
+        let mut line_starts = vec![0u];
+        line_starts.extend(input.as_slice().char_indices()
+                                .filter(|&(_, c)| c == '\n')
+                                .map(|(i, _)| i + 1));
+        Parser {
+            pos: 0u,
+            input: input,
+            source_name: "<inline>".to_string(),
+            line_starts: line_starts,
+            base: Path::new("."),
+            import_stack: Vec::new(),
+            layers: Vec::new(),
+            layer: None,
+            anonymous_layers: 0,
+            diagnostics: Vec::new(),
+            math_depth: 0,
+        }
+    }
+
+    /// Create a parser for the stylesheet at `path`, imported through the chain of files in
+    /// `import_stack`. Returns an error message if the stylesheet can't be imported.
+    fn for_file(path: &Path, mut import_stack: Vec<Path>) -> Result<Parser, String> {
+        let source_name = path.display().to_string();
+        let path = os::make_absolute(path);
+        if import_stack.contains(&path) {
+            import_stack.push(path);
+            let chain: Vec<String> = import_stack.iter().map(|p| p.display().to_string()).collect();
+            return Err(format!("@import cycle: {}", chain.connect(" -> ")));
+        }
+        if import_stack.len() >= MAX_IMPORT_DEPTH {
+            return Err(format!("@import nesting is deeper than {} levels at {}",
+                               MAX_IMPORT_DEPTH, path.display()));
+        }
+
+        let source = match File::open(&path).read_to_string() {
+            Ok(source) => source,
+            Err(e) => return Err(format!("Could not read stylesheet {}: {}", path.display(), e))
+        };
+        let mut parser = Parser::new(source);
+        parser.source_name = source_name;
+        parser.base = path.dir_path();
+        import_stack.push(path);
+        parser.import_stack = import_stack;
+        Ok(parser)
+    }
+
+    /// The line and column of the current position, counted from 1.
+    fn location(&self) -> SourceLocation {
+        // Find the last line starting at or before the current position.
+        let mut low = 0;
+        let mut high = self.line_starts.len();
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if self.line_starts[mid] <= self.pos { low = mid } else { high = mid }
+        }
+        SourceLocation {
+            source: self.source_name.clone(),
+            line: low + 1,
+            column: self.pos - self.line_starts[low] + 1,
+        }
+    }
+
+    /// Record that something was skipped at `location`.
+    fn warn(&mut self, location: SourceLocation, message: String) {
+        self.diagnostics.push(Diagnostic { location: location, message: message });
+    }
+
+    /// An error message for the next character, or the end of input, in `context`.
+    fn unexpected(&self, context: &str) -> String {
+        if self.eof() {
+            format!("Unexpected end of input in {}", context)
+        } else {
+            format!("Unexpected character {} in {}", self.next_char(), context)
+        }
+    }
+
     /// Read the next character without consuming it.
     fn next_char(&self) -> char {
         self.input.as_slice().char_at(self.pos)
@@ -77,8 +286,62 @@ impl Parser {
         })
     }
 
+    /// Parse the doctype at the start of a document, if any.
+    /// Comments before it are skipped; other input is left alone.
+    fn parse_doctype(&mut self) -> Option<Doctype> {
+        self.consume_whitespace();
+        while self.starts_with("<!--") {
+            match self.input.as_slice().slice_from(self.pos + 4).find_str("-->") {
+                Some(end) => self.pos += 4 + end + 3,
+                None => self.pos = self.input.len()
+            }
+            self.consume_whitespace();
+        }
+        let start = self.pos;
+        if !self.starts_with("<!") {
+            return None;
+        }
+        self.pos += 2;
+        let keyword = self.parse_tag_name().into_ascii_lower();
+        if keyword.as_slice() != "doctype" {
+            self.pos = start;
+            return None;
+        }
+        self.consume_whitespace();
+        let name = self.consume_while(|c| c != '>' && !c.is_whitespace()).into_ascii_lower();
+        self.consume_whitespace();
+        let (public_id, system_id) = match self.parse_tag_name().into_ascii_lower().as_slice() {
+            "public" => {
+                let public_id = self.parse_doctype_id();
+                (public_id, self.parse_doctype_id())
+            }
+            "system" => (None, self.parse_doctype_id()),
+            _ => (None, None)
+        };
+        // Ignore anything else up to the end of the doctype.
+        self.consume_while(|c| c != '>');
+        if !self.eof() {
+            self.consume_char();
+        }
+        Some(Doctype { name: name, public_id: public_id, system_id: system_id })
+    }
+
+    /// Parse a quoted public or system identifier of a doctype, if any.
+    fn parse_doctype_id(&mut self) -> Option<String> {
+        self.consume_whitespace();
+        if self.eof() || (self.next_char() != '"' && self.next_char() != '\'') {
+            return None;
+        }
+        let quote = self.consume_char();
+        let id = self.consume_while(|c| c != quote && c != '>');
+        if self.starts_with(quote.to_string().as_slice()) {
+            self.consume_char();
+        }
+        Some(id)
+    }
+
     /// Parse a single node.
-    fn parse_node(&mut self) -> dom::Node {
+    fn parse_node(&mut self) -> dom::Tree {
         match self.next_char() {
             '<' => self.parse_element(),
             _   => self.parse_text()
@@ -86,12 +349,12 @@ impl Parser {
     }
 
     /// Parse a text node.
-    fn parse_text(&mut self) -> dom::Node {
+    fn parse_text(&mut self) -> dom::Tree {
         dom::text(self.consume_while(|c| c != '<'))
     }
 
     /// Parse a single element inlcuding its open tag, contents and closing tag.
-    fn parse_element(&mut self) -> dom::Node {
+    fn parse_element(&mut self) -> dom::Tree {
         // Opening tag
         assert!(self.consume_char() == '<');
         let tag_name = self.parse_tag_name();
@@ -129,7 +392,7 @@ impl Parser {
 
     /// Parse attributes.
     fn parse_attributes(&mut self) -> dom::AttrMap {
-        let mut attributes = HashMap::new();
+        let mut attributes = dom::AttrMap::new();
         loop {
             self.consume_whitespace();
             if self.next_char() == '>' {
@@ -142,7 +405,7 @@ impl Parser {
     }
 
     /// Parse a sequence of sibling nodes.
-    fn parse_nodes(&mut self) -> Vec<dom::Node> {
+    fn parse_nodes(&mut self) -> Vec<dom::Tree> {
         let mut nodes = vec!();
         loop {
             self.consume_whitespace();
@@ -156,59 +419,813 @@ impl Parser {
 
     // Parse CSS
 
-    /// Parse a list of rules separated by optional whitespace.
+    /// Parse a whole stylesheet: any number of `@import` rules followed by a list of rules.
+    fn parse_stylesheet(&mut self) -> Stylesheet {
+        let mut rules = Vec::new();
+        loop {
+            self.consume_whitespace();
+            if !self.starts_import_rule() {
+                break;
+            }
+            rules.extend(self.parse_import_rule().into_iter());
+        }
+        rules.extend(self.parse_rules().into_iter());
+        if !self.eof() {
+            fail!("Unexpected '}}' in stylesheet");
+        }
+        Stylesheet {
+            rules: rules,
+            layers: self.layers.clone(),
+            origin: Author,
+            diagnostics: mem::replace(&mut self.diagnostics, Vec::new()),
+        }
+    }
+
+    /// Does an `@import` rule start here?
+    fn starts_import_rule(&self) -> bool {
+        let rest = self.input.as_slice().slice_from(self.pos);
+        rest.starts_with("@import") &&
+            !rest.slice_from(7).chars().next().map_or(false, valid_identifier_char)
+    }
+
+    /// Parse `@import <url> <media-query-list>?;` and return the rules of the imported stylesheet,
+    /// which take the place of the @import rule in the cascade. Malformed rules and stylesheets
+    /// that can't be imported, e.g. because of a cycle, are skipped.
+    fn parse_import_rule(&mut self) -> Vec<Rule> {
+        let location = self.location();
+        self.pos = self.pos + "@import".len();
+        self.consume_whitespace();
+        let url = match self.parse_url() {
+            Ok(url) => url,
+            Err(message) => {
+                self.warn(location, format!("{}; @import skipped", message));
+                self.skip_rule();
+                return Vec::new();
+            }
+        };
+        let media = self.parse_media_query_list();
+        if !self.eof() {
+            if !self.starts_with(";") {
+                let message = self.unexpected("@import rule");
+                self.warn(location, format!("{}; @import skipped", message));
+                self.skip_rule();
+                return Vec::new();
+            }
+            self.consume_char();
+        }
+
+        let path = self.base.join(url);
+        let mut parser = match Parser::for_file(&path, self.import_stack.clone()) {
+            Ok(parser) => parser,
+            Err(message) => {
+                self.warn(location, format!("{}; @import skipped", message));
+                return Vec::new();
+            }
+        };
+        parser.anonymous_layers = self.anonymous_layers;
+        let mut stylesheet = parser.parse_stylesheet();
+        self.anonymous_layers = parser.anonymous_layers;
+        self.diagnostics.extend(stylesheet.diagnostics.into_iter());
+        for layer in stylesheet.layers.iter() {
+            self.declare_layer(layer.as_slice());
+        }
+        if !media.queries.is_empty() {
+            for rule in stylesheet.rules.iter_mut() {
+                rule.media.insert(0, media.clone());
+            }
+        }
+        stylesheet.rules
+    }
+
+    /// Parse a quoted string or `url(...)`.
+    fn parse_url(&mut self) -> Result<String, String> {
+        if !self.starts_with("url(") {
+            return self.parse_string();
+        }
+        self.pos = self.pos + 4;
+        self.consume_whitespace();
+        let url = if self.starts_with("\"") || self.starts_with("'") {
+            try!(self.parse_string())
+        } else {
+            self.consume_while(|c| c != ')' && !c.is_whitespace())
+        };
+        self.consume_whitespace();
+        if !self.starts_with(")") {
+            return Err(self.unexpected("url()"));
+        }
+        self.consume_char();
+        Ok(url)
+    }
+
+    /// Parse a string in single or double quotes and return its contents.
+    fn parse_string(&mut self) -> Result<String, String> {
+        if !self.starts_with("\"") && !self.starts_with("'") {
+            return Err(match self.eof() {
+                true => "Expected a string or url()".to_string(),
+                false => format!("Expected a string or url(), found {}", self.next_char())
+            });
+        }
+        let quote = self.consume_char();
+        let value = self.consume_while(|c| c != quote && c != '\n');
+        if !self.starts_with(quote.to_string().as_slice()) {
+            return Err("Unterminated string".to_string());
+        }
+        self.consume_char();
+        Ok(value)
+    }
+
+    /// Parse a list of rules separated by optional whitespace, up to the end of the input or the
+    /// `}` closing the enclosing block.
     fn parse_rules(&mut self) -> Vec<Rule> {
         let mut rules = Vec::new();
         loop {
             self.consume_whitespace();
-            if self.eof() {
+            if self.eof() || self.next_char() == '}' {
                 break;
             }
-            rules.push(self.parse_rule());
+            if self.next_char() == '@' {
+                rules.extend(self.parse_at_rule(None).into_iter());
+            } else {
+                rules.extend(self.parse_rule().into_iter());
+            }
         }
         rules
     }
 
-    /// Parse a rule set: `<selectors> { <declarations> }`.
-    fn  parse_rule(&mut self) -> Rule {
-        Rule {
-            selectors: self.parse_selectors(),
-            declarations: self.parse_declarations(),
+    /// Parse a rule set: `<selectors> { <declarations> }`. Nested rules are returned after it.
+    /// Rules with an invalid selector list are skipped.
+    fn parse_rule(&mut self) -> Vec<Rule> {
+        let location = self.location();
+        match self.parse_selectors() {
+            Ok(selectors) => self.parse_style_block(selectors),
+            Err(message) => {
+                self.warn(location, format!("{}; rule skipped", message));
+                self.skip_rule();
+                Vec::new()
+            }
         }
     }
 
-    // Parse a comma separated list of selectors.
-    fn parse_selectors(&mut self) -> Vec<Selector> {
-        let mut selectors = Vec::new();
+    /// Parse an at-rule, returning the rules it contributes to the enclosing block. `parent` is
+    /// the selector list of the style rule the at-rule is nested in, if any. Unsupported at-rules
+    /// are skipped.
+    fn parse_at_rule(&mut self, parent: Option<&[Selector]>) -> Vec<Rule> {
+        let location = self.location();
+        assert!(self.consume_char() == '@');
+        let name = self.parse_identifier().into_ascii_lower();
+        self.consume_whitespace();
+        match name.as_slice() {
+            "media" => self.parse_media_rule(parent),
+            "supports" => self.parse_supports_rule(parent),
+            "layer" => self.parse_layer_rule(parent),
+            _ => {
+                let message = match name.as_slice() {
+                    "import" => "@import must precede all other rules".to_string(),
+                    _ => format!("Unsupported at-rule @{}", name)
+                };
+                self.warn(location, format!("{}; skipped", message));
+                self.skip_rule();
+                Vec::new()
+            }
+        }
+    }
+
+    /// Skip the rest of an invalid rule: its prelude up to a `;`, or its `{ }` block.
+    fn skip_rule(&mut self) {
+        let mut depth = 0u;
+        while !self.eof() {
+            match self.next_char() {
+                ';' if depth == 0 => {
+                    self.consume_char();
+                    return;
+                }
+                // The end of the enclosing block.
+                '}' if depth == 0 => return,
+                '{' => depth = depth + 1,
+                '}' => {
+                    depth = depth - 1;
+                    if depth == 0 {
+                        self.consume_char();
+                        return;
+                    }
+                }
+                _ => {}
+            }
+            self.consume_char();
+        }
+    }
+
+    /// Parse the `{ }` block of a conditional at-rule: a list of rules, or the declarations and
+    /// nested rules of the style rule with selectors `parent` that the at-rule is nested in.
+    fn parse_conditional_block(&mut self, parent: Option<&[Selector]>) -> Vec<Rule> {
+        match parent {
+            Some(selectors) => self.parse_style_block(selectors.to_vec()),
+            None => {
+                assert!(self.consume_char() == '{');
+                let rules = self.parse_rules();
+                // A block left open at the end of input is closed implicitly.
+                if !self.eof() {
+                    self.consume_char();
+                }
+                rules
+            }
+        }
+    }
+
+    /// Parse `@media <media-query-list> { <rules> }`. The query list is attached to every rule in
+    /// the block.
+    fn parse_media_rule(&mut self, parent: Option<&[Selector]>) -> Vec<Rule> {
+        let media = self.parse_media_query_list();
+        if !self.starts_with("{") {
+            let location = self.location();
+            let message = self.unexpected("@media rule");
+            self.warn(location, format!("{}; rule skipped", message));
+            self.skip_rule();
+            return Vec::new();
+        }
+        let mut rules = self.parse_conditional_block(parent);
+        for rule in rules.iter_mut() {
+            rule.media.insert(0, media.clone());
+        }
+        rules
+    }
+
+    /// Parse `@supports <condition> { <rules> }`. The rules are dropped unless the condition
+    /// holds. Rules with a malformed condition are skipped.
+    fn parse_supports_rule(&mut self, parent: Option<&[Selector]>) -> Vec<Rule> {
+        let location = self.location();
+        let condition = match self.parse_supports_condition() {
+            Ok(_) if !self.starts_with("{") => Err(self.unexpected("@supports condition")),
+            condition => condition
+        };
+        match condition {
+            Ok(supported) => {
+                let rules = self.parse_conditional_block(parent);
+                if supported { rules } else { Vec::new() }
+            }
+            Err(message) => {
+                self.warn(location, format!("{}; rule skipped", message));
+                self.skip_rule();
+                Vec::new()
+            }
+        }
+    }
+
+    /// Parse and evaluate `not <supports-in-parens>` or `<supports-in-parens>` followed by any
+    /// number of `and` or `or` clauses.
+    fn parse_supports_condition(&mut self) -> Result<bool, String> {
+        if self.consume_keyword("not") {
+            return Ok(!try!(self.parse_supports_in_parens()));
+        }
+        let mut result = try!(self.parse_supports_in_parens());
+        if self.consume_keyword("and") {
+            loop {
+                result = try!(self.parse_supports_in_parens()) && result;
+                if !self.consume_keyword("and") {
+                    break;
+                }
+            }
+        } else if self.consume_keyword("or") {
+            loop {
+                result = try!(self.parse_supports_in_parens()) || result;
+                if !self.consume_keyword("or") {
+                    break;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Parse and evaluate a parenthesized condition or `(<property>: <value>)`. The declaration
+    /// is supported if drawr implements the property with that value. Other functions and
+    /// parenthesized text, e.g. `selector(p)` or `(display)`, are unknown conditions, which are
+    /// false.
+    fn parse_supports_in_parens(&mut self) -> Result<bool, String> {
+        let location = self.location();
+        let function = self.parse_identifier();
+        if !self.starts_with("(") {
+            return Err(self.unexpected("@supports condition"));
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        let start = self.pos;
+        let nested = self.starts_with("(") ||
+                     (self.consume_keyword("not") && self.starts_with("("));
+        self.pos = start;
+        let name = self.parse_identifier();
+        self.consume_whitespace();
+        let result = if function.is_empty() && nested {
+            self.pos = start;
+            try!(self.parse_supports_condition())
+        } else if function.is_empty() && !name.is_empty() && self.starts_with(":") {
+            self.consume_char();
+            let value = self.consume_enclosed();
+            parse_value(value).map_or(false, |v| properties::is_supported(name.as_slice(), &v))
+        } else {
+            self.consume_enclosed();
+            self.warn(location, "Unknown @supports condition; treated as false".to_string());
+            false
+        };
+        self.consume_whitespace();
+        if !self.starts_with(")") {
+            return Err(self.unexpected("@supports condition"));
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        Ok(result)
+    }
+
+    /// Consume the input up to the `)` closing the current parenthesized group, or the end of
+    /// input.
+    fn consume_enclosed(&mut self) -> String {
+        let mut result = String::new();
+        let mut depth = 0u;
+        while !self.eof() && !(depth == 0 && self.next_char() == ')') {
+            match self.next_char() {
+                '(' => depth = depth + 1,
+                ')' => depth = depth - 1,
+                _ => {}
+            }
+            result.push_char(self.consume_char());
+        }
+        result
+    }
+
+    /// Parse `@layer <name> [, <name>]*;` or `@layer <name>? { <rules> }`. Malformed rules are
+    /// skipped.
+    fn parse_layer_rule(&mut self, parent: Option<&[Selector]>) -> Vec<Rule> {
+        let location = self.location();
+        let mut names = match self.parse_layer_names() {
+            Ok(names) => names,
+            Err(message) => {
+                self.warn(location, format!("{}; rule skipped", message));
+                self.skip_rule();
+                return Vec::new();
+            }
+        };
+        if self.next_char() == ';' {
+            self.consume_char();
+            for name in names.iter() {
+                self.declare_layer(name.as_slice());
+            }
+            return Vec::new();
+        }
+
+        let name = match names.len() {
+            0 => {
+                self.anonymous_layers = self.anonymous_layers + 1;
+                format!("-drawr-anonymous-layer-{}", self.anonymous_layers)
+            }
+            1 => names.pop().unwrap(),
+            _ => {
+                self.warn(location, "A @layer block can only have one name; rule skipped"
+                                        .to_string());
+                self.skip_rule();
+                return Vec::new();
+            }
+        };
+        let layer = self.declare_layer(name.as_slice());
+        let outer_layer = mem::replace(&mut self.layer, Some(layer));
+        let rules = self.parse_conditional_block(parent);
+        self.layer = outer_layer;
+        rules
+    }
+
+    /// Parse the comma separated layer names of a `@layer` rule, up to the `;` or `{` after them.
+    /// A block may have no name; a statement needs at least one.
+    fn parse_layer_names(&mut self) -> Result<Vec<String>, String> {
+        let mut names = Vec::new();
+        if self.starts_with("{") {
+            return Ok(names);
+        }
         loop {
-            selectors.push(Simple(self.parse_simple_selector()));
+            let name = self.consume_while(|c| valid_identifier_char(c) || c == '.');
+            if name.is_empty() {
+                return Err(self.unexpected("@layer rule"));
+            }
+            if name.as_slice().split('.').any(|segment| segment.is_empty()) {
+                return Err(format!("Invalid layer name {}", name));
+            }
+            names.push(name);
             self.consume_whitespace();
+            if self.eof() {
+                return Err(self.unexpected("@layer rule"));
+            }
             match self.next_char() {
+                ';' | '{' => return Ok(names),
                 ',' => {
                     self.consume_char();
-                    self.consume_whitespace()
+                    self.consume_whitespace();
+                }
+                _ => return Err(self.unexpected("@layer rule"))
+            }
+        }
+    }
+
+    /// Declare layer `name` (and its parent layers) inside the current layer, unless they were
+    /// declared before. Returns the full name of the layer.
+    fn declare_layer(&mut self, name: &str) -> String {
+        let mut full_name = self.layer.clone().unwrap_or(String::new());
+        for segment in name.split('.') {
+            if !full_name.is_empty() {
+                full_name.push_char('.');
+            }
+            full_name.push_str(segment);
+            if !self.layers.contains(&full_name) {
+                self.layers.push(full_name.clone());
+            }
+        }
+        full_name
+    }
+
+    /// Parse a comma separated list of media queries, up to a `{`, `;` or the end of input.
+    /// Malformed queries are replaced with `not all`, which never matches.
+    fn parse_media_query_list(&mut self) -> MediaQueryList {
+        let mut queries = Vec::new();
+        self.consume_whitespace();
+        if self.eof() || self.starts_with("{") || self.starts_with(";") {
+            return MediaQueryList { queries: queries };
+        }
+        loop {
+            let location = self.location();
+            match self.parse_media_query() {
+                Ok(query) => queries.push(query),
+                Err(message) => {
+                    self.warn(location, format!("{}; query replaced with `not all`", message));
+                    self.skip_media_query();
+                    queries.push(MediaQuery::not_all());
                 }
-                '{' => break,
-                c   => fail!("Unexpected character {} in selector list", c)
+            }
+            if !self.starts_with(",") {
+                break;
+            }
+            self.consume_char();
+            self.consume_whitespace();
+        }
+        MediaQueryList { queries: queries }
+    }
+
+    /// Skip the rest of a malformed media query, up to the `,` after it or the `{` or `;` that
+    /// ends the list.
+    fn skip_media_query(&mut self) {
+        let mut depth = 0u;
+        while !self.eof() {
+            match self.next_char() {
+                '{' | ';' => return,
+                ',' if depth == 0 => return,
+                '(' => depth = depth + 1,
+                ')' if depth > 0 => depth = depth - 1,
+                _ => {}
+            }
+            self.consume_char();
+        }
+    }
+
+    /// Parse `[not | only]? <media-type> [and <media-condition>]?` or `<media-condition>`.
+    fn parse_media_query(&mut self) -> Result<MediaQuery, String> {
+        let mut query = MediaQuery { qualifier: None, media_type: AllMedia, condition: None };
+        if self.starts_with("(") {
+            query.condition = Some(try!(self.parse_media_condition(true)));
+            return self.end_media_query(query);
+        }
+
+        let mut name = try!(self.parse_media_keyword());
+        if name.as_slice() == "not" && self.starts_with("(") {
+            query.condition = Some(NotCondition(box try!(self.parse_media_in_parens())));
+            return self.end_media_query(query);
+        }
+        query.qualifier = match name.as_slice() {
+            "not" => Some(NotQualifier),
+            "only" => Some(OnlyQualifier),
+            _ => None
+        };
+        if query.qualifier.is_some() {
+            name = try!(self.parse_media_keyword());
+        }
+        query.media_type = MediaType::from_name(name.as_slice());
+
+        if self.consume_keyword("and") {
+            query.condition = Some(try!(self.parse_media_condition(false)));
+        }
+        self.end_media_query(query)
+    }
+
+    /// Parse a media type or qualifier in lowercase, and the whitespace after it.
+    fn parse_media_keyword(&mut self) -> Result<String, String> {
+        let name = self.parse_identifier().into_ascii_lower();
+        if name.is_empty() {
+            return Err(self.unexpected("media query"));
+        }
+        self.consume_whitespace();
+        Ok(name)
+    }
+
+    /// Return `query` if it is followed by the `,`, `{`, `;` or end of input that ends it.
+    fn end_media_query(&mut self, query: MediaQuery) -> Result<MediaQuery, String> {
+        self.consume_whitespace();
+        if self.eof() || self.starts_with(",") || self.starts_with("{") || self.starts_with(";") {
+            Ok(query)
+        } else {
+            Err(self.unexpected("media query"))
+        }
+    }
+
+    /// Parse `not <media-in-parens>` or `<media-in-parens>` followed by any number of `and` or
+    /// (if `allow_or` is set) `or` clauses.
+    fn parse_media_condition(&mut self, allow_or: bool) -> Result<MediaCondition, String> {
+        if self.consume_keyword("not") {
+            return Ok(NotCondition(box try!(self.parse_media_in_parens())));
+        }
+        let mut conditions = vec![try!(self.parse_media_in_parens())];
+        if self.consume_keyword("and") {
+            loop {
+                conditions.push(try!(self.parse_media_in_parens()));
+                if !self.consume_keyword("and") {
+                    break;
+                }
+            }
+            Ok(AndCondition(conditions))
+        } else if allow_or && self.consume_keyword("or") {
+            loop {
+                conditions.push(try!(self.parse_media_in_parens()));
+                if !self.consume_keyword("or") {
+                    break;
+                }
+            }
+            Ok(OrCondition(conditions))
+        } else {
+            Ok(conditions.pop().unwrap())
+        }
+    }
+
+    /// Parse a parenthesized media condition or media feature.
+    fn parse_media_in_parens(&mut self) -> Result<MediaCondition, String> {
+        if !self.starts_with("(") {
+            return Err(self.unexpected("media condition"));
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        let start = self.pos;
+        let nested = self.starts_with("(") ||
+                     (self.consume_keyword("not") && self.starts_with("("));
+        self.pos = start;
+        let condition = if nested {
+            try!(self.parse_media_condition(true))
+        } else {
+            try!(self.parse_media_feature())
+        };
+        self.consume_whitespace();
+        if !self.starts_with(")") {
+            return Err(self.unexpected("media condition"));
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        Ok(condition)
+    }
+
+    /// Parse the contents of a media feature: `name`, `name: value`, `name <op> value`,
+    /// `value <op> name` or `value <op> name <op> value`. Unknown features and values of the
+    /// wrong type are errors.
+    fn parse_media_feature(&mut self) -> Result<MediaCondition, String> {
+        if self.starts_number() {
+            let low = try!(self.parse_feature_value());
+            self.consume_whitespace();
+            let low_cmp = try!(self.parse_comparison()).flip();
+            let name = try!(self.parse_feature_name());
+            let low_feature = RangeFeature(name.clone(), low_cmp, low);
+            try!(low_feature.check());
+            if self.starts_with(")") {
+                return Ok(FeatureCondition(low_feature));
+            }
+            let high_cmp = try!(self.parse_comparison());
+            let high_feature = RangeFeature(name, high_cmp, try!(self.parse_feature_value()));
+            try!(high_feature.check());
+            return Ok(AndCondition(vec![FeatureCondition(low_feature),
+                                        FeatureCondition(high_feature)]));
+        }
+
+        let name = try!(self.parse_feature_name());
+        let feature = if self.starts_with(")") {
+            BooleanFeature(name)
+        } else if self.starts_with(":") {
+            self.consume_char();
+            self.consume_whitespace();
+            let value = try!(self.parse_feature_value());
+            if name.as_slice().starts_with("min-") {
+                RangeFeature(name.as_slice().slice_from(4).to_string(), CmpGe, value)
+            } else if name.as_slice().starts_with("max-") {
+                RangeFeature(name.as_slice().slice_from(4).to_string(), CmpLe, value)
+            } else {
+                PlainFeature(name, value)
+            }
+        } else {
+            let cmp = try!(self.parse_comparison());
+            RangeFeature(name, cmp, try!(self.parse_feature_value()))
+        };
+        try!(feature.check());
+        Ok(FeatureCondition(feature))
+    }
+
+    /// Parse a media feature name in lowercase, and the whitespace after it.
+    fn parse_feature_name(&mut self) -> Result<String, String> {
+        let name = self.parse_identifier().into_ascii_lower();
+        if name.is_empty() {
+            return Err(self.unexpected("media feature"));
+        }
+        self.consume_whitespace();
+        Ok(name)
+    }
+
+    /// Parse one of `<`, `<=`, `=`, `>=`, `>` and the whitespace after it.
+    fn parse_comparison(&mut self) -> Result<Comparison, String> {
+        let (cmp, len) = if self.starts_with("<=") {
+            (CmpLe, 2)
+        } else if self.starts_with("<") {
+            (CmpLt, 1)
+        } else if self.starts_with(">=") {
+            (CmpGe, 2)
+        } else if self.starts_with(">") {
+            (CmpGt, 1)
+        } else if self.starts_with("=") {
+            (CmpEq, 1)
+        } else {
+            return Err(self.unexpected("media feature"));
+        };
+        self.pos = self.pos + len;
+        self.consume_whitespace();
+        Ok(cmp)
+    }
+
+    /// Parse a media feature value: a number with an optional unit, or a keyword. Lengths in
+    /// `em` and `rem` are relative to the initial font size.
+    fn parse_feature_value(&mut self) -> Result<FeatureValue, String> {
+        if !self.starts_number() {
+            let value = self.parse_identifier().into_ascii_lower();
+            if value.is_empty() {
+                return Err(self.unexpected("media feature"));
+            }
+            return Ok(FeatureIdent(value));
+        }
+        let number = match self.parse_float() {
+            Some(number) => number,
+            None => return Err("Invalid number in media feature".to_string())
+        };
+        let unit = self.parse_identifier().into_ascii_lower();
+        match unit.as_slice() {
+            ""           => Ok(FeatureNumber(number)),
+            "px"         => Ok(FeatureLength(number)),
+            "em" | "rem" => Ok(FeatureLength(number * properties::INITIAL_FONT_SIZE)),
+            "dppx" | "x" => Ok(FeatureResolution(number)),
+            "dpi"        => Ok(FeatureResolution(number / 96.0)),
+            "dpcm"       => Ok(FeatureResolution(number * 2.54 / 96.0)),
+            _            => Err(format!("Unknown unit {} in media feature", unit))
+        }
+    }
+
+    /// Consume `keyword` (case-insensitively) and any whitespace after it. Nothing is consumed if
+    /// the next identifier is not `keyword`.
+    fn consume_keyword(&mut self, keyword: &str) -> bool {
+        let start = self.pos;
+        if self.parse_identifier().into_ascii_lower().as_slice() == keyword {
+            self.consume_whitespace();
+            true
+        } else {
+            self.pos = start;
+            false
+        }
+    }
+
+    // Parse a comma separated list of selectors.
+    fn parse_selectors(&mut self) -> Result<Vec<Selector>, String> {
+        let mut selectors = Vec::new();
+        loop {
+            let parts = try!(self.parse_selector_parts());
+            match parts[0] {
+                (Child, _, _) => return Err("Selector starts with a combinator".to_string()),
+                _ => {}
+            }
+            if parts.iter().any(|&(_, _, nesting)| nesting) {
+                return Err("The nesting selector & can only be used in nested rules".to_string());
+            }
+            selectors.push(desugar_nested_selector(None, parts.as_slice()));
+            if !try!(self.consume_selector_separator()) {
+                break;
             }
         }
         // Sort by specificity (highest first)
         selectors.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
-        selectors
+        Ok(selectors)
+    }
+
+    /// Parse the selector list of a rule nested in a rule with the given selectors, and desugar
+    /// it into absolute selectors.
+    fn parse_nested_selectors(&mut self, parents: &[Selector]) -> Result<Vec<Selector>, String> {
+        let mut selectors = Vec::new();
+        loop {
+            let parts = try!(self.parse_selector_parts());
+            for parent in parents.iter() {
+                selectors.push(desugar_nested_selector(Some(parent), parts.as_slice()));
+            }
+            if !try!(self.consume_selector_separator()) {
+                break;
+            }
+        }
+        selectors.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
+        Ok(selectors)
+    }
+
+    /// Consume the `,` between two selectors and return true, or return false at the `{` or the
+    /// end of input that ends the list.
+    fn consume_selector_separator(&mut self) -> Result<bool, String> {
+        self.consume_whitespace();
+        if self.eof() {
+            return Ok(false);
+        }
+        match self.next_char() {
+            ',' => {
+                self.consume_char();
+                self.consume_whitespace();
+                Ok(true)
+            }
+            '{' => Ok(false),
+            c   => Err(format!("Unexpected character {} in selector list", c))
+        }
+    }
+
+    /// Parse a selector into the simple selectors it is made of, each with the combinator on its
+    /// left (the first one has `Descendant` unless the selector starts with `>`) and whether it
+    /// contains the nesting selector `&`.
+    fn parse_selector_parts(&mut self)
+                            -> Result<Vec<(Combinator, SimpleSelector, bool)>, String> {
+        let mut parts = Vec::new();
+        let mut combinator = Descendant;
+        loop {
+            self.consume_whitespace();
+            if !self.eof() && self.next_char() == '>' {
+                self.consume_char();
+                self.consume_whitespace();
+                combinator = Child;
+            }
+            let (selector, nesting) = try!(self.parse_compound_selector());
+            parts.push((combinator, selector, nesting));
+
+            let start = self.pos;
+            self.consume_whitespace();
+            if self.eof() {
+                self.pos = start;
+                break;
+            }
+            match self.next_char() {
+                ',' | '{' => {
+                    self.pos = start;
+                    break;
+                }
+                '>' => {}
+                _ if self.pos > start => combinator = Descendant,
+                c => return Err(format!("Unexpected character {} in selector", c))
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Parse a simple selector that may contain the nesting selector `&`, e.g. `&.active`.
+    /// Returns the selector without the `&`, and whether it was present.
+    fn parse_compound_selector(&mut self) -> Result<(SimpleSelector, bool), String> {
+        let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new() };
+        let mut nesting = false;
+        let compound_start = self.pos;
+        loop {
+            let start = self.pos;
+            if !self.eof() && self.next_char() == '&' {
+                self.consume_char();
+                nesting = true;
+            }
+            selector = merge_simple_selectors(&selector, &try!(self.parse_simple_selector()));
+            if self.pos == start {
+                break;
+            }
+        }
+        if self.pos == compound_start {
+            return Err(match self.eof() {
+                true => "Expected a selector".to_string(),
+                false => format!("Unexpected character {} in selector", self.next_char())
+            });
+        }
+        Ok((selector, nesting))
     }
 
     /// Parse one simple selector, e.g.: `type#id.class1.class2.classn`
-    fn parse_simple_selector(&mut self) -> SimpleSelector {
+    fn parse_simple_selector(&mut self) -> Result<SimpleSelector, String> {
         let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new() };
         while !self.eof() {
             match self.next_char() {
                 '#' => {
                     self.consume_char();
-                    selector.id = Some(self.parse_identifier());
+                    selector.id = Some(try!(self.parse_selector_name("#")));
                 }
                 '.' => {
                     self.consume_char();
-                    selector.class.push(self.parse_identifier());
+                    selector.class.push(try!(self.parse_selector_name(".")));
                 }
                 '*' => {
                     // universal selector
@@ -220,78 +1237,394 @@ impl Parser {
                 _ => break
             }
         }
-        selector
+        Ok(selector)
     }
 
-    /// Parse a list of declarations enclosed by `{ }`.
-    fn parse_declarations(&mut self) -> Vec<Declaration> {
+    /// Parse the id or class name after `prefix`, which must not be empty.
+    fn parse_selector_name(&mut self, prefix: &str) -> Result<String, String> {
+        let name = self.parse_identifier();
+        if name.is_empty() {
+            return Err(format!("Expected a name after {} in selector", prefix));
+        }
+        Ok(name)
+    }
+
+    /// Parse a block of declarations and nested rules enclosed by `{ }`, for a rule with the
+    /// given selectors. Returns the rule followed by the desugared nested rules.
+    fn parse_style_block(&mut self, selectors: Vec<Selector>) -> Vec<Rule> {
         let mut declarations = Vec::new();
+        let mut nested_rules = Vec::new();
         assert!(self.consume_char() == '{');
         loop {
             self.consume_whitespace();
+            if self.eof() {
+                break;
+            }
             if self.next_char() == '}' {
                 self.consume_char();
                 break;
             }
-            declarations.push(self.parse_declaration());
+            if self.next_char() == '@' {
+                nested_rules.extend(self.parse_at_rule(Some(selectors.as_slice())).into_iter());
+            } else if self.starts_nested_rule() {
+                let location = self.location();
+                match self.parse_nested_selectors(selectors.as_slice()) {
+                    Ok(nested_selectors) => {
+                        nested_rules.extend(self.parse_style_block(nested_selectors).into_iter());
+                    }
+                    Err(message) => {
+                        self.warn(location, format!("{}; rule skipped", message));
+                        self.skip_rule();
+                    }
+                }
+            } else {
+                declarations.extend(self.parse_declaration().into_iter());
+            }
+        }
+
+        let mut rules = vec![Rule {
+            selectors: selectors,
+            declarations: declarations,
+            media: Vec::new(),
+            layer: self.layer.clone(),
+        }];
+        rules.extend(nested_rules.into_iter());
+        rules
+    }
+
+    /// Does a nested rule start here, rather than a declaration? Nested rules have a `{` before
+    /// the next `;` or `}`.
+    fn starts_nested_rule(&self) -> bool {
+        let mut depth = 0u;
+        for c in self.input.as_slice().slice_from(self.pos).chars() {
+            match c {
+                '(' => depth = depth + 1,
+                ')' if depth > 0 => depth = depth - 1,
+                '{' if depth == 0 => return true,
+                ';' | '}' if depth == 0 => return false,
+                _ => {}
+            }
         }
-        declarations
+        false
     }
 
-    /// Parse a `<property>: <value>;` declaration.
-    fn parse_declaration(&mut self) -> Declaration {
+    /// Parse a `<property>: <value>;` declaration. The values of custom properties and values
+    /// containing `var()` references are left unparsed. Shorthands are expanded into one
+    /// declaration per longhand. Invalid declarations are skipped up to the next `;` or `}`.
+    fn parse_declaration(&mut self) -> Vec<Declaration> {
+        let location = self.location();
         let property_name = self.parse_identifier();
         self.consume_whitespace();
-        assert!(self.consume_char() == ':');
-        self.consume_whitespace();
-        let value = self.parse_value();
+        if self.eof() || self.next_char() != ':' {
+            self.consume_raw_value();
+            self.consume_declaration_end();
+            self.warn(location, format!("Expected ':' after '{}'; declaration skipped",
+                                        property_name));
+            return Vec::new();
+        }
+        self.consume_char();
         self.consume_whitespace();
-        assert!(self.consume_char() == ';');
+        let raw = self.consume_raw_value();
+        self.consume_declaration_end();
 
-        Declaration {
-            name: property_name,
+        if !properties::is_known(property_name.as_slice()) {
+            self.warn(location, format!("Unknown property {}; declaration skipped",
+                                        property_name));
+            return Vec::new();
+        }
+        let longhands = match properties::expand_shorthand(property_name.as_slice()) {
+            Some(longhands) => longhands.iter().map(|longhand| longhand.to_string()).collect(),
+            None => vec![property_name.clone()]
+        };
+        // Values with `var()` references are parsed after substitution. The longhands of a
+        // shorthand keep its text until then, since each of them may take a different part of it.
+        let values = if property_name.as_slice().starts_with("--") ||
+                        custom_properties::has_references(raw.as_slice()) {
+            match longhands.len() {
+                1 => Some(vec![Unparsed(raw)]),
+                _ => Some(vec![UnparsedShorthand(property_name.clone(), raw)])
+            }
+        } else {
+            parse_values(property_name.as_slice(), raw)
+        };
+        let values = match values.and_then(|values| distribute(longhands.len(), values)) {
+            Some(values) => values,
+            None => {
+                self.warn(location, format!("Invalid value for property {}; declaration skipped",
+                                            property_name));
+                return Vec::new();
+            }
+        };
+        longhands.into_iter().zip(values.into_iter()).map(|(longhand, value)| Declaration {
+            name: longhand,
             value: value,
+            location: location.clone(),
+        }).collect()
+    }
+
+    /// Consume the `;` ending a declaration, if any. The last declaration of a block may end at
+    /// the `}` instead.
+    fn consume_declaration_end(&mut self) {
+        self.consume_whitespace();
+        if !self.eof() && self.next_char() == ';' {
+            self.consume_char();
         }
     }
 
-    /// Parse a value
-    fn parse_value(&mut self) -> Value {
+    /// Consume the text of a value up to the `;` or `}` ending it, and return it without
+    /// surrounding whitespace.
+    fn consume_raw_value(&mut self) -> String {
+        let mut value = String::new();
+        let mut depth = 0u;
+        while !self.eof() {
+            match self.next_char() {
+                ';' | '}' if depth == 0 => break,
+                '(' => depth = depth + 1,
+                ')' if depth > 0 => depth = depth - 1,
+                _ => {}
+            }
+            value.push_char(self.consume_char());
+        }
+        value.as_slice().trim().to_string()
+    }
+
+    /// Parse a value, or return `None` if the input doesn't start with a valid value.
+    fn parse_value(&mut self) -> Option<Value> {
+        if self.eof() {
+            return None;
+        }
+        if self.starts_number() {
+            return self.parse_length();
+        }
         match self.next_char() {
-            '0'..'9' => self.parse_length(),
             '#' => self.parse_color(),
-            _   => Keyword(self.parse_identifier())
+            c if valid_identifier_char(c) => {
+                let name = self.parse_identifier();
+                if !self.eof() && self.next_char() == '(' {
+                    self.consume_char();
+                    let function = name.into_ascii_lower();
+                    self.parse_math_function(function.as_slice()).map(|node| Calc(box node))
+                } else {
+                    Some(Keyword(name))
+                }
+            }
+            _ => None
         }
     }
 
-    fn parse_length(&mut self) -> Value {
-        Length(self.parse_float(), self.parse_unit())
+    /// Parse a length or a percentage.
+    fn parse_length(&mut self) -> Option<Value> {
+        let f = match self.parse_float() {
+            Some(f) => f,
+            None => return None
+        };
+        if !self.eof() && self.next_char() == '%' {
+            self.consume_char();
+            return Some(Percentage(f));
+        }
+        // The unit of a zero length is optional.
+        if f == 0.0 && (self.eof() || !valid_identifier_char(self.next_char())) {
+            return Some(Length(0.0, Px));
+        }
+        self.parse_unit().map(|unit| Length(f, unit))
     }
 
-    fn parse_float(&mut self) -> f32 {
+    /// Does the input start with a number, optionally signed?
+    fn starts_number(&self) -> bool {
+        let mut chars = self.input.as_slice().slice_from(self.pos).chars();
+        match chars.next() {
+            Some('0'..'9') | Some('.') => true,
+            Some('-') | Some('+') => match chars.next() {
+                Some('0'..'9') | Some('.') => true,
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    fn parse_float(&mut self) -> Option<f32> {
+        let sign = match self.next_char() {
+            '-' => { self.consume_char(); -1.0 }
+            '+' => { self.consume_char(); 1.0 }
+            _ => 1.0
+        };
         let s = self.consume_while(|c| match c {
             '0'..'9' | '.' => true,
             _ => false
         });
-        let f: Option<f32> = from_str(s.as_slice());
-        f.unwrap()
+        from_str(s.as_slice()).map(|f: f32| sign * f)
+    }
+
+    /// Parse the arguments of `calc()`, `min()`, `max()` or `clamp()` after the opening
+    /// parenthesis, up to and including the closing one. Values nested deeper than
+    /// `MAX_MATH_DEPTH` are invalid.
+    fn parse_math_function(&mut self, name: &str) -> Option<CalcNode> {
+        if self.math_depth >= MAX_MATH_DEPTH {
+            return None;
+        }
+        self.math_depth += 1;
+        let node = self.parse_math_arguments(name);
+        self.math_depth -= 1;
+        node
+    }
+
+    fn parse_math_arguments(&mut self, name: &str) -> Option<CalcNode> {
+        let mut args = Vec::new();
+        loop {
+            self.consume_whitespace();
+            match self.parse_calc_sum() {
+                Some(arg) => args.push(arg),
+                None => return None
+            }
+            self.consume_whitespace();
+            if self.eof() {
+                return None;
+            }
+            match self.consume_char() {
+                ',' => {}
+                ')' => break,
+                _ => return None
+            }
+        }
+
+        match (name, args.len()) {
+            ("calc", 1) => args.pop(),
+            ("min", n) if n > 0 => Some(CalcMin(args)),
+            ("max", n) if n > 0 => Some(CalcMax(args)),
+            ("clamp", 3) => {
+                let max = args.pop().unwrap();
+                let value = args.pop().unwrap();
+                let min = args.pop().unwrap();
+                Some(CalcClamp(box min, box value, box max))
+            }
+            _ => None
+        }
+    }
+
+    /// Parse `<calc-product> [ [ + | - ] <calc-product> ]*`. The operators must be surrounded by
+    /// whitespace.
+    fn parse_calc_sum(&mut self) -> Option<CalcNode> {
+        let mut left = match self.parse_calc_product() {
+            Some(node) => node,
+            None => return None
+        };
+        loop {
+            let start = self.pos;
+            self.consume_whitespace();
+            let is_operator = self.starts_with("+ ") || self.starts_with("- ");
+            if self.pos == start || !is_operator {
+                self.pos = start;
+                break;
+            }
+            let op = self.consume_char();
+            self.consume_whitespace();
+            let right = match self.parse_calc_product() {
+                Some(node) => node,
+                None => return None
+            };
+            left = match op {
+                '+' => CalcSum(box left, box right),
+                _   => CalcDifference(box left, box right)
+            };
+        }
+        Some(left)
+    }
+
+    /// Parse `<calc-value> [ [ * | / ] <calc-value> ]*`. One side of `*` and the right side of `/`
+    /// must be numbers, so that the result is a number, a length or a percentage.
+    fn parse_calc_product(&mut self) -> Option<CalcNode> {
+        let mut left = match self.parse_calc_value() {
+            Some(node) => node,
+            None => return None
+        };
+        loop {
+            let start = self.pos;
+            self.consume_whitespace();
+            if self.eof() || (self.next_char() != '*' && self.next_char() != '/') {
+                self.pos = start;
+                break;
+            }
+            let op = self.consume_char();
+            self.consume_whitespace();
+            let right = match self.parse_calc_value() {
+                Some(node) => node,
+                None => return None
+            };
+            left = match op {
+                '*' if left.is_number() || right.is_number() => CalcProduct(box left, box right),
+                '/' if right.is_number() => CalcQuotient(box left, box right),
+                _ => return None
+            };
+        }
+        Some(left)
+    }
+
+    /// Parse a number, length, percentage, parenthesized expression or nested math function.
+    fn parse_calc_value(&mut self) -> Option<CalcNode> {
+        if self.eof() {
+            return None;
+        }
+        if self.next_char() == '(' {
+            self.consume_char();
+            return self.parse_math_function("calc");
+        }
+        if self.starts_number() {
+            let number = match self.parse_float() {
+                Some(number) => number,
+                None => return None
+            };
+            if !self.eof() && self.next_char() == '%' {
+                self.consume_char();
+                return Some(CalcPercentage(number));
+            }
+            return match self.parse_identifier().into_ascii_lower().as_slice() {
+                ""   => Some(CalcNumber(number)),
+                "px" => Some(CalcLength(number)),
+                _    => None
+            };
+        }
+        let name = self.parse_identifier().into_ascii_lower();
+        if self.eof() || self.next_char() != '(' {
+            return None;
+        }
+        self.consume_char();
+        self.parse_math_function(name.as_slice())
     }
 
-    fn parse_unit(&mut self) -> Unit {
+    fn parse_unit(&mut self) -> Option<Unit> {
         match self.parse_identifier().into_ascii_lower().as_slice() {
-            "px" => Px,
-            _    => fail!("unrecognized unit")
+            "px" => Some(Px),
+            _    => None
         }
     }
 
-    fn parse_color(&mut self) -> Value {
+    /// Parse `#rrggbb` or `#rrggbbaa`.
+    fn parse_color(&mut self) -> Option<Value> {
         assert!(self.consume_char() == '#');
-        Color(self.parse_hex_pair(), self.parse_hex_pair(), self.parse_hex_pair(), 255)
+        let (r, g, b) = match (self.parse_hex_pair(), self.parse_hex_pair(),
+                               self.parse_hex_pair()) {
+            (Some(r), Some(g), Some(b)) => (r, g, b),
+            _ => return None
+        };
+        if self.eof() || self.next_char().to_digit(16).is_none() {
+            return Some(Color(r, g, b, 255));
+        }
+        self.parse_hex_pair().map(|a| Color(r, g, b, a))
     }
 
-    fn parse_hex_pair(&mut self) -> u8 {
-        let s = self.input.as_slice().slice(self.pos, self.pos + 2);
-        self.pos = self.pos + 2;
-        FromStrRadix::from_str_radix(s, 16).unwrap()
+    /// Parse two hexadecimal digits.
+    fn parse_hex_pair(&mut self) -> Option<u8> {
+        let mut value = 0u8;
+        for _ in range(0u, 2) {
+            if self.eof() {
+                return None;
+            }
+            match self.consume_char().to_digit(16) {
+                Some(digit) => value = value * 16 + digit as u8,
+                None => return None
+            }
+        }
+        Some(value)
     }
 
     fn parse_identifier(&mut self) -> String {
@@ -299,9 +1632,226 @@ impl Parser {
     }
 }
 
+/// Build the selector made of `parts` (see `parse_selector_parts`) nested in rule with selector
+/// `parent`. Parts containing `&` are merged with the parent selector; if there are none, the
+/// parent selector is prepended with the combinator of the first part.
+fn desugar_nested_selector(parent: Option<&Selector>,
+                           parts: &[(Combinator, SimpleSelector, bool)]) -> Selector {
+    let mut result = match parent {
+        Some(parent) if !parts.iter().any(|&(_, _, nesting)| nesting) => Some(parent.clone()),
+        _ => None
+    };
+    for &(combinator, ref simple, nesting) in parts.iter() {
+        let part = match parent {
+            Some(parent) if nesting => merge_rightmost(parent, simple),
+            _ => Simple(simple.clone())
+        };
+        result = Some(match result {
+            None => part,
+            Some(prefix) => prepend(prefix, combinator, part),
+        });
+    }
+    result.unwrap()
+}
+
+/// Prepend `prefix` and `combinator` to the leftmost simple selector of `selector`.
+fn prepend(prefix: Selector, combinator: Combinator, selector: Selector) -> Selector {
+    match selector {
+        Simple(simple) => Complex(box prefix, combinator, simple),
+        Complex(left, c, right) => Complex(box prepend(prefix, combinator, *left), c, right),
+    }
+}
+
+/// Merge `simple` into the rightmost simple selector of `selector`.
+fn merge_rightmost(selector: &Selector, simple: &SimpleSelector) -> Selector {
+    match *selector {
+        Simple(ref s) => Simple(merge_simple_selectors(s, simple)),
+        Complex(ref left, c, ref right) => {
+            Complex(left.clone(), c, merge_simple_selectors(right, simple))
+        }
+    }
+}
+
+/// A simple selector matching elements that match both `a` and `b`.
+fn merge_simple_selectors(a: &SimpleSelector, b: &SimpleSelector) -> SimpleSelector {
+    SimpleSelector {
+        tag_name: b.tag_name.clone().or(a.tag_name.clone()),
+        id: b.id.clone().or(a.id.clone()),
+        class: a.class.iter().chain(b.class.iter()).map(|c| c.clone()).collect(),
+    }
+}
+
 fn valid_identifier_char(c: char) -> bool {
     match c {
         'a'..'z' | 'A'..'Z' | '0'..'9' | '-' | '_' => true,
         _ => false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use media::{MediaQueryList, MediaQuery, AllMedia, Device, Screen};
+    use media::{FeatureCondition, RangeFeature, CmpGe, FeatureLength};
+    use css::{Value, Keyword, Length, Px};
+    use std::io::TempDir;
+    use std::io::fs::File;
+    use super::{parse_css, parse_css_file, MAX_IMPORT_DEPTH};
+
+    /// Assert that `source` parses into `rules` rules with one diagnostic, without declaring any
+    /// layer.
+    fn assert_skipped(source: &str, rules: uint) {
+        let stylesheet = parse_css(source.to_string());
+        assert_eq!((source, stylesheet.rules.len(), stylesheet.diagnostics.len()),
+                   (source, rules, 1));
+        assert!(stylesheet.layers.is_empty());
+    }
+
+    fn write_file(dir: &TempDir, name: &str, source: &str) {
+        File::create(&dir.path().join(name)).write_str(source).unwrap();
+    }
+
+    #[test]
+    fn malformed_layer_rules_are_skipped() {
+        assert_skipped("@layer foo(", 0);
+        assert_skipped("@layer foo( div { display: block; }", 0);
+        assert_skipped("@layer \"x\"; div { display: block; }", 1);
+        assert_skipped("@layer a b { p { color: red; } } div { display: block; }", 1);
+        assert_skipped("@layer a, b { p { color: red; } } div { display: block; }", 1);
+        assert_skipped("@layer a..b; div { display: block; }", 1);
+        assert_skipped("@layer a, ; div { display: block; }", 1);
+        assert_skipped("@layer", 0);
+        assert_skipped("@layer a", 0);
+        assert_skipped("div { @layer a b { color: red; } display: block; }", 1);
+    }
+
+    #[test]
+    fn unknown_supports_conditions_are_false() {
+        assert_skipped("@supports selector(p) { p { color: red; } } div { display: block; }", 1);
+        assert_skipped("@supports (display) { p { color: red; } } div { display: block; }", 1);
+        assert_skipped("@supports not x { p { color: red; } } div { display: block; }", 1);
+        assert_skipped("@supports (display: block) p { color: red; } div { display: block; }",
+                       1);
+        assert_skipped("@supports (display: block", 0);
+        assert_skipped("@supports", 0);
+
+        let stylesheet = parse_css("@supports not selector(p) { p { color: red; } }
+                                    @supports (display: block) or (display) {
+                                        div { display: block; }
+                                    }".to_string());
+        assert_eq!(stylesheet.rules.len(), 2);
+        assert_eq!(stylesheet.diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn malformed_media_queries_are_not_all() {
+        let queries = [
+            "\"print\"",
+            "&",
+            "screen print",
+            "not",
+            "screen and",
+            "only screen and (color) or (width > 1px)",
+            "(min-width: 40furlongs)",
+            "(aspect-ratio: 16/9)",
+            "(orientation: sideways)",
+            "(min-orientation: portrait)",
+            "(unknown-feature)",
+            "(width <> 10px)",
+            "(width: 10px",
+        ];
+        let device = Device::new(Screen, 800.0, 600.0);
+        for &query in queries.iter() {
+            let stylesheet = parse_css(format!("@media {} {{ p {{ color: red; }} }}", query));
+            let media = MediaQueryList { queries: vec![MediaQuery::not_all()] };
+            assert_eq!((query, &stylesheet.rules[0].media), (query, &vec![media]));
+            assert_eq!(stylesheet.diagnostics.len(), 1);
+            assert!(!stylesheet.rules[0].media[0].matches(&device));
+        }
+
+        let stylesheet = parse_css("@media \"print\", (min-width: 40em) { p { color: red; } }"
+                                   .to_string());
+        let width = RangeFeature("width".to_string(), CmpGe, FeatureLength(640.0));
+        let query = MediaQuery {
+            qualifier: None,
+            media_type: AllMedia,
+            condition: Some(FeatureCondition(width)),
+        };
+        assert_eq!(stylesheet.rules[0].media[0].queries, vec![MediaQuery::not_all(), query]);
+        assert_eq!(stylesheet.diagnostics.len(), 1);
+        assert!(stylesheet.rules[0].media[0].matches(&device));
+
+        assert_skipped("@media screen; div { display: block; }", 1);
+        assert_skipped("@media screen", 0);
+    }
+
+    #[test]
+    fn malformed_imports_are_skipped() {
+        let dir = TempDir::new("drawr").unwrap();
+        write_file(&dir, "x.css", "x { display: block; }");
+        write_file(&dir, "main.css", "@import url(x.css) screen;
+                                      @import foo.css;
+                                      @import \"x.css\" screen print;
+                                      @imports \"x.css\";
+                                      p { display: block; }");
+        let stylesheet = parse_css_file(&dir.path().join("main.css"));
+        assert_eq!(stylesheet.rules.len(), 3);
+        assert_eq!(stylesheet.diagnostics.len(), 3);
+        assert_eq!(stylesheet.rules[0].media[0].queries[0].media_type, Screen);
+        assert_eq!(stylesheet.rules[1].media[0].queries, vec![MediaQuery::not_all()]);
+
+        assert_skipped("@import url(x.css", 0);
+        assert_skipped("@import 'x.css", 0);
+        assert_skipped("@import \"x.css\" { display: block; } div { display: block; }", 1);
+    }
+
+    #[test]
+    fn import_cycles_are_skipped() {
+        let dir = TempDir::new("drawr").unwrap();
+        write_file(&dir, "a.css", "@import \"b.css\"; a { display: block; }");
+        write_file(&dir, "b.css", "@import url(a.css); b { display: block; }");
+        let stylesheet = parse_css_file(&dir.path().join("a.css"));
+        assert_eq!(stylesheet.rules.len(), 2);
+        assert_eq!(stylesheet.diagnostics.len(), 1);
+        assert!(stylesheet.diagnostics[0].message.as_slice().starts_with("@import cycle: "));
+    }
+
+    #[test]
+    fn imports_nested_too_deeply_are_skipped() {
+        let dir = TempDir::new("drawr").unwrap();
+        for i in range(0u, MAX_IMPORT_DEPTH + 4) {
+            write_file(&dir, format!("{}.css", i).as_slice(),
+                       format!("@import \"{}.css\"; p {{ display: block; }}", i + 1).as_slice());
+        }
+        let stylesheet = parse_css_file(&dir.path().join("0.css"));
+        assert_eq!(stylesheet.rules.len(), MAX_IMPORT_DEPTH);
+        assert_eq!(stylesheet.diagnostics.len(), 1);
+        assert!(stylesheet.diagnostics[0].message.as_slice().starts_with("@import nesting"));
+    }
+
+    #[test]
+    fn four_sided_shorthands() {
+        let stylesheet = parse_css("p { margin: 0 auto; padding: 1px 2px 3px; }
+                                    div { margin: 1px inherit; padding: inherit; }".to_string());
+        let declarations: Vec<(String, Value)> = stylesheet.rules.iter()
+            .flat_map(|rule| rule.declarations.iter())
+            .map(|d| (d.name.clone(), d.value.clone()))
+            .collect();
+        let auto = Keyword("auto".to_string());
+        let inherit = Keyword("inherit".to_string());
+        assert_eq!(declarations, vec![
+            ("margin-top".to_string(), Length(0.0, Px)),
+            ("margin-right".to_string(), auto.clone()),
+            ("margin-bottom".to_string(), Length(0.0, Px)),
+            ("margin-left".to_string(), auto),
+            ("padding-top".to_string(), Length(1.0, Px)),
+            ("padding-right".to_string(), Length(2.0, Px)),
+            ("padding-bottom".to_string(), Length(3.0, Px)),
+            ("padding-left".to_string(), Length(2.0, Px)),
+            ("padding-top".to_string(), inherit.clone()),
+            ("padding-right".to_string(), inherit.clone()),
+            ("padding-bottom".to_string(), inherit.clone()),
+            ("padding-left".to_string(), inherit),
+        ]);
+        assert_eq!(stylesheet.diagnostics.len(), 1);
+    }
+}
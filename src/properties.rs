@@ -0,0 +1,424 @@
+//! Registry of the CSS properties drawr implements and their computed values.
+//!
+//! The `ComputedStyle` struct, with one typed field per supported longhand property, is generated
+//! from the table at the bottom of this file together with the functions that compute each
+//! property from a CSS value. Shorthands are expanded into longhands by the parser.
+
+use css::{Value, Keyword, Color, Length, Percentage, Calc, Unparsed, UnparsedShorthand, Px};
+use css::CalcNode;
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum Display {
+    Inline,
+    Block,
+    DisplayNone,
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum TextAlign {
+    AlignLeft,
+    AlignRight,
+    AlignCenter,
+    AlignJustify,
+}
+
+/// A length, percentage or math function. Lengths are absolute, percentages are resolved during
+/// layout.
+#[deriving(Show, Clone, PartialEq)]
+pub enum LengthPercentage {
+    Fixed(f32), // px
+    Percent(f32),
+    Expr(Box<CalcNode>), // a math function with percentages
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub enum LengthOrAuto {
+    Auto,
+    Len(LengthPercentage),
+}
+
+#[deriving(Show, Clone, PartialEq)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+/// Values computed earlier for the same element, needed to compute other properties.
+pub struct ComputeContext {
+    pub parent_font_size: f32,
+    pub font_size: f32,
+}
+
+/// Initial value of `font-size` (`medium`), in px.
+pub static INITIAL_FONT_SIZE: f32 = 16.0;
+
+/// Shorthand properties and the longhands they set.
+pub static SHORTHANDS: &'static [(&'static str, &'static [&'static str])] = &[
+    ("margin", &["margin-top", "margin-right", "margin-bottom", "margin-left"]),
+    ("padding", &["padding-top", "padding-right", "padding-bottom", "padding-left"]),
+    ("border-width", &["border-top-width", "border-right-width", "border-bottom-width",
+                       "border-left-width"]),
+];
+
+/// The basic named colors, as RGB.
+static NAMED_COLORS: &'static [(&'static str, u8, u8, u8)] = &[
+    ("black", 0, 0, 0),
+    ("silver", 192, 192, 192),
+    ("gray", 128, 128, 128),
+    ("white", 255, 255, 255),
+    ("maroon", 128, 0, 0),
+    ("red", 255, 0, 0),
+    ("purple", 128, 0, 128),
+    ("fuchsia", 255, 0, 255),
+    ("green", 0, 128, 0),
+    ("lime", 0, 255, 0),
+    ("olive", 128, 128, 0),
+    ("yellow", 255, 255, 0),
+    ("navy", 0, 0, 128),
+    ("blue", 0, 0, 255),
+    ("teal", 0, 128, 128),
+    ("aqua", 0, 255, 255),
+];
+
+/// Return the longhands set by shorthand property `name`, or `None` if it isn't a shorthand.
+pub fn expand_shorthand(name: &str) -> Option<&'static [&'static str]> {
+    SHORTHANDS.iter().find(|&&(shorthand, _)| shorthand == name).map(|&(_, longhands)| longhands)
+}
+
+/// Return true if `name` is a supported longhand or shorthand property, or a custom property.
+pub fn is_known(name: &str) -> bool {
+    name.starts_with("--") || LONGHANDS.iter().any(|&longhand| longhand == name) ||
+        expand_shorthand(name).is_some()
+}
+
+/// Return true if drawr implements property `name` with the given value. Custom properties
+/// accept any value, and values with `var()` references are only validated after substitution.
+pub fn is_supported(name: &str, value: &Value) -> bool {
+    if !is_known(name) {
+        return false;
+    }
+    let longhand = match expand_shorthand(name) {
+        Some(longhands) => longhands[0],
+        None => name
+    };
+    match *value {
+        Unparsed(_) | UnparsedShorthand(..) => true,
+        Keyword(ref k) if is_css_wide_keyword(k.as_slice()) => true,
+        _ if name.starts_with("--") => true,
+        ref value => {
+            let context = ComputeContext {
+                parent_font_size: INITIAL_FONT_SIZE,
+                font_size: INITIAL_FONT_SIZE,
+            };
+            let mut style = ComputedStyle::initial();
+            style.set(longhand, value, &context)
+        }
+    }
+}
+
+/// `inherit`, `initial`, `unset` and `revert` are valid values for every property.
+pub fn is_css_wide_keyword(keyword: &str) -> bool {
+    match keyword {
+        "inherit" | "initial" | "unset" | "revert" => true,
+        _ => false
+    }
+}
+
+/// Return the color named by `keyword`, if any. `transparent` is transparent black.
+pub fn named_color(keyword: &str) -> Option<Rgba> {
+    if keyword == "transparent" {
+        return Some(Rgba::transparent());
+    }
+    NAMED_COLORS.iter()
+        .find(|&&(name, _, _, _)| name == keyword)
+        .map(|&(_, r, g, b)| Rgba { r: r, g: g, b: b, a: 255 })
+}
+
+impl Rgba {
+    pub fn transparent() -> Rgba {
+        Rgba { r: 0, g: 0, b: 0, a: 0 }
+    }
+
+    pub fn black() -> Rgba {
+        Rgba { r: 0, g: 0, b: 0, a: 255 }
+    }
+}
+
+impl LengthPercentage {
+    /// Return the size in px, with percentages taken of `percentage_base`.
+    pub fn resolve(&self, percentage_base: f32) -> f32 {
+        match *self {
+            Fixed(px) => px,
+            Percent(p) => percentage_base * p / 100.0,
+            Expr(ref node) => node.resolve(percentage_base),
+        }
+    }
+
+    pub fn has_percentage(&self) -> bool {
+        match *self {
+            Fixed(_) => false,
+            Percent(_) | Expr(_) => true,
+        }
+    }
+}
+
+impl LengthOrAuto {
+    /// Return the size in px, with percentages taken of `percentage_base`, or `None` for `auto`.
+    pub fn resolve(&self, percentage_base: f32) -> Option<f32> {
+        match *self {
+            Auto => None,
+            Len(ref length) => Some(length.resolve(percentage_base)),
+        }
+    }
+}
+
+/// Conversion of computed values back to CSS values, e.g. for debug output.
+pub trait ToCssValue {
+    fn to_css_value(&self) -> Value;
+}
+
+impl ToCssValue for Display {
+    fn to_css_value(&self) -> Value {
+        Keyword(match *self {
+            Inline => "inline",
+            Block => "block",
+            DisplayNone => "none",
+        }.to_string())
+    }
+}
+
+impl ToCssValue for TextAlign {
+    fn to_css_value(&self) -> Value {
+        Keyword(match *self {
+            AlignLeft => "left",
+            AlignRight => "right",
+            AlignCenter => "center",
+            AlignJustify => "justify",
+        }.to_string())
+    }
+}
+
+impl ToCssValue for LengthPercentage {
+    fn to_css_value(&self) -> Value {
+        match *self {
+            Fixed(px) => Length(px, Px),
+            Percent(p) => Percentage(p),
+            Expr(ref node) => Calc(node.clone()),
+        }
+    }
+}
+
+impl ToCssValue for LengthOrAuto {
+    fn to_css_value(&self) -> Value {
+        match *self {
+            Auto => Keyword("auto".to_string()),
+            Len(ref length) => length.to_css_value(),
+        }
+    }
+}
+
+impl ToCssValue for Rgba {
+    fn to_css_value(&self) -> Value {
+        Color(self.r, self.g, self.b, self.a)
+    }
+}
+
+impl ToCssValue for f32 {
+    fn to_css_value(&self) -> Value {
+        Length(*self, Px)
+    }
+}
+
+impl ToCssValue for Option<f32> {
+    fn to_css_value(&self) -> Value {
+        match *self {
+            Some(px) => Length(px, Px),
+            None => Keyword("normal".to_string()),
+        }
+    }
+}
+
+// Functions computing a property value from a CSS value. They return `None` for invalid values.
+
+fn compute_display(value: &Value, _: &ComputeContext) -> Option<Display> {
+    match *value {
+        Keyword(ref k) => match k.as_slice() {
+            "inline" => Some(Inline),
+            "block" => Some(Block),
+            "none" => Some(DisplayNone),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+fn compute_text_align(value: &Value, _: &ComputeContext) -> Option<TextAlign> {
+    match *value {
+        Keyword(ref k) => match k.as_slice() {
+            "left" => Some(AlignLeft),
+            "right" => Some(AlignRight),
+            "center" => Some(AlignCenter),
+            "justify" => Some(AlignJustify),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+fn compute_length_percentage(value: &Value, _: &ComputeContext) -> Option<LengthPercentage> {
+    match *value {
+        Length(px, Px) => Some(Fixed(px)),
+        Percentage(p) => Some(Percent(p)),
+        Calc(ref node) if node.has_percentage() => Some(Expr(node.clone())),
+        Calc(ref node) => Some(Fixed(node.resolve(0.0))),
+        _ => None
+    }
+}
+
+fn compute_length_or_auto(value: &Value, context: &ComputeContext) -> Option<LengthOrAuto> {
+    match *value {
+        Keyword(ref k) if k.as_slice() == "auto" => Some(Auto),
+        ref value => compute_length_percentage(value, context).map(|length| Len(length)),
+    }
+}
+
+/// An absolute length, in px. Percentages are not allowed.
+fn compute_length(value: &Value, context: &ComputeContext) -> Option<f32> {
+    match compute_length_percentage(value, context) {
+        Some(Fixed(px)) => Some(px),
+        _ => None
+    }
+}
+
+fn compute_color(value: &Value, _: &ComputeContext) -> Option<Rgba> {
+    match *value {
+        Color(r, g, b, a) => Some(Rgba { r: r, g: g, b: b, a: a }),
+        Keyword(ref k) => named_color(k.as_slice()),
+        _ => None
+    }
+}
+
+/// Percentages of `font-size` refer to the parent's font size.
+fn compute_font_size(value: &Value, context: &ComputeContext) -> Option<f32> {
+    compute_length_percentage(value, context).map(|l| l.resolve(context.parent_font_size))
+}
+
+/// `normal` is `None`. Percentages refer to the element's own font size.
+fn compute_line_height(value: &Value, context: &ComputeContext) -> Option<Option<f32>> {
+    match *value {
+        Keyword(ref k) if k.as_slice() == "normal" => Some(None),
+        ref value => compute_length_percentage(value, context)
+                         .map(|l| Some(l.resolve(context.font_size))),
+    }
+}
+
+macro_rules! longhands(
+    ($($name:expr => $field:ident: $ty:ty = $initial:expr, inherited: $inherited:expr,
+       compute: $compute:ident;)+) => (
+        /// Names of all the supported longhand properties.
+        pub static LONGHANDS: &'static [&'static str] = &[$($name),+];
+
+        /// Computed values of all the supported properties of a node.
+        #[deriving(Show, Clone, PartialEq)]
+        pub struct ComputedStyle {
+            $(pub $field: $ty),+
+        }
+
+        impl ComputedStyle {
+            /// The initial values of all properties.
+            pub fn initial() -> ComputedStyle {
+                ComputedStyle { $($field: $initial),+ }
+            }
+
+            /// The starting point of the computed style of a child of `parent`: inherited
+            /// properties take the parent's values and the others their initial values.
+            pub fn inherit(parent: &ComputedStyle) -> ComputedStyle {
+                ComputedStyle {
+                    $($field: if $inherited { parent.$field.clone() } else { $initial }),+
+                }
+            }
+
+            /// Set property `name` to the computed value of `value`. Returns false if `name` is
+            /// not a supported longhand or `value` is not valid for it.
+            pub fn set(&mut self, name: &str, value: &Value, context: &ComputeContext) -> bool {
+                match name {
+                    $(n if n == $name => match $compute(value, context) {
+                        Some(computed) => { self.$field = computed; true }
+                        None => false
+                    },)+
+                    _ => false
+                }
+            }
+
+            /// Set property `name` to its value in `source`.
+            pub fn copy_from(&mut self, name: &str, source: &ComputedStyle) {
+                match name {
+                    $(n if n == $name => { self.$field = source.$field.clone(); })+
+                    _ => {}
+                }
+            }
+
+            /// Return the value of property `name` as a CSS value, or `None` if `name` is not a
+            /// supported longhand.
+            pub fn get(&self, name: &str) -> Option<Value> {
+                match name {
+                    $(n if n == $name => Some(self.$field.to_css_value()),)+
+                    _ => None
+                }
+            }
+        }
+
+        /// Return true if longhand property `name` is inherited.
+        pub fn is_inherited(name: &str) -> bool {
+            match name {
+                $(n if n == $name => $inherited,)+
+                _ => false
+            }
+        }
+    )
+)
+
+longhands!(
+    "display" => display: Display = Inline, inherited: false,
+        compute: compute_display;
+    "width" => width: LengthOrAuto = Auto, inherited: false,
+        compute: compute_length_or_auto;
+    "height" => height: LengthOrAuto = Auto, inherited: false,
+        compute: compute_length_or_auto;
+    "margin-top" => margin_top: LengthOrAuto = Len(Fixed(0.0)), inherited: false,
+        compute: compute_length_or_auto;
+    "margin-right" => margin_right: LengthOrAuto = Len(Fixed(0.0)), inherited: false,
+        compute: compute_length_or_auto;
+    "margin-bottom" => margin_bottom: LengthOrAuto = Len(Fixed(0.0)), inherited: false,
+        compute: compute_length_or_auto;
+    "margin-left" => margin_left: LengthOrAuto = Len(Fixed(0.0)), inherited: false,
+        compute: compute_length_or_auto;
+    "padding-top" => padding_top: LengthPercentage = Fixed(0.0), inherited: false,
+        compute: compute_length_percentage;
+    "padding-right" => padding_right: LengthPercentage = Fixed(0.0), inherited: false,
+        compute: compute_length_percentage;
+    "padding-bottom" => padding_bottom: LengthPercentage = Fixed(0.0), inherited: false,
+        compute: compute_length_percentage;
+    "padding-left" => padding_left: LengthPercentage = Fixed(0.0), inherited: false,
+        compute: compute_length_percentage;
+    "border-top-width" => border_top_width: f32 = 0.0, inherited: false,
+        compute: compute_length;
+    "border-right-width" => border_right_width: f32 = 0.0, inherited: false,
+        compute: compute_length;
+    "border-bottom-width" => border_bottom_width: f32 = 0.0, inherited: false,
+        compute: compute_length;
+    "border-left-width" => border_left_width: f32 = 0.0, inherited: false,
+        compute: compute_length;
+    "background" => background: Rgba = Rgba::transparent(), inherited: false,
+        compute: compute_color;
+    "color" => color: Rgba = Rgba::black(), inherited: true,
+        compute: compute_color;
+    "font-size" => font_size: f32 = INITIAL_FONT_SIZE, inherited: true,
+        compute: compute_font_size;
+    "line-height" => line_height: Option<f32> = None, inherited: true,
+        compute: compute_line_height;
+    "text-align" => text_align: TextAlign = AlignLeft, inherited: true,
+        compute: compute_text_align;
+)
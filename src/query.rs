@@ -0,0 +1,182 @@
+//! Query the computed style and box geometry of elements, like `getComputedStyle` and
+//! `getBoundingClientRect` in browsers.
+
+use css::{Selector, Value, UserAgent, Author};
+use dom::{NodeRef, Element, ElementData, Text};
+use layout::{LayoutBox, Dimensions, BlockNode, InlineNode};
+use parser;
+use properties::LONGHANDS;
+use serializer;
+use std::fmt;
+use style::{StyledNode, matches};
+use text;
+use traversal::{Visitor, pre_order, walk};
+
+/// The computed style of a node and the geometry of its box.
+pub struct ComputedElement<'a> {
+    pub styled_node: &'a StyledNode<'a>,
+    // Dimensions of the box of the node, or `None` if it doesn't generate one, e.g. because it
+    // has `display: none`.
+    pub dimensions: Option<Dimensions>,
+}
+
+impl<'a> ComputedElement<'a> {
+    pub fn node(&self) -> NodeRef<'a> {
+        self.styled_node.node()
+    }
+
+    /// Return the computed value of longhand property `name`, or `None` if it isn't supported.
+    pub fn value(&self, name: &str) -> Option<Value> {
+        self.styled_node.value(name)
+    }
+
+    /// Return the computed value of custom property `name` (including the leading `--`).
+    pub fn custom_property(&self, name: &str) -> Option<String> {
+        self.styled_node.custom_property(name)
+    }
+
+    /// The text of the element as rendered, see `text::inner_text`.
+    pub fn inner_text(&self) -> String {
+        text::inner_text(self.styled_node)
+    }
+
+    /// Describe how the cascade went for each property declared on the element: its computed
+    /// value, then the declarations that applied to it by decreasing priority, with the winning
+    /// one marked with `*`.
+    pub fn explain(&self) -> String {
+        let mut output = String::new();
+        match *self.node().node_type() {
+            Element(ref elem) => output.push_str(format!("<{}>\n", elem.tag_name).as_slice()),
+            Text(_) => output.push_str("#text\n"),
+        }
+        let cascade = self.styled_node.cascade();
+        let mut names: Vec<&String> = cascade.keys().collect();
+        names.sort();
+        for name in names.into_iter() {
+            let value = match self.value(name.as_slice()) {
+                Some(value) => serializer::value_to_css(&value),
+                None => self.custom_property(name.as_slice())
+                            .unwrap_or("<guaranteed-invalid>".to_string())
+            };
+            output.push_str(format!("  {}: {}\n", name, value).as_slice());
+
+            let trace = cascade.find(name).unwrap();
+            for (i, applied) in trace.declarations.iter().enumerate().rev() {
+                let (origin, _, (a, b, c)) = applied.level;
+                let origin = match origin {
+                    UserAgent => "user-agent",
+                    Author => "author",
+                };
+                let layer = match applied.layer {
+                    Some(ref layer) => format!(", layer {}", layer),
+                    None => String::new()
+                };
+                let marker = if trace.winner == Some(i) { "*" } else { " " };
+                output.push_str(format!("    {} {} {{ {}: {} }}", marker,
+                                        serializer::selector_to_css(&applied.selector),
+                                        applied.declaration.name,
+                                        serializer::value_to_css(&applied.declaration.value))
+                                .as_slice());
+                output.push_str(format!("  {}{}, specificity ({}, {}, {}), {}\n",
+                                        origin, layer, a, b, c, applied.declaration.location)
+                                .as_slice());
+            }
+        }
+        output
+    }
+}
+
+/// Return the first element, in tree order, matched by the comma separated `selectors`, or an
+/// error if they aren't a valid selector list.
+pub fn query_selector<'a>(style_root: &'a StyledNode<'a>, layout_root: &LayoutBox<'a>,
+                          selectors: &str) -> Result<Option<ComputedElement<'a>>, String> {
+    Ok(try!(query_selector_all(style_root, layout_root, selectors)).into_iter().next())
+}
+
+/// Return all the elements, in tree order, matched by the comma separated `selectors`, or an
+/// error if they aren't a valid selector list.
+pub fn query_selector_all<'a>(style_root: &'a StyledNode<'a>, layout_root: &LayoutBox<'a>,
+                              selectors: &str) -> Result<Vec<ComputedElement<'a>>, String> {
+    let selectors = try!(parser::parse_selectors(selectors.to_string()));
+    let mut collector = MatchCollector {
+        selectors: selectors.as_slice(),
+        ancestors: Vec::new(),
+        found: Vec::new(),
+    };
+    walk(style_root, &mut collector);
+    Ok(collector.found.into_iter().map(|styled_node| ComputedElement {
+        styled_node: styled_node,
+        dimensions: find_box(layout_root, styled_node.node()),
+    }).collect())
+}
+
+/// Return the computed style of DOM node `node`, or `None` if it isn't in the style tree.
+pub fn query_node<'a>(style_root: &'a StyledNode<'a>, layout_root: &LayoutBox<'a>, node: NodeRef)
+                      -> Option<ComputedElement<'a>> {
+    find_styled_node(style_root, node).map(|styled_node| ComputedElement {
+        styled_node: styled_node,
+        dimensions: find_box(layout_root, node),
+    })
+}
+
+/// Collects the nodes of a style tree that match `selectors` while `walk` visits it.
+struct MatchCollector<'a, 'b> {
+    selectors: &'b [Selector],
+    // Ancestor elements of the current node, root first.
+    ancestors: Vec<&'a ElementData>,
+    found: Vec<&'a StyledNode<'a>>,
+}
+
+impl<'a, 'b> Visitor<&'a StyledNode<'a>> for MatchCollector<'a, 'b> {
+    fn enter(&mut self, styled: &'a StyledNode<'a>) {
+        match *styled.node().node_type() {
+            Element(ref elem) => {
+                let ancestors = self.ancestors.as_slice();
+                if self.selectors.iter().any(|selector| matches(elem, ancestors, selector)) {
+                    self.found.push(styled);
+                }
+                self.ancestors.push(elem);
+            }
+            Text(_) => {}
+        }
+    }
+
+    fn leave(&mut self, styled: &'a StyledNode<'a>) {
+        match *styled.node().node_type() {
+            Element(_) => { self.ancestors.pop(); }
+            Text(_) => {}
+        }
+    }
+}
+
+fn find_styled_node<'a>(styled: &'a StyledNode<'a>, node: NodeRef) -> Option<&'a StyledNode<'a>> {
+    pre_order(styled).find(|styled| styled.node() == node)
+}
+
+/// Return the dimensions of the box generated by `node`, if any.
+fn find_box(layout_box: &LayoutBox, node: NodeRef) -> Option<Dimensions> {
+    pre_order(layout_box).filter_map(|layout_box| match layout_box.box_type {
+        BlockNode(styled) | InlineNode(styled) if styled.node() == node => {
+            Some(layout_box.dimensions)
+        }
+        _ => None
+    }).next()
+}
+
+/// Lists the computed values of all the properties and the box of the element.
+impl<'a> fmt::Show for ComputedElement<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.node().node_type() {
+            Element(ref elem) => try!(writeln!(f, "<{}>", elem.tag_name)),
+            Text(_) => try!(writeln!(f, "#text")),
+        }
+        for name in LONGHANDS.iter() {
+            let value = self.value(*name).unwrap();
+            try!(writeln!(f, "  {}: {}", name, serializer::value_to_css(&value)));
+        }
+        match self.dimensions {
+            Some(ref d) => writeln!(f, "  box: {}", d),
+            None => writeln!(f, "  box: none"),
+        }
+    }
+}
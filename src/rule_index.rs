@@ -0,0 +1,233 @@
+//! Index of the style rules that apply to a device, so that each element is only matched against
+//! the rules that could possibly match it.
+//!
+//! Selectors are bucketed by their rightmost compound selector: by id if it has one, otherwise by
+//! its first class, otherwise by tag name, and the rest go to the universal bucket. Selectors
+//! with combinators also record hashes of the ids, classes and tags their ancestors need, which
+//! are checked against an `AncestorFilter` (a counting Bloom filter of the ancestors of the
+//! element) before the selector is matched.
+//!
+//! Ids and classes are bucketed and hashed in ASCII lowercase, so that the same index serves
+//! documents in quirks mode, where they match case-insensitively.
+
+use css::{Stylesheet, Rule, Selector, Simple, Complex, SimpleSelector};
+use document::QuirksMode;
+use dom::ElementData;
+use media::Device;
+use std::ascii::StrAsciiExt; // for `to_ascii_lower`
+use std::collections::hashmap::HashMap;
+use std::hash;
+use style::{CascadeLevel, MatchedRule, matches_in_mode};
+
+/// A selector of a rule, with what is needed to match it quickly and sort the matches.
+struct IndexedSelector<'a> {
+    selector: &'a Selector,
+    rule: &'a Rule,
+    level: CascadeLevel,
+    // Index of the stylesheet, of the rule in the stylesheet and of the selector in the rule.
+    position: (uint, uint, uint),
+    // Hashes of the ids, classes and tag names the ancestors of a matching element must have.
+    ancestor_hashes: Vec<u64>,
+}
+
+pub struct RuleIndex<'a> {
+    by_id: HashMap<String, Vec<IndexedSelector<'a>>>,
+    by_class: HashMap<String, Vec<IndexedSelector<'a>>>,
+    by_tag: HashMap<String, Vec<IndexedSelector<'a>>>,
+    universal: Vec<IndexedSelector<'a>>,
+}
+
+impl<'a> RuleIndex<'a> {
+    /// Index the rules of `stylesheets` that apply to `device`, i.e. whose media queries match.
+    pub fn new(stylesheets: &[&'a Stylesheet], device: &Device) -> RuleIndex<'a> {
+        let mut index = RuleIndex {
+            by_id: HashMap::new(),
+            by_class: HashMap::new(),
+            by_tag: HashMap::new(),
+            universal: Vec::new(),
+        };
+        for (sheet_position, &stylesheet) in stylesheets.iter().enumerate() {
+            let layer_ranks = stylesheet.layer_ranks();
+            for (rule_position, rule) in stylesheet.rules.iter().enumerate() {
+                if !rule.media.iter().all(|media| media.matches(device)) {
+                    continue;
+                }
+                let layer_rank = match rule.layer {
+                    Some(ref layer) => *layer_ranks.find(layer).unwrap(),
+                    None => stylesheet.unlayered_rank()
+                };
+                for (selector_position, selector) in rule.selectors.iter().enumerate() {
+                    index.insert(IndexedSelector {
+                        selector: selector,
+                        rule: rule,
+                        level: (stylesheet.origin, layer_rank, selector.specificity()),
+                        position: (sheet_position, rule_position, selector_position),
+                        ancestor_hashes: ancestor_hashes(selector),
+                    });
+                }
+            }
+        }
+        index
+    }
+
+    fn insert(&mut self, entry: IndexedSelector<'a>) {
+        let selector: &'a Selector = entry.selector;
+        let rightmost = match *selector {
+            Simple(ref simple) | Complex(_, _, ref simple) => simple,
+        };
+        let bucket = match (&rightmost.id, rightmost.class.as_slice().head(), &rightmost.tag_name) {
+            (&Some(ref id), _, _) => {
+                self.by_id.find_or_insert_with(id.as_slice().to_ascii_lower(), |_| Vec::new())
+            }
+            (_, Some(class), _) => {
+                let class = class.as_slice().to_ascii_lower();
+                self.by_class.find_or_insert_with(class, |_| Vec::new())
+            }
+            (_, _, &Some(ref tag)) => {
+                self.by_tag.find_or_insert_with(tag.clone(), |_| Vec::new())
+            }
+            _ => &mut self.universal
+        };
+        bucket.push(entry);
+    }
+
+    /// Find all the rules that match `elem`, whose ancestors are `ancestors` (root first) and
+    /// are in `filter`, in a document in `quirks_mode`. The rules are in source order and each is
+    /// matched by its first matching selector.
+    pub fn matching_rules(&self, elem: &ElementData, ancestors: &[&ElementData],
+                          filter: &AncestorFilter, quirks_mode: QuirksMode)
+                          -> Vec<MatchedRule<'a>> {
+        let mut candidates: Vec<&IndexedSelector<'a>> = Vec::new();
+        for id in elem.id().iter() {
+            for bucket in self.by_id.find(&id.as_slice().to_ascii_lower()).iter() {
+                candidates.extend(bucket.iter());
+            }
+        }
+        for class in elem.classes().iter() {
+            for bucket in self.by_class.find(&class.as_slice().to_ascii_lower()).iter() {
+                candidates.extend(bucket.iter());
+            }
+        }
+        for bucket in self.by_tag.find(&elem.tag_name).iter() {
+            candidates.extend(bucket.iter());
+        }
+        candidates.extend(self.universal.iter());
+
+        let mut matched: Vec<&IndexedSelector<'a>> = candidates.into_iter()
+            .filter(|entry| filter.might_contain_all(entry.ancestor_hashes.as_slice()))
+            .filter(|entry| matches_in_mode(elem, ancestors, entry.selector, quirks_mode))
+            .collect();
+        matched.sort_by(|a, b| a.position.cmp(&b.position));
+
+        let mut rules = Vec::new();
+        let mut last_rule = None;
+        for entry in matched.into_iter() {
+            let (sheet_position, rule_position, _) = entry.position;
+            if last_rule != Some((sheet_position, rule_position)) {
+                rules.push(MatchedRule {
+                    level: entry.level,
+                    rule: entry.rule,
+                    selector: entry.selector,
+                });
+                last_rule = Some((sheet_position, rule_position));
+            }
+        }
+        rules
+    }
+}
+
+/// Hashes of the ids, classes and tag names required by the compound selectors left of the
+/// rightmost one.
+fn ancestor_hashes(selector: &Selector) -> Vec<u64> {
+    let mut hashes = Vec::new();
+    let mut selector = selector;
+    loop {
+        match *selector {
+            Simple(_) => break,
+            Complex(ref left, _, _) => {
+                let simple = match **left {
+                    Simple(ref simple) | Complex(_, _, ref simple) => simple,
+                };
+                hashes.extend(simple_selector_hashes(simple).into_iter());
+                selector = &**left;
+            }
+        }
+    }
+    hashes
+}
+
+fn simple_selector_hashes(selector: &SimpleSelector) -> Vec<u64> {
+    let mut hashes = Vec::new();
+    for tag in selector.tag_name.iter() {
+        hashes.push(tag_hash(tag.as_slice()));
+    }
+    for id in selector.id.iter() {
+        hashes.push(id_hash(id.as_slice()));
+    }
+    for class in selector.class.iter() {
+        hashes.push(class_hash(class.as_slice()));
+    }
+    hashes
+}
+
+fn element_hashes(elem: &ElementData) -> Vec<u64> {
+    let mut hashes = vec![tag_hash(elem.tag_name.as_slice())];
+    for id in elem.id().iter() {
+        hashes.push(id_hash(id.as_slice()));
+    }
+    for class in elem.classes().iter() {
+        hashes.push(class_hash(class.as_slice()));
+    }
+    hashes
+}
+
+fn tag_hash(tag: &str) -> u64 { hash::hash(&('t', tag)) }
+fn id_hash(id: &str) -> u64 { hash::hash(&('#', id.to_ascii_lower())) }
+fn class_hash(class: &str) -> u64 { hash::hash(&('.', class.to_ascii_lower())) }
+
+/// Number of counters of the Bloom filter.
+static FILTER_SIZE: uint = 4096;
+
+/// A counting Bloom filter of the tags, ids and classes of the ancestors of an element. Elements
+/// are pushed on the way down the tree and popped on the way back up.
+pub struct AncestorFilter {
+    counters: Vec<u8>,
+}
+
+impl AncestorFilter {
+    pub fn new() -> AncestorFilter {
+        AncestorFilter { counters: Vec::from_elem(FILTER_SIZE, 0u8) }
+    }
+
+    pub fn push(&mut self, elem: &ElementData) {
+        for hash in element_hashes(elem).iter() {
+            for &i in counter_indices(*hash).iter() {
+                // Saturated counters stay saturated, since we no longer know their true count.
+                if self.counters[i] < 255 {
+                    *self.counters.get_mut(i) += 1;
+                }
+            }
+        }
+    }
+
+    /// Remove an element pushed earlier.
+    pub fn pop(&mut self, elem: &ElementData) {
+        for hash in element_hashes(elem).iter() {
+            for &i in counter_indices(*hash).iter() {
+                if self.counters[i] < 255 {
+                    *self.counters.get_mut(i) -= 1;
+                }
+            }
+        }
+    }
+
+    /// Return false if some of the hashes are definitely not in the filter.
+    pub fn might_contain_all(&self, hashes: &[u64]) -> bool {
+        hashes.iter().all(|&hash| counter_indices(hash).iter().all(|&i| self.counters[i] > 0))
+    }
+}
+
+/// The two counters of the filter a hash maps to.
+fn counter_indices(hash: u64) -> [uint, ..2] {
+    [(hash as uint) % FILTER_SIZE, ((hash >> 32) as uint) % FILTER_SIZE]
+}
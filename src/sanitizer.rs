@@ -0,0 +1,410 @@
+//! Sanitizer for untrusted HTML.
+//!
+//! The sanitized tree only has the elements, attributes and URL schemes allowed by a `Policy`.
+//! Elements whose content is never shown as text, like `<script>` and `<style>`, are removed
+//! with their content; other elements that aren't allowed are replaced by their children.
+//! Event handler attributes are always removed. `style` attributes only keep the declarations of
+//! allowed properties with a valid value, rewritten in canonical form. Everything removed is
+//! reported with its path in the original tree (see `diff::path`).
+
+use diff::path;
+use dom;
+use dom::{NodeRef, Tree, AttrMap, ElementData, Element, Text};
+use parser;
+use properties;
+use serializer::value_to_css;
+use std::ascii::OwnedStrAsciiExt; // for `into_ascii_lower`
+use std::fmt;
+use std::mem;
+use traversal::{Visitor, walk};
+
+/// What the sanitized tree may contain. Names are in lowercase.
+pub struct Policy {
+    pub elements: &'static [&'static str],
+    pub attributes: &'static [&'static str], // allowed on all elements
+    pub url_schemes: &'static [&'static str], // allowed in URL attributes; relative URLs always are
+    pub properties: &'static [&'static str], // allowed in `style` attributes
+}
+
+/// Formatting, structural and media elements without scripting, forms or embedded documents.
+pub static DEFAULT_POLICY: Policy = Policy {
+    elements: &[
+        "html", "head", "title", "body", "div", "span", "p", "br", "hr", "h1", "h2", "h3", "h4",
+        "h5", "h6", "a", "b", "i", "u", "s", "em", "strong", "small", "sub", "sup", "code",
+        "pre", "blockquote", "q", "ul", "ol", "li", "dl", "dt", "dd", "table", "caption",
+        "thead", "tbody", "tfoot", "tr", "th", "td", "img",
+    ],
+    attributes: &[
+        "id", "class", "title", "lang", "dir", "style", "href", "src", "alt", "width", "height",
+        "colspan", "rowspan", "cite",
+    ],
+    url_schemes: &["http", "https", "mailto"],
+    properties: &[
+        "display", "width", "height", "margin", "margin-top", "margin-right", "margin-bottom",
+        "margin-left", "padding", "padding-top", "padding-right", "padding-bottom",
+        "padding-left", "border-width", "background", "color", "font-size", "line-height",
+    ],
+};
+
+/// Elements removed with their content when they aren't allowed.
+static CONTENT_ELEMENTS: &'static [&'static str] = &[
+    "script", "style", "template", "noscript", "iframe", "frame", "frameset", "object", "embed",
+    "applet",
+];
+
+/// Attributes whose value is a URL.
+static URL_ATTRIBUTES: &'static [&'static str] = &["href", "src", "cite", "action", "background"];
+
+/// Something removed by the sanitizer. Paths are in the original tree.
+#[deriving(Clone, PartialEq)]
+pub enum Removal {
+    DroppedElement(String), // path; removed with its content
+    UnwrappedElement(String), // path; replaced by its children
+    DroppedAttribute(String, String, String), // path, name and value
+    DroppedDeclaration(String, String), // path and text of the declaration in `style`
+}
+
+impl fmt::Show for Removal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DroppedElement(ref path) => write!(f, "removed {}", path),
+            UnwrappedElement(ref path) => write!(f, "unwrapped {}", path),
+            DroppedAttribute(ref path, ref name, ref value) => {
+                write!(f, "removed attribute {}=\"{}\" from {}", name, value, path)
+            }
+            DroppedDeclaration(ref path, ref declaration) => {
+                write!(f, "removed style declaration \"{}\" from {}", declaration, path)
+            }
+        }
+    }
+}
+
+/// Return a sanitized copy of the tree of `root`, and what was removed from it, in document
+/// order. If the root itself isn't kept, its remaining content is wrapped in an `html` element
+/// like the parser does.
+pub fn sanitize(root: NodeRef, policy: &Policy) -> (Tree, Vec<Removal>) {
+    let mut sanitizer = Sanitizer {
+        policy: policy,
+        removals: Vec::new(),
+        stack: Vec::new(),
+        dropped_depth: 0,
+        output: Vec::new(),
+    };
+    walk(root, &mut sanitizer);
+    let mut nodes = sanitizer.output;
+    let tree = if nodes.len() == 1 {
+        nodes.pop().unwrap()
+    } else {
+        dom::elem("html".to_string(), AttrMap::new(), nodes)
+    };
+    (tree, sanitizer.removals)
+}
+
+/// Builds the sanitized tree while `walk` visits the original one.
+struct Sanitizer<'p> {
+    policy: &'p Policy,
+    removals: Vec<Removal>,
+    // The nodes entered but not left yet, root first.
+    stack: Vec<PendingNode>,
+    // Number of nodes entered inside a dropped element, including it.
+    dropped_depth: uint,
+    // The sanitized nodes that replace the root.
+    output: Vec<Tree>,
+}
+
+/// The sanitized nodes built so far for a node of the original tree.
+struct PendingNode {
+    // The tag name and sanitized attributes of a kept element, or `None` for a text node or an
+    // unwrapped element, whose children replace it.
+    element: Option<(String, AttrMap)>,
+    children: Vec<Tree>,
+}
+
+impl<'a, 'p> Visitor<NodeRef<'a>> for Sanitizer<'p> {
+    fn enter(&mut self, node: NodeRef<'a>) {
+        if self.dropped_depth > 0 {
+            self.dropped_depth += 1;
+            return;
+        }
+        let elem = match *node.node_type() {
+            Text(ref data) => {
+                let text = dom::text(data.clone());
+                self.stack.push(PendingNode { element: None, children: vec![text] });
+                return;
+            }
+            Element(ref elem) => elem
+        };
+        let tag_name = elem.tag_name.clone().into_ascii_lower();
+        let element = if contains(self.policy.elements, tag_name.as_slice()) {
+            let attributes = sanitize_attributes(node, elem, self.policy, &mut self.removals);
+            Some((elem.tag_name.clone(), attributes))
+        } else if contains(CONTENT_ELEMENTS, tag_name.as_slice()) {
+            self.removals.push(DroppedElement(path(node)));
+            self.dropped_depth = 1;
+            return;
+        } else {
+            self.removals.push(UnwrappedElement(path(node)));
+            None
+        };
+        self.stack.push(PendingNode { element: element, children: Vec::new() });
+    }
+
+    fn leave(&mut self, _node: NodeRef<'a>) {
+        if self.dropped_depth > 0 {
+            self.dropped_depth -= 1;
+            return;
+        }
+        let pending = self.stack.pop().unwrap();
+        let nodes = match pending.element {
+            Some((tag_name, attributes)) => {
+                vec![dom::elem(tag_name, attributes, pending.children)]
+            }
+            None => pending.children
+        };
+        match self.stack.last_mut() {
+            Some(parent) => parent.children.extend(nodes.into_iter()),
+            None => self.output = nodes
+        }
+    }
+}
+
+fn sanitize_attributes(node: NodeRef, elem: &ElementData, policy: &Policy,
+                       removals: &mut Vec<Removal>) -> AttrMap {
+    let mut attributes = AttrMap::new();
+    for (name, value) in elem.attributes().iter() {
+        let lower_name = name.clone().into_ascii_lower();
+        let allowed = !lower_name.as_slice().starts_with("on") &&
+                      contains(policy.attributes, lower_name.as_slice());
+        if !allowed ||
+           (contains(URL_ATTRIBUTES, lower_name.as_slice()) &&
+            !is_allowed_url(value.as_slice(), policy)) {
+            removals.push(DroppedAttribute(path(node), name.clone(), value.clone()));
+        } else if lower_name.as_slice() == "style" {
+            let (style, dropped) = sanitize_style(value.as_slice(), policy);
+            for declaration in dropped.into_iter() {
+                removals.push(DroppedDeclaration(path(node), declaration));
+            }
+            if !style.is_empty() {
+                attributes.insert(name.clone(), style);
+            }
+        } else {
+            attributes.insert(name.clone(), value.clone());
+        }
+    }
+    attributes
+}
+
+/// Is `url` relative or does it have one of the allowed schemes? Browsers ignore whitespace and
+/// control characters in URLs, so they are ignored here too. The parser doesn't decode character
+/// references, so URLs with numeric ones or `&colon;`, which could hide the scheme, aren't
+/// allowed.
+fn is_allowed_url(url: &str, policy: &Policy) -> bool {
+    let url = url.chars().filter(|&c| c > ' ').collect::<String>().into_ascii_lower();
+    let url = url.as_slice();
+    if url.contains("&#") || url.contains("&colon") {
+        return false;
+    }
+    let scheme_end = match url.find(|c: char| c == ':' || c == '/' || c == '?') {
+        Some(i) if url.char_at(i) == ':' => i,
+        _ => return true
+    };
+    contains(policy.url_schemes, url.slice_to(scheme_end))
+}
+
+/// Sanitize the value of a `style` attribute. Returns the declarations that are kept, separated
+/// by semicolons, and the text of the ones that were dropped.
+pub fn sanitize_style(style: &str, policy: &Policy) -> (String, Vec<String>) {
+    let mut kept = Vec::new();
+    let mut dropped = Vec::new();
+    for declaration in split_declarations(style).into_iter() {
+        match sanitize_declaration(declaration.as_slice(), policy) {
+            Some(clean) => kept.push(clean),
+            None => dropped.push(declaration)
+        }
+    }
+    (kept.connect("; "), dropped)
+}
+
+/// Split a declaration list on the semicolons outside parentheses, dropping empty declarations.
+fn split_declarations(style: &str) -> Vec<String> {
+    let mut declarations = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0u;
+    for c in style.chars() {
+        match c {
+            ';' if depth == 0 => {
+                declarations.push(mem::replace(&mut current, String::new()));
+                continue;
+            }
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ => {}
+        }
+        current.push_char(c);
+    }
+    declarations.push(current);
+    declarations.iter()
+        .map(|declaration| declaration.as_slice().trim().to_string())
+        .filter(|declaration| !declaration.is_empty())
+        .collect()
+}
+
+/// The canonical form of `name: value` if the property is allowed and the value valid for it.
+/// Shorthands take one to four values, one for each side. Values with `var()` references can't be
+/// validated, so they aren't allowed.
+fn sanitize_declaration(declaration: &str, policy: &Policy) -> Option<String> {
+    let colon = match declaration.find(':') {
+        Some(i) => i,
+        None => return None
+    };
+    let name = declaration.slice_to(colon).trim().to_string().into_ascii_lower();
+    if !contains(policy.properties, name.as_slice()) {
+        return None;
+    }
+    let values = match parser::parse_values(name.as_slice(),
+                                            declaration.slice_from(colon + 1).to_string()) {
+        Some(values) => values,
+        None => return None
+    };
+    let max_values = properties::expand_shorthand(name.as_slice()).map_or(1, |sides| sides.len());
+    if values.is_empty() || values.len() > max_values {
+        return None;
+    }
+    let values: Vec<String> = values.iter().map(|value| value_to_css(value)).collect();
+    Some(format!("{}: {}", name, values.connect(" ")))
+}
+
+fn contains(names: &[&str], name: &str) -> bool {
+    names.iter().any(|&n| n == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use dom::Dom;
+    use parser::parse_html;
+    use super::{sanitize, sanitize_style, DEFAULT_POLICY, Removal};
+    use super::{DroppedElement, UnwrappedElement, DroppedAttribute, DroppedDeclaration};
+
+    fn sanitize_html(html: &str) -> (Dom, Vec<Removal>) {
+        let document = parse_html(html.to_string());
+        let (tree, removals) = sanitize(document.root(), &DEFAULT_POLICY);
+        (Dom::new(tree), removals)
+    }
+
+    #[test]
+    fn unsafe_urls_are_removed() {
+        let urls = [
+            "javascript:alert(1)",
+            "JaVaScript:alert(1)",
+            " \t javascript:alert(1)",
+            "\x01javascript:alert(1)",
+            "java\nscript:alert(1)",
+            "&#106;avascript:alert(1)",
+            "&#x6A;avascript:alert(1)",
+            "javascript&colon;alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+        ];
+        for &url in urls.iter() {
+            let (dom, removals) = sanitize_html(format!("<a href=\"{}\">x</a>", url).as_slice());
+            assert_eq!(removals, vec![DroppedAttribute("/a".to_string(), "href".to_string(),
+                                                       url.to_string())]);
+            assert_eq!(dom.root().element().unwrap().get_attribute("href"), None);
+        }
+
+        let urls = ["https://example.com/", "/path?q=1", "page.html#top", "mailto:a@example.com"];
+        for &url in urls.iter() {
+            let (dom, removals) = sanitize_html(format!("<a href=\"{}\">x</a>", url).as_slice());
+            assert!(removals.is_empty());
+            assert_eq!(dom.root().element().unwrap().get_attribute("href"),
+                       Some(&url.to_string()));
+        }
+    }
+
+    #[test]
+    fn event_handlers_are_removed() {
+        let (dom, removals) = sanitize_html("<p onclick=\"a()\" OnMouseOver=\"b()\" title=\"t\">\
+                                             x</p>");
+        assert_eq!(removals, vec![
+            DroppedAttribute("/p".to_string(), "onclick".to_string(), "a()".to_string()),
+            DroppedAttribute("/p".to_string(), "OnMouseOver".to_string(), "b()".to_string()),
+        ]);
+        let p = dom.root().element().unwrap();
+        assert_eq!(p.get_attribute("title"), Some(&"t".to_string()));
+        assert_eq!(p.attributes().iter().count(), 1);
+    }
+
+    #[test]
+    fn script_and_style_are_dropped_with_their_content() {
+        let (dom, removals) = sanitize_html("<div><script>alert(1)</script><p>Text</p>\
+                                             <style>p { color: red; }</style></div>");
+        assert_eq!(removals, vec![DroppedElement("/div/script".to_string()),
+                                  DroppedElement("/div/style".to_string())]);
+        assert_eq!(dom.root().text_content(), "Text".to_string());
+        assert_eq!(dom.root().children().count(), 1);
+    }
+
+    #[test]
+    fn unknown_elements_are_unwrapped() {
+        let (dom, removals) = sanitize_html("<div><custom><b>Bold</b> text</custom></div>");
+        assert_eq!(removals, vec![UnwrappedElement("/div/custom".to_string())]);
+        assert_eq!(dom.root().children().count(), 2);
+        assert_eq!(dom.root().text_content(), "Bold text".to_string());
+
+        // The content of an unwrapped root is wrapped in an `html` element.
+        let (dom, removals) = sanitize_html("<custom><p>a</p><p>b</p></custom>");
+        assert_eq!(removals, vec![UnwrappedElement("/custom".to_string())]);
+        assert_eq!(dom.root().element().unwrap().tag_name, "html".to_string());
+        assert_eq!(dom.root().children().count(), 2);
+    }
+
+    #[test]
+    fn style_declarations_are_filtered() {
+        let (style, dropped) = sanitize_style("background: url(javascript:alert(1)); \
+                                               COLOR: #FF0000; position: fixed; width: var(--w)",
+                                              &DEFAULT_POLICY);
+        assert_eq!(style, "color: #ff0000".to_string());
+        assert_eq!(dropped, vec!["background: url(javascript:alert(1))".to_string(),
+                                 "position: fixed".to_string(), "width: var(--w)".to_string()]);
+
+        let (dom, removals) = sanitize_html("<p style=\"background: url(x.png)\">x</p>");
+        assert_eq!(removals, vec![DroppedDeclaration("/p".to_string(),
+                                                     "background: url(x.png)".to_string())]);
+        assert_eq!(dom.root().element().unwrap().get_attribute("style"), None);
+    }
+
+    #[test]
+    fn hostile_style_values_do_not_fail() {
+        let deep_calc = format!("width: calc({}1px{})", String::from_char(10000, '('),
+                                String::from_char(10000, ')'));
+        let styles = [
+            "color:#aé", "color: #éa0000", "color: #ff00", "color: #", "color: \x00",
+            "width: calc(", "width: ((", "width: calc(1px + )", "width: calc(1px * 2px)",
+            "margin: 1px 2px 3px 4px 5px", ":", ";;;", "width:", "display: ☃",
+            deep_calc.as_slice(),
+        ];
+        for &style in styles.iter() {
+            let (kept, dropped) = sanitize_style(style, &DEFAULT_POLICY);
+            assert_eq!(kept, String::new());
+            assert!(dropped.len() <= 1);
+        }
+
+        let (_, removals) = sanitize_html("<p style=\"color:#aé\">x</p>");
+        assert_eq!(removals, vec![DroppedDeclaration("/p".to_string(), "color:#aé".to_string())]);
+    }
+
+    #[test]
+    fn shorthands_keep_all_their_values() {
+        let (kept, dropped) = sanitize_style("margin: 4px 8px; padding: 1px 2px 3px 4px; \
+                                              border-width: 0 1PX 2px", &DEFAULT_POLICY);
+        assert_eq!(kept, "margin: 4px 8px; padding: 1px 2px 3px 4px; \
+                          border-width: 0px 1px 2px".to_string());
+        assert!(dropped.is_empty());
+
+        let styles = ["margin: 1px inherit", "width: 1px 2px", "padding: 1px red", "margin: ;"];
+        for &style in styles.iter() {
+            let (kept, dropped) = sanitize_style(style, &DEFAULT_POLICY);
+            assert_eq!(kept, String::new());
+            assert_eq!(dropped.len(), 1);
+        }
+    }
+}
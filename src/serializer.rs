@@ -0,0 +1,509 @@
+//! Serialize stylesheets back to CSS text.
+//!
+//! Two output formats are supported: a canonical pretty-printed form and a minified form. Both
+//! round-trip: parsing the output yields a stylesheet equal to the one that was serialized.
+//! `@import` rules are not preserved since the parser inlines the imported rules, and neither are
+//! `@supports` rules since the parser evaluates them. Nested rules are written in their desugared
+//! form.
+
+use css::{Stylesheet, Rule, Selector, Simple, Complex, SimpleSelector, Declaration};
+use css::{Descendant, Child};
+use css::{Value, Keyword, Color, Length, Percentage, Calc, Unparsed, UnparsedShorthand, Px};
+use css::{CalcNode, CalcNumber, CalcLength, CalcPercentage, CalcSum, CalcDifference, CalcProduct};
+use css::{CalcQuotient, CalcMin, CalcMax, CalcClamp};
+use media::{MediaQueryList, MediaQuery, NotQualifier, OnlyQualifier};
+use media::{MediaType, AllMedia, Screen, Print, UnknownMedia};
+use media::{MediaCondition, FeatureCondition, NotCondition, AndCondition, OrCondition};
+use media::{MediaFeature, BooleanFeature, PlainFeature, RangeFeature};
+use media::{FeatureValue, FeatureLength, FeatureResolution, FeatureNumber, FeatureIdent};
+use media::{Comparison, CmpLt, CmpLe, CmpEq, CmpGe, CmpGt};
+use properties;
+
+pub enum Format {
+    Pretty,
+    Minified,
+}
+
+/// Serialize `stylesheet` in the given format.
+pub fn to_css(stylesheet: &Stylesheet, format: Format) -> String {
+    let mut serializer = Serializer {
+        output: String::new(),
+        minify: match format { Pretty => false, Minified => true },
+        depth: 0,
+    };
+    serializer.write_stylesheet(stylesheet);
+    serializer.output
+}
+
+/// Serialize a single value, in the pretty format.
+pub fn value_to_css(value: &Value) -> String {
+    let mut serializer = Serializer { output: String::new(), minify: false, depth: 0 };
+    serializer.write_value(value);
+    serializer.output
+}
+
+/// Serialize a single selector, in the pretty format.
+pub fn selector_to_css(selector: &Selector) -> String {
+    let mut serializer = Serializer { output: String::new(), minify: false, depth: 0 };
+    serializer.write_selector(selector);
+    serializer.output
+}
+
+/// A block enclosing style rules.
+#[deriving(PartialEq)]
+enum Block {
+    MediaBlock(MediaQueryList),
+    LayerBlock(String),
+}
+
+struct Serializer {
+    output: String,
+    minify: bool,
+    // Nesting depth of the block being written, for indentation.
+    depth: uint,
+}
+
+impl Serializer {
+    fn write(&mut self, s: &str) {
+        self.output.push_str(s);
+    }
+
+    /// Write `pretty` in the pretty format, or `minified` otherwise.
+    fn write_either(&mut self, pretty: &str, minified: &str) {
+        let s = if self.minify { minified } else { pretty };
+        self.write(s);
+    }
+
+    fn write_indent(&mut self) {
+        if !self.minify {
+            for _ in range(0, self.depth) {
+                self.write("  ");
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        if !self.minify {
+            self.write("\n");
+        }
+    }
+
+    fn open_block(&mut self) {
+        self.write_either(" {", "{");
+        self.newline();
+        self.depth = self.depth + 1;
+    }
+
+    fn close_block(&mut self) {
+        self.depth = self.depth - 1;
+        self.write_indent();
+        self.write("}");
+        self.newline();
+    }
+
+    // Rules
+
+    /// Write the layer order and rules of a stylesheet. Consecutive rules sharing enclosing
+    /// @media and @layer blocks are written inside the same blocks.
+    fn write_stylesheet(&mut self, stylesheet: &Stylesheet) {
+        if !stylesheet.layers.is_empty() {
+            self.write("@layer ");
+            for (i, layer) in stylesheet.layers.iter().enumerate() {
+                if i > 0 {
+                    self.write_either(", ", ",");
+                }
+                self.write(layer.as_slice());
+            }
+            self.write(";");
+            self.newline();
+        }
+
+        let mut open_blocks: Vec<Block> = Vec::new();
+        for (i, rule) in stylesheet.rules.iter().enumerate() {
+            let mut blocks: Vec<Block> = rule.media.iter()
+                                                   .map(|m| MediaBlock(m.clone()))
+                                                   .collect();
+            for layer in rule.layer.iter() {
+                blocks.push(LayerBlock(layer.clone()));
+            }
+
+            let common = open_blocks.iter().zip(blocks.iter())
+                                    .take_while(|&(a, b)| a == b)
+                                    .count();
+            while open_blocks.len() > common {
+                open_blocks.pop();
+                self.close_block();
+            }
+            if (i > 0 || !stylesheet.layers.is_empty()) && !self.minify && common == 0 {
+                self.write("\n");
+            }
+            for block in blocks.slice_from(common).iter() {
+                self.write_indent();
+                match *block {
+                    MediaBlock(ref media) => {
+                        self.write("@media ");
+                        self.write_media_query_list(media);
+                    }
+                    LayerBlock(ref layer) => {
+                        self.write("@layer ");
+                        self.write(layer.as_slice());
+                    }
+                }
+                self.open_block();
+            }
+            open_blocks = blocks;
+            self.write_rule(rule);
+        }
+        for _ in open_blocks.iter() {
+            self.close_block();
+        }
+    }
+
+    fn write_rule(&mut self, rule: &Rule) {
+        self.write_indent();
+        for (i, selector) in rule.selectors.iter().enumerate() {
+            if i > 0 {
+                self.write_either(", ", ",");
+            }
+            self.write_selector(selector);
+        }
+        self.open_block();
+        for declaration in rule.declarations.iter() {
+            self.write_declaration(declaration);
+        }
+        self.close_block();
+    }
+
+    fn write_selector(&mut self, selector: &Selector) {
+        match *selector {
+            Simple(ref simple) => self.write_simple_selector(simple),
+            Complex(ref left, combinator, ref simple) => {
+                self.write_selector(&**left);
+                match combinator {
+                    Descendant => self.write(" "),
+                    Child => self.write_either(" > ", ">"),
+                }
+                self.write_simple_selector(simple);
+            }
+        }
+    }
+
+    fn write_simple_selector(&mut self, selector: &SimpleSelector) {
+        if selector.tag_name.is_none() && selector.id.is_none() && selector.class.is_empty() {
+            self.write("*");
+        }
+        for tag_name in selector.tag_name.iter() {
+            self.write(tag_name.as_slice());
+        }
+        for id in selector.id.iter() {
+            self.write("#");
+            self.write(id.as_slice());
+        }
+        for class in selector.class.iter() {
+            self.write(".");
+            self.write(class.as_slice());
+        }
+    }
+
+    fn write_declaration(&mut self, declaration: &Declaration) {
+        // A shorthand with `var()` references is written once, in place of its first longhand.
+        let name = match declaration.value {
+            UnparsedShorthand(ref shorthand, _) => {
+                match properties::expand_shorthand(shorthand.as_slice()) {
+                    Some(longhands) if longhands[0] == declaration.name.as_slice() => shorthand,
+                    _ => return
+                }
+            }
+            _ => &declaration.name
+        };
+        self.write_indent();
+        self.write(name.as_slice());
+        self.write_either(": ", ":");
+        self.write_value(&declaration.value);
+        self.write(";");
+        self.newline();
+    }
+
+    // Values
+
+    fn write_value(&mut self, value: &Value) {
+        match *value {
+            Keyword(ref keyword) => self.write(keyword.as_slice()),
+            Color(r, g, b, 255) => self.write(format!("#{:02x}{:02x}{:02x}", r, g, b).as_slice()),
+            Color(r, g, b, a) => {
+                self.write(format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a).as_slice())
+            }
+            Length(f, Px) => {
+                self.write_number(f);
+                self.write("px");
+            }
+            Percentage(p) => {
+                self.write_number(p);
+                self.write("%");
+            }
+            Calc(ref node) => match **node {
+                CalcMin(_) | CalcMax(_) | CalcClamp(..) => self.write_calc(&**node),
+                _ => {
+                    self.write("calc(");
+                    self.write_calc(&**node);
+                    self.write(")");
+                }
+            },
+            Unparsed(ref text) | UnparsedShorthand(_, ref text) => self.write(text.as_slice()),
+        }
+    }
+
+    /// Write a number, dropping the leading zero of fractions when minifying.
+    fn write_number(&mut self, n: f32) {
+        let s = n.to_string();
+        if self.minify && s.as_slice().starts_with("0.") {
+            self.write(s.as_slice().slice_from(1));
+        } else if self.minify && s.as_slice().starts_with("-0.") {
+            self.write("-");
+            self.write(s.as_slice().slice_from(2));
+        } else {
+            self.write(s.as_slice());
+        }
+    }
+
+    /// Write a math expression, parenthesizing subexpressions as needed so that the parser
+    /// rebuilds the same tree.
+    fn write_calc(&mut self, node: &CalcNode) {
+        match *node {
+            CalcNumber(n) => self.write_number(n),
+            CalcLength(n) => {
+                self.write_number(n);
+                self.write("px");
+            }
+            CalcPercentage(p) => {
+                self.write_number(p);
+                self.write("%");
+            }
+            CalcSum(ref a, ref b) | CalcDifference(ref a, ref b) => {
+                self.write_calc(&**a);
+                // Whitespace around `+` and `-` is required.
+                self.write(match *node { CalcSum(..) => " + ", _ => " - " });
+                self.write_calc_operand(&**b, is_sum(&**b));
+            }
+            CalcProduct(ref a, ref b) | CalcQuotient(ref a, ref b) => {
+                self.write_calc_operand(&**a, is_sum(&**a));
+                self.write(match (node, self.minify) {
+                    (&CalcProduct(..), false) => " * ",
+                    (&CalcProduct(..), true) => "*",
+                    (_, false) => " / ",
+                    (_, true) => "/",
+                });
+                self.write_calc_operand(&**b, is_sum(&**b) || is_product(&**b));
+            }
+            CalcMin(ref args) | CalcMax(ref args) => {
+                self.write(match *node { CalcMin(_) => "min(", _ => "max(" });
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.write_either(", ", ",");
+                    }
+                    self.write_calc(arg);
+                }
+                self.write(")");
+            }
+            CalcClamp(ref min, ref value, ref max) => {
+                self.write("clamp(");
+                self.write_calc(&**min);
+                self.write_either(", ", ",");
+                self.write_calc(&**value);
+                self.write_either(", ", ",");
+                self.write_calc(&**max);
+                self.write(")");
+            }
+        }
+    }
+
+    fn write_calc_operand(&mut self, node: &CalcNode, parenthesize: bool) {
+        if parenthesize {
+            self.write("(");
+            self.write_calc(node);
+            self.write(")");
+        } else {
+            self.write_calc(node);
+        }
+    }
+
+    // Media queries
+
+    fn write_media_query_list(&mut self, list: &MediaQueryList) {
+        for (i, query) in list.queries.iter().enumerate() {
+            if i > 0 {
+                self.write_either(", ", ",");
+            }
+            self.write_media_query(query);
+        }
+    }
+
+    fn write_media_query(&mut self, query: &MediaQuery) {
+        match query.qualifier {
+            Some(NotQualifier) => self.write("not "),
+            Some(OnlyQualifier) => self.write("only "),
+            None => {}
+        }
+        let write_type = query.qualifier.is_some() || query.condition.is_none() ||
+                         query.media_type != AllMedia;
+        if write_type {
+            self.write_media_type(&query.media_type);
+        }
+        for condition in query.condition.iter() {
+            if !write_type {
+                self.write_media_condition(condition);
+            } else if is_or(condition) {
+                // `or` can't follow a media type without parentheses.
+                self.write(" and (");
+                self.write_media_condition(condition);
+                self.write(")");
+            } else {
+                self.write(" and ");
+                self.write_media_condition(condition);
+            }
+        }
+    }
+
+    fn write_media_type(&mut self, media_type: &MediaType) {
+        match *media_type {
+            AllMedia => self.write("all"),
+            Screen => self.write("screen"),
+            Print => self.write("print"),
+            UnknownMedia(ref name) => self.write(name.as_slice()),
+        }
+    }
+
+    fn write_media_condition(&mut self, condition: &MediaCondition) {
+        match *condition {
+            FeatureCondition(_) => self.write_media_in_parens(condition),
+            NotCondition(ref inner) => {
+                self.write("not ");
+                self.write_media_in_parens(&**inner);
+            }
+            AndCondition(ref conditions) | OrCondition(ref conditions) => {
+                let separator = match *condition { AndCondition(_) => " and ", _ => " or " };
+                for (i, c) in conditions.iter().enumerate() {
+                    if i > 0 {
+                        self.write(separator);
+                    }
+                    self.write_media_in_parens(c);
+                }
+            }
+        }
+    }
+
+    fn write_media_in_parens(&mut self, condition: &MediaCondition) {
+        self.write("(");
+        match *condition {
+            FeatureCondition(ref feature) => self.write_media_feature(feature),
+            _ => self.write_media_condition(condition),
+        }
+        self.write(")");
+    }
+
+    fn write_media_feature(&mut self, feature: &MediaFeature) {
+        match *feature {
+            BooleanFeature(ref name) => self.write(name.as_slice()),
+            PlainFeature(ref name, ref value) => {
+                self.write(name.as_slice());
+                self.write_either(": ", ":");
+                self.write_feature_value(value);
+            }
+            RangeFeature(ref name, ref cmp, ref value) => {
+                self.write(name.as_slice());
+                self.write_either(" ", "");
+                self.write_comparison(cmp);
+                self.write_either(" ", "");
+                self.write_feature_value(value);
+            }
+        }
+    }
+
+    fn write_comparison(&mut self, cmp: &Comparison) {
+        self.write(match *cmp {
+            CmpLt => "<",
+            CmpLe => "<=",
+            CmpEq => "=",
+            CmpGe => ">=",
+            CmpGt => ">",
+        });
+    }
+
+    fn write_feature_value(&mut self, value: &FeatureValue) {
+        match *value {
+            FeatureLength(n) => {
+                self.write_number(n);
+                self.write("px");
+            }
+            FeatureResolution(n) => {
+                self.write_number(n);
+                self.write("dppx");
+            }
+            FeatureNumber(n) => self.write_number(n),
+            FeatureIdent(ref ident) => self.write(ident.as_slice()),
+        }
+    }
+}
+
+fn is_sum(node: &CalcNode) -> bool {
+    match *node {
+        CalcSum(..) | CalcDifference(..) => true,
+        _ => false
+    }
+}
+
+fn is_product(node: &CalcNode) -> bool {
+    match *node {
+        CalcProduct(..) | CalcQuotient(..) => true,
+        _ => false
+    }
+}
+
+fn is_or(condition: &MediaCondition) -> bool {
+    match *condition {
+        OrCondition(_) => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use css::Stylesheet;
+    use parser::{parse_css, parse_css_file};
+    use super::{to_css, Pretty, Minified};
+
+    fn assert_round_trips(stylesheet: &Stylesheet) {
+        for &format in [Pretty, Minified].iter() {
+            assert_eq!(parse_css(to_css(stylesheet, format)), *stylesheet);
+        }
+    }
+
+    #[test]
+    fn example_stylesheet_round_trips() {
+        let mut stylesheet = parse_css_file(&Path::new("examples/test.css"));
+        stylesheet.rules.extend(parse_css("p { color: #336699; background: #ff000080; }"
+                                          .to_string()).rules.into_iter());
+        assert_round_trips(&stylesheet);
+    }
+
+    #[test]
+    fn at_rules_and_math_functions_round_trip() {
+        let stylesheet = parse_css("
+            @layer base, theme;
+            html { --gap: 4px; margin: var(--gap) 0; width: calc(100% - 2 * 10px); }
+            @media screen and (min-width: 600px) {
+                .inner { padding: clamp(1px, 2%, 10px); }
+            }
+            @layer base {
+                p { color: #336699; }
+                @layer reset { div > p.a.b { display: block; } }
+            }
+            @layer { span { background: red; } }
+            @supports (display: block) {
+                span#name { height: min(50%, calc(100px / 2)); }
+            }
+            @media print { @layer theme { em { font-size: max(12px, 1%); } } }
+        ".to_string());
+        assert_round_trips(&stylesheet);
+    }
+}
@@ -0,0 +1,115 @@
+//! Style sharing: elements that are equivalent for the cascade reuse the computed style of an
+//! element styled shortly before them instead of running the cascade again.
+//!
+//! Two elements are equivalent if they have the same tag name and attributes (and so the same id
+//! and classes), their parents have the same computed style object and they inherit the same
+//! custom properties. Styles are only shared when the parent styles are the same object, not
+//! merely equal: parents only share a style when they are equivalent themselves, so equivalent
+//! elements also have equivalent ancestors and match the same selectors.
+
+use custom_properties::CustomPropertyMap;
+use dom::ElementData;
+use properties::ComputedStyle;
+use std::fmt;
+use std::sync::Arc;
+use style::CascadeTrace;
+
+/// Number of recently styled elements the cache remembers.
+static CACHE_SIZE: uint = 8;
+
+struct CacheEntry<'a> {
+    elem: &'a ElementData,
+    parent_style: Arc<ComputedStyle>,
+    inherited_custom: CustomPropertyMap,
+    style: Arc<ComputedStyle>,
+    custom: CustomPropertyMap,
+    cascade: Arc<CascadeTrace>,
+}
+
+/// The recently styled elements, most recent first.
+pub struct StyleSharingCache<'a> {
+    entries: Vec<CacheEntry<'a>>,
+    pub stats: SharingStats,
+}
+
+/// How often the cache could be used.
+#[deriving(Clone)]
+pub struct SharingStats {
+    pub hits: uint,
+    pub misses: uint,
+}
+
+impl<'a> StyleSharingCache<'a> {
+    pub fn new() -> StyleSharingCache<'a> {
+        StyleSharingCache { entries: Vec::new(), stats: SharingStats { hits: 0, misses: 0 } }
+    }
+
+    /// Return the computed style, custom properties and cascade trace of a cached element
+    /// equivalent to `elem`, if any.
+    pub fn find(&mut self, elem: &ElementData, parent_style: &Arc<ComputedStyle>,
+                inherited_custom: &CustomPropertyMap)
+                -> Option<(Arc<ComputedStyle>, CustomPropertyMap, Arc<CascadeTrace>)> {
+        let found = self.entries.iter().position(|entry| {
+            same_object(&entry.parent_style, parent_style) &&
+                entry.elem.tag_name == elem.tag_name &&
+                entry.elem.attributes() == elem.attributes() &&
+                entry.inherited_custom == *inherited_custom
+        });
+        match found {
+            Some(i) => {
+                self.stats.hits += 1;
+                // Move the entry to the front.
+                let entry = self.entries.remove(i).unwrap();
+                let result = (entry.style.clone(), entry.custom.clone(), entry.cascade.clone());
+                self.entries.insert(0, entry);
+                Some(result)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Remember the style computed for `elem`.
+    pub fn insert(&mut self, elem: &'a ElementData, parent_style: &Arc<ComputedStyle>,
+                  inherited_custom: &CustomPropertyMap, style: &Arc<ComputedStyle>,
+                  custom: &CustomPropertyMap, cascade: &Arc<CascadeTrace>) {
+        if self.entries.len() == CACHE_SIZE {
+            self.entries.pop();
+        }
+        self.entries.insert(0, CacheEntry {
+            elem: elem,
+            parent_style: parent_style.clone(),
+            inherited_custom: inherited_custom.clone(),
+            style: style.clone(),
+            custom: custom.clone(),
+            cascade: cascade.clone(),
+        });
+    }
+}
+
+impl SharingStats {
+    /// Add the statistics of another traversal, e.g. of another subtree.
+    pub fn add(&mut self, other: &SharingStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+    }
+
+    /// Fraction of the elements that shared a style, between 0 and 1.
+    pub fn hit_rate(&self) -> f32 {
+        let total = self.hits + self.misses;
+        if total == 0 { 0.0 } else { self.hits as f32 / total as f32 }
+    }
+}
+
+impl fmt::Show for SharingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "style sharing: {} hits, {} misses ({:.1}% hit rate)",
+               self.hits, self.misses, self.hit_rate() * 100.0)
+    }
+}
+
+fn same_object(a: &Arc<ComputedStyle>, b: &Arc<ComputedStyle>) -> bool {
+    &**a as *const ComputedStyle == &**b as *const ComputedStyle
+}
@@ -1,118 +1,761 @@
 //! Apply CSS styles to a DOM tree and produce a style tree.
 
-use dom::{Node, Element, ElementData, Text};
-use css::{Stylesheet, Rule, Selector, Simple, SimpleSelector, Value, Keyword, Specificity};
+use document::{Document, QuirksMode, Quirks, NoQuirks};
+use dom::{NodeRef, Element, ElementData, Text};
+use css::{Stylesheet, Rule, Declaration, Selector, Simple, Complex, SimpleSelector};
+use css::{Value, Keyword, Unparsed, UnparsedShorthand};
+use css::{Descendant, Child};
+use css::{Specificity, Origin, UserAgent, Author};
+use custom_properties;
+use custom_properties::CustomPropertyMap;
+use invalidation::{Damage, NoDamage, RestyleSubtree};
+use media::Device;
+use parser;
+use properties;
+use properties::{ComputedStyle, ComputeContext};
+use rule_index::{RuleIndex, AncestorFilter};
+use sharing::{StyleSharingCache, SharingStats};
+use std::ascii::StrAsciiExt; // for `eq_ignore_ascii_case`
 use std::collections::hashmap::HashMap;
+use std::mem;
+use std::sync::Arc;
+use traversal::{Visitor, walk};
 
-/// Map from CSS property names to values. A PropertyMap will be associated with a DOM node.
+pub use properties::{Display, Inline, Block, DisplayNone};
+
+/// Map from CSS property names to values, used for the cascaded values of an element.
 pub type PropertyMap = HashMap<String, Value>;
 
 /// The styled node.
 pub struct StyledNode<'a> {
-    node: &'a Node, // pointer to a DOM node
-    specified_values: PropertyMap,
+    node: NodeRef<'a>,
+    pub style: Arc<ComputedStyle>, // shared by elements with equivalent styles
+    custom_properties: CustomPropertyMap,
+    cascade: Arc<CascadeTrace>,
     pub children: Vec<StyledNode<'a>>,
 }
 
-pub type MatchedRule<'a> = (Specificity, &'a Rule);
+/// The styles of a style tree, without its references to the DOM, so that they can be kept while
+/// the DOM is changed. `invalidation::invalidate` records in it which nodes need restyling.
+pub struct StyleSnapshot {
+    style: Arc<ComputedStyle>,
+    custom_properties: CustomPropertyMap,
+    cascade: Arc<CascadeTrace>,
+    pub damage: Damage,
+    pub children_changed: bool,
+    pub children: Vec<StyleSnapshot>,
+}
+
+/// A declaration that applied to an element, with the rule it comes from. It doesn't borrow the
+/// stylesheet, so that styles can outlive it (see `StyleSnapshot`).
+#[deriving(Clone)]
+pub struct AppliedDeclaration {
+    pub declaration: Declaration,
+    pub selector: Selector, // the selector of the rule that matched the element
+    pub layer: Option<String>,
+    pub level: CascadeLevel,
+}
 
-#[deriving(PartialEq)]
-pub enum Display {
-    Inline,
-    Block,
-    DisplayNone,
+/// The declarations of a property that applied to an element.
+#[deriving(Clone)]
+pub struct PropertyTrace {
+    // In cascade order, lowest priority first.
+    pub declarations: Vec<AppliedDeclaration>,
+    // Index of the declaration that won the cascade, or `None` if they were all reverted.
+    pub winner: Option<uint>,
 }
 
+/// How the cascade went for each property declared on an element.
+pub type CascadeTrace = HashMap<String, PropertyTrace>;
+
 impl<'a> StyledNode<'a> {
-    /// Return the specified value of a property if it exists, otherwise `None`.
+    pub fn new(node: NodeRef<'a>, style: Arc<ComputedStyle>, custom_properties: CustomPropertyMap,
+               cascade: Arc<CascadeTrace>, children: Vec<StyledNode<'a>>)
+               -> StyledNode<'a> {
+        StyledNode {
+            node: node,
+            style: style,
+            custom_properties: custom_properties,
+            cascade: cascade,
+            children: children,
+        }
+    }
+
+    /// The DOM node this node styles.
+    pub fn node(&self) -> NodeRef<'a> {
+        self.node
+    }
+
+    /// Return the computed value of longhand property `name`, or `None` if it isn't supported.
     pub fn value(&self, name: &str) -> Option<Value> {
-        self.specified_values.find_equiv(&name).map(|v| v.clone())
+        self.style.get(name)
+    }
+
+    /// Return the computed value of custom property `name` (including the leading `--`), or `None`
+    /// if it has the guaranteed-invalid value.
+    pub fn custom_property(&self, name: &str) -> Option<String> {
+        self.custom_properties.find_equiv(&name).map(|v| v.clone())
     }
 
-    /// Return the specified value of property `name`, or property `fallback_name` if that doesn't
-    /// exist. or value `default` if neither does.
-    pub fn lookup(&self, name: &str, fallback_name: &str, default: &Value) -> Value {
-        self.value(name).unwrap_or_else(|| self.value(fallback_name)
-                        .unwrap_or_else(|| default.clone()))
+    /// The declarations that applied to the node, for each property declared on it.
+    pub fn cascade(&self) -> &CascadeTrace {
+        &*self.cascade
     }
 
     /// The value of the display property defaults to inline.
     pub fn display(&self) -> Display {
-        match self.value("display") {
-            Some(Keyword(s)) => match s.as_slice() {
-                "block" => Block,
-                "none"  => DisplayNone,
-                _       => Inline
-            },
-            _ => Inline
+        self.style.display
+    }
+
+    /// Take a snapshot of the styles of the subtree, to restyle it after the DOM changes.
+    pub fn snapshot(&self) -> StyleSnapshot {
+        let mut builder = SnapshotBuilder { stack: Vec::new(), root: None };
+        walk(self, &mut builder);
+        builder.root.unwrap()
+    }
+}
+
+// Style trees are dropped one node at a time rather than recursively, so that deep trees don't
+// overflow the stack.
+#[unsafe_destructor]
+impl<'a> Drop for StyledNode<'a> {
+    fn drop(&mut self) {
+        let mut descendants = mem::replace(&mut self.children, Vec::new());
+        loop {
+            match descendants.pop() {
+                Some(mut node) => {
+                    descendants.extend(mem::replace(&mut node.children, Vec::new()).into_iter())
+                }
+                None => break
+            }
+        }
+    }
+}
+
+impl Drop for StyleSnapshot {
+    fn drop(&mut self) {
+        let mut descendants = mem::replace(&mut self.children, Vec::new());
+        loop {
+            match descendants.pop() {
+                Some(mut node) => {
+                    descendants.extend(mem::replace(&mut node.children, Vec::new()).into_iter())
+                }
+                None => break
+            }
         }
     }
 }
 
-fn matches(elem: &ElementData, selector: &Selector) -> bool {
+/// Builds the snapshot of a style tree while `walk` visits it.
+struct SnapshotBuilder {
+    // The snapshots of the nodes entered but not left yet, root first.
+    stack: Vec<StyleSnapshot>,
+    root: Option<StyleSnapshot>,
+}
+
+impl<'a> Visitor<&'a StyledNode<'a>> for SnapshotBuilder {
+    fn enter(&mut self, node: &'a StyledNode<'a>) {
+        self.stack.push(StyleSnapshot {
+            style: node.style.clone(),
+            custom_properties: node.custom_properties.clone(),
+            cascade: node.cascade.clone(),
+            damage: NoDamage,
+            children_changed: false,
+            children: Vec::new(),
+        });
+    }
+
+    fn leave(&mut self, _node: &'a StyledNode<'a>) {
+        let snapshot = self.stack.pop().unwrap();
+        match self.stack.last_mut() {
+            Some(parent) => parent.children.push(snapshot),
+            None => self.root = Some(snapshot)
+        }
+    }
+}
+
+/// Position of a matched rule in the cascade: its origin, the rank of its cascade layer and the
+/// specificity of the matching selector.
+pub type CascadeLevel = (Origin, uint, Specificity);
+
+/// A rule that matches an element, and the first of its selectors that does.
+pub struct MatchedRule<'a> {
+    pub level: CascadeLevel,
+    pub rule: &'a Rule,
+    pub selector: &'a Selector,
+}
+
+/// Context shared by the styling of all the nodes of a tree.
+pub struct StyleContext<'a> {
+    pub document: &'a Document,
+    // The rules of the stylesheets that apply to the device.
+    index: RuleIndex<'a>,
+}
+
+impl<'a> StyleContext<'a> {
+    pub fn new(document: &'a Document, stylesheets: &[&'a Stylesheet], device: &Device)
+               -> StyleContext<'a> {
+        StyleContext { document: document, index: RuleIndex::new(stylesheets, device) }
+    }
+}
+
+/// Return true if `selector` matches `elem`, whose ancestors are `ancestors` (root first).
+pub fn matches(elem: &ElementData, ancestors: &[&ElementData], selector: &Selector) -> bool {
+    matches_in_mode(elem, ancestors, selector, NoQuirks)
+}
+
+/// Like `matches`, for an element of a document in `quirks_mode`. In quirks mode, ids and
+/// classes match ASCII case-insensitively.
+pub fn matches_in_mode(elem: &ElementData, ancestors: &[&ElementData], selector: &Selector,
+                       quirks_mode: QuirksMode) -> bool {
     match *selector {
-        Simple(ref simple_selector) => matches_simple_selector(elem, simple_selector)
+        Simple(ref simple_selector) => matches_simple_selector(elem, simple_selector, quirks_mode),
+        Complex(ref left, combinator, ref right) => {
+            if !matches_simple_selector(elem, right, quirks_mode) {
+                return false;
+            }
+            match combinator {
+                Child => match ancestors.last() {
+                    Some(parent) => {
+                        matches_in_mode(*parent, ancestors.init(), &**left, quirks_mode)
+                    }
+                    None => false
+                },
+                Descendant => range(0, ancestors.len()).rev().any(|i| {
+                    matches_in_mode(ancestors[i], ancestors.slice_to(i), &**left, quirks_mode)
+                }),
+            }
+        }
     }
 }
 
-fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
+fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector,
+                           quirks_mode: QuirksMode) -> bool {
+    let same_name = |a: &String, b: &String| match quirks_mode {
+        Quirks => a.as_slice().eq_ignore_ascii_case(b.as_slice()),
+        NoQuirks => a == b
+    };
+
     // Check tag selector
     if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
         return false;
     }
 
     // Check ID selector
-    if selector.id.iter().any(|id| elem.id() != Some(id)) {
+    if selector.id.iter().any(|id| !elem.id().map_or(false, |elem_id| same_name(elem_id, id))) {
         return false;
     }
 
     // Check class selectors
     let elem_classes = elem.classes();
-    if selector.class.iter().any(|class| !elem_classes.contains(&class.as_slice())) {
+    if selector.class.iter().any(|class| {
+        !elem_classes.contains(class) && !elem_classes.iter().any(|c| same_name(c, class))
+    }) {
         return false;
     }
 
     return true;
 }
 
-/// If `rule` matches `elem`, return a `MatchedRule`. Otherwise return `None`.
-fn match_rule<'a>(elem: &ElementData, rule: &'a Rule) -> Option<MatchedRule<'a>> {
-    // Find the first (highest-specificity) matching selector.
-    rule.selectors
-        .iter()
-        .find(|selector| matches(elem, *selector))
-        .map(|selector| (selector.specificity(), rule))
-}
+/// Apply styles to a single element, returning the specified values, the computed custom
+/// properties and the trace of the cascade. `var()` references are substituted using the custom
+/// properties declared on the element and the ones inherited from its parent.
+fn specified_values(elem: &ElementData, ancestors: &[&ElementData], filter: &AncestorFilter,
+                    ctx: &StyleContext, inherited_custom: &CustomPropertyMap)
+                    -> (PropertyMap, CustomPropertyMap, CascadeTrace) {
+    let mut user_agent_values = HashMap::new();
+    let mut author_values = HashMap::new();
+    let mut custom = HashMap::new();
+    let mut trace: CascadeTrace = HashMap::new();
+    let mut rules = ctx.index.matching_rules(elem, ancestors, filter, ctx.document.quirks_mode);
+
+    // Sort by origin, cascade layer and then by specificity so that the application of more
+    // specific styles override the application of less specific styles.
+    rules.sort_by(|a, b| a.level.cmp(&b.level));
+    for matched in rules.iter() {
+        let (origin, _, _) = matched.level;
+        for declaration in matched.rule.declarations.iter() {
+            match declaration.value {
+                Unparsed(ref text) if declaration.is_custom_property() => {
+                    custom.insert(declaration.name.clone(), text.clone());
+                }
+                ref value => {
+                    let values = match origin {
+                        UserAgent => &mut user_agent_values,
+                        Author => &mut author_values,
+                    };
+                    values.insert(declaration.name.clone(), value.clone());
+                }
+            }
+            let property = trace.find_or_insert_with(declaration.name.clone(), |_| {
+                PropertyTrace { declarations: Vec::new(), winner: None }
+            });
+            property.declarations.push(AppliedDeclaration {
+                declaration: declaration.clone(),
+                selector: matched.selector.clone(),
+                layer: matched.rule.layer.clone(),
+                level: matched.level,
+            });
+        }
+    }
+    for (_, property) in trace.iter_mut() {
+        property.winner = cascade_winner(property.declarations.as_slice());
+    }
 
-/// Find all CSS rules that match the given element.
-fn matching_rules<'a>(elem: &ElementData, stylesheet: &'a Stylesheet) -> Vec<MatchedRule<'a>> {
-    stylesheet.rules.iter().filter_map(|rule| match_rule(elem, rule)).collect()
+    let values = cascade_origins(user_agent_values, author_values);
+    let custom = custom_properties::compute(inherited_custom, &custom);
+    (substitute_variables(values, &custom), custom, trace)
 }
 
-/// Apply styles to a single element, returning the specified values.
-fn specified_values(elem: &ElementData, stylesheet: &Stylesheet) -> PropertyMap {
-    let mut values = HashMap::new();
-    let mut rules = matching_rules(elem, stylesheet);
+/// Index of the declaration that wins the cascade among `declarations`, which are in cascade
+/// order. This follows `cascade_origins`.
+fn cascade_winner(declarations: &[AppliedDeclaration]) -> Option<uint> {
+    let last_in = |origin: Origin| {
+        declarations.iter().rposition(|applied| {
+            let (declaration_origin, _, _) = applied.level;
+            declaration_origin == origin
+        })
+    };
+    let is_reverted = |i: uint| is_revert(&declarations[i].declaration.value);
+    match last_in(Author) {
+        Some(i) if !is_reverted(i) => Some(i),
+        _ => match last_in(UserAgent) {
+            Some(i) if !is_reverted(i) => Some(i),
+            _ => None
+        }
+    }
+}
 
-    // Sort by specificity so that the application of more specific styles override the application
-    // of less specific styles.
-    rules.sort_by(|&(sa, _), &(sb, _)| sa.cmp(&sb));
-    for &(_, rule) in rules.iter() {
-        for declaration in rule.declarations.iter() {
-            values.insert(declaration.name.clone(), declaration.value.clone());
+/// Combine the values declared in each origin. `revert` rolls a property back to its value in
+/// the previous origin, and acts as `unset` in the user-agent origin.
+fn cascade_origins(user_agent: PropertyMap, author: PropertyMap) -> PropertyMap {
+    let mut values: PropertyMap = user_agent.into_iter()
+                                            .filter(|&(_, ref value)| !is_revert(value))
+                                            .collect();
+    for (name, value) in author.into_iter() {
+        if !is_revert(&value) {
+            values.insert(name, value);
         }
     }
     values
 }
 
-/// Apply a stylesheet to an entire DOM tree, returning a StyledNode tree.
-pub fn style_tree<'a>(root: &'a Node, stylesheet: &'a Stylesheet) -> StyledNode<'a> {
-    StyledNode {
-        node: root,
-        specified_values: match root.node_type {
-            Element(ref elem) => specified_values(elem, stylesheet),
-            Text(_) => HashMap::new()
-        },
-        children: root.children.iter().map(|child| style_tree(child, stylesheet)).collect()
+fn is_revert(value: &Value) -> bool {
+    match *value {
+        Keyword(ref k) => k.as_slice() == "revert",
+        _ => false
+    }
+}
+
+/// Compute the style of an element from its cascaded values and the computed style of its
+/// parent, handling inheritance and the `inherit`, `initial`, `unset` and `revert` keywords.
+fn compute_style(cascaded: &PropertyMap, parent: &ComputedStyle) -> ComputedStyle {
+    let initial = ComputedStyle::initial();
+    let mut style = ComputedStyle::inherit(parent);
+    let mut context = ComputeContext {
+        parent_font_size: parent.font_size,
+        font_size: parent.font_size,
+    };
+
+    // Other properties can depend on the font size, so it is computed first.
+    match cascaded.find_equiv(&"font-size") {
+        Some(value) => apply(&mut style, "font-size", value, parent, &initial, &context),
+        None => {}
+    }
+    context.font_size = style.font_size;
+    for (name, value) in cascaded.iter() {
+        if name.as_slice() != "font-size" {
+            apply(&mut style, name.as_slice(), value, parent, &initial, &context);
+        }
+    }
+    style
+}
+
+/// Set property `name` of `style` from its cascaded value.
+fn apply(style: &mut ComputedStyle, name: &str, value: &Value, parent: &ComputedStyle,
+         initial: &ComputedStyle, context: &ComputeContext) {
+    match *value {
+        Keyword(ref k) if k.as_slice() == "inherit" => style.copy_from(name, parent),
+        Keyword(ref k) if k.as_slice() == "initial" => style.copy_from(name, initial),
+        // `revert` is handled by `cascade_origins`, unless it comes from a `var()` substitution.
+        Keyword(ref k) if k.as_slice() == "unset" || k.as_slice() == "revert" => {
+            unset(style, name, parent, initial)
+        }
+        ref value => {
+            // Values that are invalid at computed-value time (after `var()` substitution) act
+            // as `unset`.
+            if !style.set(name, value, context) {
+                unset(style, name, parent, initial);
+            }
+        }
+    }
+}
+
+fn unset(style: &mut ComputedStyle, name: &str, parent: &ComputedStyle, initial: &ComputedStyle) {
+    if properties::is_inherited(name) {
+        style.copy_from(name, parent)
+    } else {
+        style.copy_from(name, initial)
+    }
+}
+
+/// Substitute `var()` references in unparsed values. Properties that become invalid after the
+/// substitution are dropped, as if they had not been declared. The longhands of a shorthand take
+/// their part of the shorthand's substituted value.
+fn substitute_variables(values: PropertyMap, custom: &CustomPropertyMap) -> PropertyMap {
+    values.into_iter().filter_map(|(name, value)| {
+        let lookup = |n: &str| custom.find_equiv(&n).map(|v| v.clone());
+        let value = match value {
+            Unparsed(text) => {
+                custom_properties::substitute(text.as_slice(), lookup)
+                    .and_then(|text| parser::parse_value(text))
+            }
+            UnparsedShorthand(shorthand, text) => {
+                custom_properties::substitute(text.as_slice(), lookup).and_then(|text| {
+                    parser::parse_longhand_value(shorthand.as_slice(), name.as_slice(), text)
+                })
+            }
+            value => Some(value)
+        };
+        value.map(|value| (name, value))
+    }).collect()
+}
+
+/// Mutable state of the traversal of the DOM tree.
+pub struct TraversalState<'a> {
+    // Ancestor elements of the current node, root first. They are also in `filter`.
+    ancestors: Vec<&'a ElementData>,
+    filter: AncestorFilter,
+    pub sharing_cache: StyleSharingCache<'a>,
+}
+
+impl<'a> TraversalState<'a> {
+    /// The state for styling a subtree whose ancestors are `ancestors`, root first.
+    pub fn new(ancestors: &[&'a ElementData]) -> TraversalState<'a> {
+        let mut state = TraversalState {
+            ancestors: Vec::new(),
+            filter: AncestorFilter::new(),
+            sharing_cache: StyleSharingCache::new(),
+        };
+        for &ancestor in ancestors.iter() {
+            state.ancestors.push(ancestor);
+            state.filter.push(ancestor);
+        }
+        state
+    }
+
+    pub fn ancestors(&self) -> &[&'a ElementData] {
+        self.ancestors.as_slice()
+    }
+
+    /// Descend into the children of `node`.
+    pub fn enter(&mut self, node: NodeRef<'a>) {
+        match *node.node_type() {
+            Element(ref elem) => {
+                self.ancestors.push(elem);
+                self.filter.push(elem);
+            }
+            Text(_) => {}
+        }
+    }
+
+    /// Return from the children of `node`.
+    pub fn leave(&mut self, node: NodeRef<'a>) {
+        match *node.node_type() {
+            Element(ref elem) => {
+                self.ancestors.pop();
+                self.filter.pop(elem);
+            }
+            Text(_) => {}
+        }
+    }
+}
+
+/// Apply stylesheets to the tree of a document, returning a StyledNode tree and statistics about
+/// style sharing. Media queries are evaluated against `device`.
+pub fn style_tree<'a>(document: &'a Document, stylesheets: &[&'a Stylesheet], device: &Device)
+                      -> (StyledNode<'a>, SharingStats) {
+    let ctx = StyleContext::new(document, stylesheets, device);
+    let mut state = TraversalState::new(&[]);
+    let root = style_subtree(document.root(), &mut state, &ctx, &Arc::new(ComputedStyle::initial()),
+                             &HashMap::new());
+    (root, state.sharing_cache.stats)
+}
+
+/// Style `node` and its descendants. The parent of `node` has the given computed style and
+/// custom properties; the root element inherits from the initial values.
+pub fn style_subtree<'a>(node: NodeRef<'a>, state: &mut TraversalState<'a>, ctx: &StyleContext,
+                         parent_style: &Arc<ComputedStyle>, inherited_custom: &CustomPropertyMap)
+                         -> StyledNode<'a> {
+    restyle_subtree(node, None, true, state, ctx, parent_style, inherited_custom)
+}
+
+/// Restyle the tree of a document after it changed. `previous` is a snapshot of its styles from
+/// before the changes, passed through `invalidation::invalidate`; the styles of the nodes the
+/// changes can't affect are reused from it.
+pub fn restyle_tree<'a>(document: &'a Document, stylesheets: &[&'a Stylesheet],
+                        device: &Device, previous: &StyleSnapshot)
+                        -> (StyledNode<'a>, SharingStats) {
+    let ctx = StyleContext::new(document, stylesheets, device);
+    let mut state = TraversalState::new(&[]);
+    let root = restyle_subtree(document.root(), Some(previous), false, &mut state, &ctx,
+                               &Arc::new(ComputedStyle::initial()), &HashMap::new());
+    (root, state.sharing_cache.stats)
+}
+
+/// Like `style_subtree`, but reuse the style in `previous` unless the node is damaged or `force`
+/// is true, e.g. because an ancestor's style changed.
+fn restyle_subtree<'a, 'b>(node: NodeRef<'a>, previous: Option<&'b StyleSnapshot>, force: bool,
+                           state: &'b mut TraversalState<'a>, ctx: &'b StyleContext,
+                           parent_style: &'b Arc<ComputedStyle>,
+                           inherited_custom: &'b CustomPropertyMap) -> StyledNode<'a> {
+    let mut styler = Styler {
+        state: state,
+        ctx: ctx,
+        previous: previous,
+        force: force,
+        parent_style: parent_style,
+        inherited_custom: inherited_custom,
+        stack: Vec::new(),
+        root: None,
+    };
+    walk(node, &mut styler);
+    styler.root.unwrap()
+}
+
+/// Builds the style tree of a subtree while `walk` visits it.
+struct Styler<'a, 'b> {
+    state: &'b mut TraversalState<'a>,
+    ctx: &'b StyleContext<'b>,
+    // The snapshot of the root of the subtree, whether to restyle it anyway, and the style and
+    // custom properties of its parent.
+    previous: Option<&'b StyleSnapshot>,
+    force: bool,
+    parent_style: &'b Arc<ComputedStyle>,
+    inherited_custom: &'b CustomPropertyMap,
+    // The nodes entered but not left yet, root first.
+    stack: Vec<PendingNode<'a, 'b>>,
+    root: Option<StyledNode<'a>>,
+}
+
+/// A node whose style is computed but whose children are still being styled.
+struct PendingNode<'a, 'b> {
+    style: Arc<ComputedStyle>,
+    custom: CustomPropertyMap,
+    cascade: Arc<CascadeTrace>,
+    children: Vec<StyledNode<'a>>,
+    // Whether the children must be restyled, and their snapshots if they match the DOM.
+    force_children: bool,
+    previous_children: Option<&'b Vec<StyleSnapshot>>,
+}
+
+impl<'a, 'b> Visitor<NodeRef<'a>> for Styler<'a, 'b> {
+    fn enter(&mut self, node: NodeRef<'a>) {
+        let (previous, force) = match self.stack.last() {
+            Some(parent) => {
+                let index = parent.children.len();
+                (parent.previous_children.and_then(|children| children.get(index)),
+                 parent.force_children)
+            }
+            None => (self.previous, self.force)
+        };
+        let (style, custom, cascade) = {
+            let (parent_style, inherited_custom) = match self.stack.last() {
+                Some(parent) => (&parent.style, &parent.custom),
+                None => (self.parent_style, self.inherited_custom)
+            };
+            match previous {
+                Some(previous) if !force && previous.damage == NoDamage => {
+                    (previous.style.clone(), previous.custom_properties.clone(),
+                     previous.cascade.clone())
+                }
+                _ => style_node(node, self.state, self.ctx, parent_style, inherited_custom)
+            }
+        };
+
+        // The children must be restyled if they may match different selectors or inherit
+        // different values. Children that are new or moved have no previous style.
+        let (force_children, previous_children) = match previous {
+            Some(previous) => {
+                let force_children = force || previous.damage == RestyleSubtree ||
+                                     *previous.style != *style ||
+                                     previous.custom_properties != custom;
+                // A snapshot that doesn't match the DOM, e.g. because the changes weren't
+                // passed to `invalidate`, is ignored.
+                let aligned = !previous.children_changed &&
+                              previous.children.len() == node.children().count();
+                let children = if aligned { Some(&previous.children) } else { None };
+                (force_children, children)
+            }
+            None => (true, None)
+        };
+        self.state.enter(node);
+        self.stack.push(PendingNode {
+            style: style,
+            custom: custom,
+            cascade: cascade,
+            children: Vec::new(),
+            force_children: force_children,
+            previous_children: previous_children,
+        });
+    }
+
+    fn leave(&mut self, node: NodeRef<'a>) {
+        self.state.leave(node);
+        let pending = self.stack.pop().unwrap();
+        let styled = StyledNode::new(node, pending.style, pending.custom, pending.cascade,
+                                     pending.children);
+        match self.stack.last_mut() {
+            Some(parent) => parent.children.push(styled),
+            None => self.root = Some(styled)
+        }
+    }
+}
+
+/// Compute the style, custom properties and cascade trace of `node`, without its descendants.
+pub fn style_node<'a>(node: NodeRef<'a>, state: &mut TraversalState<'a>, ctx: &StyleContext,
+                      parent_style: &Arc<ComputedStyle>, inherited_custom: &CustomPropertyMap)
+                      -> (Arc<ComputedStyle>, CustomPropertyMap, Arc<CascadeTrace>) {
+    match *node.node_type() {
+        Element(ref elem) => {
+            match state.sharing_cache.find(elem, parent_style, inherited_custom) {
+                Some(shared) => shared,
+                None => {
+                    let (specified, custom, cascade) =
+                        specified_values(elem, state.ancestors.as_slice(), &state.filter, ctx,
+                                         inherited_custom);
+                    let style = Arc::new(compute_style(&specified, &**parent_style));
+                    let cascade = Arc::new(cascade);
+                    state.sharing_cache.insert(elem, parent_style, inherited_custom, &style,
+                                               &custom, &cascade);
+                    (style, custom, cascade)
+                }
+            }
+        }
+        // Text nodes inherit the inherited properties of their parent.
+        Text(_) => {
+            (Arc::new(ComputedStyle::inherit(&**parent_style)), inherited_custom.clone(),
+             Arc::new(HashMap::new()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use css::{Length, Px};
+    use document::Document;
+    use dom;
+    use invalidation::invalidate;
+    use media::{Device, Screen};
+    use parallel;
+    use parser::{parse_css, parse_html};
+    use traversal::pre_order;
+    use super::{style_tree, restyle_tree};
+
+    #[test]
+    fn restyle_after_mutations_matches_full_style() {
+        let mut document = parse_html("<!DOCTYPE html><html><body><div id=\"a\" class=\"x\">\
+                                       <p>One</p></div><div><p>Two</p></div></body></html>"
+                                      .to_string());
+        let stylesheet = parse_css("
+            body { color: #000011; }
+            .x p { color: red; }
+            .y { display: none; }
+            .y p, div > p.added { font-size: 30px; }
+        ".to_string());
+        let stylesheets = [&stylesheet];
+        let device = Device::new(Screen, 800.0, 600.0);
+        let mut snapshot = style_tree(&document, &stylesheets, &device).val0().snapshot();
+
+        let (body, div) = {
+            let body = document.root().first_child().unwrap();
+            (body.id(), body.first_child().unwrap().id())
+        };
+        document.dom.set_attribute(div, "class", "y".to_string());
+        let mut attributes = dom::AttrMap::new();
+        attributes.insert("class".to_string(), "added".to_string());
+        let p = document.dom.create(dom::elem("p".to_string(), attributes,
+                                              vec![dom::text("Three".to_string())]));
+        let last_div = document.dom.get(body).last_child().unwrap().id();
+        document.dom.append_child(last_div, p);
+        let records = document.dom.take_records();
+        invalidate(&document.dom, records.as_slice(), &stylesheets, &mut snapshot);
+
+        let (restyled, _) = restyle_tree(&document, &stylesheets, &device, &snapshot);
+        let (full, _) = style_tree(&document, &stylesheets, &device);
+        let restyled: Vec<_> = pre_order(&restyled).map(|n| (n.node().id(), (*n.style).clone()))
+                                                    .collect();
+        let full: Vec<_> = pre_order(&full).map(|n| (n.node().id(), (*n.style).clone()))
+                                            .collect();
+        assert!(restyled == full);
+    }
+
+    #[test]
+    fn var_references_in_shorthands() {
+        let document = parse_html("<p></p>".to_string());
+        let stylesheet = parse_css("p {
+            --gap: 4px; --pair: 1px 2px; --empty: ;
+            margin: var(--gap) 0;
+            padding: var(--pair);
+            border-width: var(--empty) 3px var(--missing, 5px);
+        }".to_string());
+        let stylesheets = [&stylesheet];
+        let device = Device::new(Screen, 800.0, 600.0);
+        let (root, _) = style_tree(&document, &stylesheets, &device);
+        let values = [
+            ("margin-top", 4.0), ("margin-right", 0.0),
+            ("margin-bottom", 4.0), ("margin-left", 0.0),
+            ("padding-top", 1.0), ("padding-right", 2.0),
+            ("padding-bottom", 1.0), ("padding-left", 2.0),
+            ("border-top-width", 3.0), ("border-right-width", 5.0),
+            ("border-bottom-width", 3.0), ("border-left-width", 5.0),
+        ];
+        for &(name, px) in values.iter() {
+            assert_eq!((name, root.value(name)), (name, Some(Length(px, Px))));
+        }
+    }
+
+    #[test]
+    fn ids_and_classes_match_case_insensitively_in_quirks_mode() {
+        let stylesheet = parse_css("#MAIN { margin-top: 1px; } .Outer p { padding-top: 2px; }"
+                                   .to_string());
+        let stylesheets = [&stylesheet];
+        let device = Device::new(Screen, 800.0, 600.0);
+        for &(doctype, px) in [("", 1.0), ("<!DOCTYPE html>", 0.0)].iter() {
+            let document = parse_html(format!("{}<div id=\"main\" class=\"outer\"><p></p></div>",
+                                              doctype));
+            let (root, _) = style_tree(&document, &stylesheets, &device);
+            assert_eq!(root.value("margin-top"), Some(Length(px, Px)));
+            assert_eq!(root.children[0].value("padding-top"), Some(Length(px * 2.0, Px)));
+        }
+    }
+
+    #[test]
+    fn deep_trees() {
+        // 100,000 nested divs, with enough children at the bottom for the parallel styler to split
+        // the tree there.
+        let leaves = Vec::from_fn(16, |_| dom::elem("p".to_string(), dom::AttrMap::new(), vec![]));
+        let mut tree = dom::elem("div".to_string(), dom::AttrMap::new(), leaves);
+        for _ in range(0u, 100000) {
+            tree = dom::elem("div".to_string(), dom::AttrMap::new(), vec![tree]);
+        }
+        let mut document = Document::new(dom::Dom::new(tree), None);
+        let stylesheet = parse_css(".x p { margin-top: 2px; }".to_string());
+        let stylesheets = [&stylesheet];
+        let device = Device::new(Screen, 800.0, 600.0);
+
+        let mut snapshot = {
+            let (styled, _) = style_tree(&document, &stylesheets, &device);
+            let (parallel_styled, _) = parallel::style_tree(&document, &stylesheets, &device, 4);
+            assert_eq!(pre_order(&parallel_styled).count(), 100017);
+            styled.snapshot()
+        };
+        let root = document.root().id();
+        document.dom.set_attribute(root, "class", "x".to_string());
+        let records = document.dom.take_records();
+        invalidate(&document.dom, records.as_slice(), &stylesheets, &mut snapshot);
+        let (restyled, _) = restyle_tree(&document, &stylesheets, &device, &snapshot);
+        let leaf = pre_order(&restyled).last().unwrap();
+        assert_eq!(leaf.value("margin-top"), Some(Length(2.0, Px)));
     }
 }
@@ -0,0 +1,204 @@
+//! Extraction of the readable text of a styled document, like `innerText` in browsers.
+//!
+//! Elements with `display: none` are skipped. Whitespace is collapsed, block elements start on
+//! a new line (paragraphs are separated by a blank line), `<br>` breaks the line, list items are
+//! prefixed with a bullet or their number and table cells are separated by tabs.
+
+use dom::{Element, Text};
+use std::cmp::max;
+use style::{StyledNode, Block, DisplayNone};
+use traversal::{Visitor, walk};
+
+/// Return the rendered text of `root` and its descendants.
+pub fn inner_text(root: &StyledNode) -> String {
+    let mut builder = TextBuilder {
+        output: String::new(),
+        pending_breaks: 0,
+        pending_space: false,
+        hidden_depth: 0,
+        siblings: Vec::new(),
+    };
+    walk(root, &mut builder);
+    builder.output
+}
+
+struct TextBuilder {
+    output: String,
+    // Line breaks required before the next text.
+    pending_breaks: uint,
+    // Whether whitespace was collapsed since the last text.
+    pending_space: bool,
+    // Number of nodes entered inside an element with `display: none`, including it.
+    hidden_depth: uint,
+    // For each node entered but not left, root first, the cells and list items among its
+    // children so far.
+    siblings: Vec<SiblingCounts>,
+}
+
+/// The number of table cells and list items among the children of an element.
+struct SiblingCounts {
+    cells: uint,
+    items: uint,
+}
+
+impl TextBuilder {
+    /// Add text, collapsing whitespace.
+    fn text(&mut self, text: &str) {
+        for c in text.chars() {
+            if c.is_whitespace() {
+                self.pending_space = true;
+                continue;
+            }
+            self.flush_breaks();
+            let after_space = self.output.is_empty() ||
+                              self.output.as_slice().ends_with(" ") ||
+                              self.output.as_slice().ends_with("\n") ||
+                              self.output.as_slice().ends_with("\t");
+            if self.pending_space && !after_space {
+                self.output.push_char(' ');
+            }
+            self.pending_space = false;
+            self.output.push_char(c);
+        }
+    }
+
+    /// Add text that is kept as is, like the line break of a `<br>`.
+    fn literal(&mut self, text: &str) {
+        self.flush_breaks();
+        self.output.push_str(text);
+        self.pending_space = false;
+    }
+
+    /// Require `count` line breaks before the next text. They are dropped at the start and the
+    /// end of the output.
+    fn line_breaks(&mut self, count: uint) {
+        self.pending_breaks = max(self.pending_breaks, count);
+        self.pending_space = false;
+    }
+
+    fn flush_breaks(&mut self) {
+        if !self.output.is_empty() {
+            for _ in range(0, self.pending_breaks) {
+                self.output.push_char('\n');
+            }
+        }
+        self.pending_breaks = 0;
+    }
+}
+
+impl<'a> Visitor<&'a StyledNode<'a>> for TextBuilder {
+    fn enter(&mut self, styled: &'a StyledNode<'a>) {
+        let node = styled.node();
+        let tag_name = match *node.node_type() {
+            Element(ref elem) => elem.tag_name.as_slice(),
+            Text(_) => ""
+        };
+        // The cells and list items before the node among its siblings, hidden ones included.
+        let preceding = match self.siblings.last_mut() {
+            Some(counts) => {
+                let preceding = *counts;
+                match tag_name {
+                    "td" | "th" => counts.cells += 1,
+                    "li" => counts.items += 1,
+                    _ => {}
+                }
+                preceding
+            }
+            None => SiblingCounts { cells: 0, items: 0 }
+        };
+        self.siblings.push(SiblingCounts { cells: 0, items: 0 });
+
+        if self.hidden_depth > 0 || styled.display() == DisplayNone {
+            self.hidden_depth += 1;
+            return;
+        }
+        match *node.node_type() {
+            Text(ref data) => self.text(data.as_slice()),
+            Element(ref elem) => {
+                match elem.tag_name.as_slice() {
+                    "br" => self.literal("\n"),
+                    "p" => self.line_breaks(2),
+                    "tr" => self.line_breaks(1),
+                    "td" | "th" => {
+                        if preceding.cells > 0 {
+                            self.literal("\t");
+                        }
+                    }
+                    "li" => {
+                        self.line_breaks(1);
+                        match node.parent().and_then(|parent| parent.element()) {
+                            Some(list) if list.tag_name.as_slice() == "ol" => {
+                                let number = preceding.items + 1;
+                                self.literal(format!("{}. ", number).as_slice());
+                            }
+                            _ => self.literal("- ")
+                        }
+                    }
+                    _ if styled.display() == Block => self.line_breaks(1),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn leave(&mut self, styled: &'a StyledNode<'a>) {
+        self.siblings.pop();
+        if self.hidden_depth > 0 {
+            self.hidden_depth -= 1;
+            return;
+        }
+        match styled.node().element() {
+            Some(elem) if elem.tag_name.as_slice() == "p" => self.line_breaks(2),
+            Some(elem) if elem.tag_name.as_slice() == "tr" => self.line_breaks(1),
+            Some(_) if styled.display() == Block => self.line_breaks(1),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use media::{Device, Screen};
+    use parser::{parse_css, parse_html};
+    use style::style_tree;
+    use super::inner_text;
+    use user_agent;
+
+    /// The inner text of the root of `html`, styled by the user agent stylesheet.
+    fn text_of(html: &str) -> String {
+        let document = parse_html(html.to_string());
+        let user_agent_stylesheet = user_agent::stylesheet();
+        let stylesheet = parse_css(".hidden { display: none; }".to_string());
+        let stylesheets = [&user_agent_stylesheet, &stylesheet];
+        let device = Device::new(Screen, 800.0, 600.0);
+        let (root, _) = style_tree(&document, &stylesheets, &device);
+        inner_text(&root)
+    }
+
+    #[test]
+    fn inline_whitespace_is_collapsed() {
+        assert_eq!(text_of("<p>  Hello \t  <b> big \n </b>  world  </p>"),
+                   "Hello big world".to_string());
+    }
+
+    #[test]
+    fn blocks_start_new_lines() {
+        assert_eq!(text_of("<div>  One  <div> Two </div>Three<p>Four</p> Five</div>"),
+                   "One\nTwo\nThree\n\nFour\n\nFive".to_string());
+    }
+
+    #[test]
+    fn hidden_subtrees_are_skipped() {
+        assert_eq!(text_of("<div>Shown<span class=\"hidden\">Hidden <b>too</b></span> again\
+                            <script>var x;</script><ul class=\"hidden\"><li>No</li></ul></div>"),
+                   "Shown again".to_string());
+    }
+
+    #[test]
+    fn lists_and_tables() {
+        assert_eq!(text_of("<div><ol><li>a</li><li class=\"hidden\">b</li><li>c</li></ol>\
+                            <ul><li>d</li></ul><table><tr><td>1</td><td>2</td></tr>\
+                            <tr><th>3</th></tr></table></div>"),
+                   "1. a\n3. c\n- d\n1\t2\n3".to_string());
+    }
+}
@@ -0,0 +1,129 @@
+//! Traversal of the DOM, style and layout trees.
+//!
+//! The iterators and `walk` keep their own stack or queue instead of recursing, so they work on
+//! trees of any depth.
+
+use dom::NodeRef;
+use layout::LayoutBox;
+use std::collections::{Deque, RingBuf};
+use style::StyledNode;
+
+/// A node of a tree that can be traversed.
+pub trait TreeNode: Copy {
+    /// The children of the node, first to last.
+    fn child_nodes(&self) -> Vec<Self>;
+}
+
+impl<'a> TreeNode for NodeRef<'a> {
+    fn child_nodes(&self) -> Vec<NodeRef<'a>> {
+        self.children().collect()
+    }
+}
+
+impl<'a> TreeNode for &'a StyledNode<'a> {
+    fn child_nodes(&self) -> Vec<&'a StyledNode<'a>> {
+        let node: &'a StyledNode<'a> = *self;
+        node.children.iter().collect()
+    }
+}
+
+impl<'a> TreeNode for &'a LayoutBox<'a> {
+    fn child_nodes(&self) -> Vec<&'a LayoutBox<'a>> {
+        let layout_box: &'a LayoutBox<'a> = *self;
+        layout_box.children.iter().collect()
+    }
+}
+
+/// Iterate over `root` and its descendants, each node before its children.
+pub fn pre_order<N: TreeNode>(root: N) -> PreOrder<N> {
+    PreOrder { stack: vec![root] }
+}
+
+/// Iterate over `root` and its descendants, each node after its children.
+pub fn post_order<N: TreeNode>(root: N) -> PostOrder<N> {
+    PostOrder { stack: vec![(root, false)] }
+}
+
+/// Iterate over `root` and its descendants level by level.
+pub fn breadth_first<N: TreeNode>(root: N) -> BreadthFirst<N> {
+    let mut queue = RingBuf::new();
+    queue.push_back(root);
+    BreadthFirst { queue: queue }
+}
+
+pub struct PreOrder<N> {
+    // Nodes still to visit, the next one last.
+    stack: Vec<N>,
+}
+
+impl<N: TreeNode> Iterator<N> for PreOrder<N> {
+    fn next(&mut self) -> Option<N> {
+        let node = self.stack.pop();
+        for node in node.iter() {
+            self.stack.extend(node.child_nodes().into_iter().rev());
+        }
+        node
+    }
+}
+
+pub struct PostOrder<N> {
+    // Nodes still to visit, the next one last, and whether their children have been pushed.
+    stack: Vec<(N, bool)>,
+}
+
+impl<N: TreeNode> Iterator<N> for PostOrder<N> {
+    fn next(&mut self) -> Option<N> {
+        loop {
+            match self.stack.pop() {
+                Some((node, true)) => return Some(node),
+                Some((node, false)) => {
+                    self.stack.push((node, true));
+                    self.stack.extend(node.child_nodes().into_iter().rev().map(|c| (c, false)));
+                }
+                None => return None
+            }
+        }
+    }
+}
+
+pub struct BreadthFirst<N> {
+    queue: RingBuf<N>,
+}
+
+impl<N: TreeNode> Iterator<N> for BreadthFirst<N> {
+    fn next(&mut self) -> Option<N> {
+        let node = self.queue.pop_front();
+        for node in node.iter() {
+            for child in node.child_nodes().into_iter() {
+                self.queue.push_back(child);
+            }
+        }
+        node
+    }
+}
+
+/// Called by `walk` on each node of a tree.
+pub trait Visitor<N> {
+    /// Called before the children of `node` are visited.
+    fn enter(&mut self, node: N);
+
+    /// Called after the children of `node` are visited.
+    fn leave(&mut self, _node: N) {}
+}
+
+/// Visit `root` and its descendants in tree order.
+pub fn walk<N: TreeNode, V: Visitor<N>>(root: N, visitor: &mut V) {
+    // Nodes still to visit, the next one last, and whether they have been entered.
+    let mut stack = vec![(root, false)];
+    loop {
+        match stack.pop() {
+            Some((node, true)) => visitor.leave(node),
+            Some((node, false)) => {
+                visitor.enter(node);
+                stack.push((node, true));
+                stack.extend(node.child_nodes().into_iter().rev().map(|c| (c, false)));
+            }
+            None => break
+        }
+    }
+}
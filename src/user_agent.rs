@@ -0,0 +1,13 @@
+//! The built-in user-agent stylesheet, based on the rendering section of the HTML standard.
+
+use css::{Stylesheet, UserAgent};
+use parser;
+
+static USER_AGENT_CSS: &'static str = include_str!("user_agent.css");
+
+/// Parse the user-agent stylesheet.
+pub fn stylesheet() -> Stylesheet {
+    let mut stylesheet = parser::parse_css_named(USER_AGENT_CSS.to_string(), "user-agent");
+    stylesheet.origin = UserAgent;
+    stylesheet
+}